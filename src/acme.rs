@@ -0,0 +1,228 @@
+use std::{
+    fs,
+    io::{self, Write},
+    iter,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+
+use crate::config::Cidr;
+use crate::db;
+use crate::id::constant_time_eq;
+use crate::snapshot;
+
+/// How many TXT values each account keeps, so a certificate for both a name
+/// and its wildcard can be validated at once
+const TXT_VALUES: usize = 2;
+/// Length of a DNS-01 challenge's TXT value, a base64url SHA-256 digest
+const TXT_LEN: usize = 43;
+/// The label under which a d5 user's challenges are looked up
+const CHALLENGE_LABEL: &str = "_acme-challenge";
+/// How many accounts may be registered, since each rewrites the whole file
+const MAX_ACCOUNTS: usize = 1000;
+
+/// An account of the acme-dns API, which may set the TXT records of its
+/// subdomain
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub username: String,
+    /// The digest of the account's password, as `Token::hash` holds it
+    pub key_hash: String,
+    pub subdomain: String,
+    /// The d5 user whose `_acme-challenge.USER.ZONE` also has the account's
+    /// TXT records, if it was registered with their credentials
+    pub owner: Option<String>,
+    /// Networks updates must come from; anywhere when empty
+    pub allow_from: Vec<Cidr>,
+    /// The latest TXT values, oldest first
+    pub txt: Vec<String>,
+}
+
+/// The acme-dns accounts, saved to the `ACME_DNS_FILE` on every change
+#[derive(Debug, Clone, Default)]
+pub struct Accounts {
+    path: PathBuf,
+    accounts: Arc<RwLock<Vec<Account>>>,
+}
+
+impl Account {
+    fn to_json(&self) -> Value {
+        json!({
+            "username": self.username,
+            "key_hash": self.key_hash,
+            "subdomain": self.subdomain,
+            "owner": self.owner,
+            "allowfrom": self.allow_from.iter().map(Cidr::to_string).collect::<Vec<_>>(),
+            "txt": self.txt,
+        })
+    }
+
+    fn parse(v: &Value) -> Option<Self> {
+        let strings = |key: &str| v[key].as_array()?.iter().map(|s| s.as_str().map(String::from)).collect::<Option<Vec<_>>>();
+        Some(Account {
+            username: v["username"].as_str()?.to_string(),
+            key_hash: v["key_hash"].as_str()?.to_string(),
+            subdomain: v["subdomain"].as_str()?.to_string(),
+            owner: v["owner"].as_str().map(String::from),
+            allow_from: strings("allowfrom")?.iter().map(|cidr| cidr.parse().ok()).collect::<Option<_>>()?,
+            txt: strings("txt")?,
+        })
+    }
+}
+
+impl Accounts {
+    /// The accounts saved at `path`; a missing file holds none
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let accounts = match fs::read(path) {
+            Ok(json) => match serde_json::from_slice::<Value>(&json) {
+                Ok(Value::Array(accounts)) => accounts.iter()
+                    .map(Account::parse)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unreadable account"))?,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a JSON array of accounts")),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Accounts { path: path.to_path_buf(), accounts: Arc::new(RwLock::new(accounts)) })
+    }
+
+    /// Create an account for `owner`, if any, returning it and its password.
+    /// Errors are named as acme-dns names them.
+    pub fn register(&self, owner: Option<String>, allow_from: Vec<Cidr>) -> Result<(Account, String), &'static str> {
+        let mut accounts = self.accounts.write();
+        if accounts.len() >= MAX_ACCOUNTS {
+            return Err("too_many_accounts");
+        }
+        let password = db::random_hex(20);
+        let account = Account {
+            username: db::uuid(),
+            key_hash: db::token_hash(&password),
+            subdomain: db::uuid(),
+            owner,
+            allow_from,
+            txt: Vec::new(),
+        };
+        accounts.push(account.clone());
+        if let Err(e) = self.save(&accounts) {
+            eprintln!("[ERROR] Unable to save {}: {}", self.path.display(), e);
+            accounts.pop();
+            return Err("db_error");
+        }
+        Ok((account, password))
+    }
+
+    /// Add `txt` to `subdomain`'s values, replacing its oldest, if `username`
+    /// and `password` are its account's and the request comes `from` one of
+    /// its networks.  Errors are named as acme-dns names them.
+    pub fn update(&self, username: &str, password: &str, subdomain: &str, txt: &str, from: Option<IpAddr>) -> Result<(), &'static str> {
+        let hash = db::token_hash(password);
+        let mut accounts = self.accounts.write();
+        // Check every account, so the time taken doesn't reveal which matched
+        let found = accounts.iter().enumerate().fold(None, |found, (n, a)| {
+            let matched = a.username == username && constant_time_eq(a.key_hash.as_bytes(), hash.as_bytes());
+            if matched { Some(n) } else { found }
+        });
+        let account = found.map(|n| &mut accounts[n]).ok_or("forbidden")?;
+        if account.subdomain != subdomain {
+            return Err("forbidden");
+        }
+        let allowed = account.allow_from.is_empty()
+            || from.is_some_and(|ip| account.allow_from.iter().any(|net| net.contains(ip)));
+        if !allowed {
+            return Err("forbidden");
+        }
+        if txt.len() != TXT_LEN || !txt.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err("bad_txt");
+        }
+        account.txt.push(txt.to_string());
+        if account.txt.len() > TXT_VALUES {
+            account.txt.remove(0);
+        }
+        self.save(&accounts).map_err(|e| {
+            eprintln!("[ERROR] Unable to save {}: {}", self.path.display(), e);
+            "db_error"
+        })
+    }
+
+    /// The TXT values of `name`, relative to the zone: an account's
+    /// `SUBDOMAIN`, or `_acme-challenge.USER` for the accounts `USER` owns
+    pub fn txt(&self, name: &str) -> Vec<String> {
+        let accounts = self.accounts.read();
+        let owner = name.strip_prefix(CHALLENGE_LABEL).and_then(|user| user.strip_prefix('.'));
+        accounts.iter()
+            .filter(|a| match owner {
+                Some(user) => a.owner.as_ref().is_some_and(|owner| owner.eq_ignore_ascii_case(user)),
+                None => a.subdomain.eq_ignore_ascii_case(name),
+            })
+            .flat_map(|a| a.txt.iter().cloned())
+            .collect()
+    }
+
+    /// The names, relative to the zone, that have TXT values
+    pub fn names(&self) -> Vec<String> {
+        let accounts = self.accounts.read();
+        accounts.iter()
+            .filter(|a| !a.txt.is_empty())
+            .flat_map(|a| iter::once(a.subdomain.clone()).chain(a.owner.as_ref().map(|owner| format!("{}.{}", CHALLENGE_LABEL, owner))))
+            .collect()
+    }
+
+    /// Write every account, replacing the file only once it is completely
+    /// written.  It holds key hashes, so only its owner may read it.
+    fn save(&self, accounts: &[Account]) -> io::Result<()> {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = snapshot::create_private(Path::new(&tmp))?;
+        let json = accounts.iter().map(Account::to_json).collect::<Value>();
+        file.write_all(json.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[test]
+fn register_and_update() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("d5-acme-{}.json", std::process::id()));
+    let accounts = Accounts::open(&path).unwrap();
+    let (derp, password) = accounts.register(Some("derp".to_string()), Vec::new()).unwrap();
+    let (limited, limited_password) = accounts.register(None, vec!["10.0.0.0/8".parse().unwrap()]).unwrap();
+    let txt = |n: u8| char::from(b'a' + n).to_string().repeat(TXT_LEN);
+
+    for n in 0..3 {
+        assert_eq!(accounts.update(&derp.username, &password, &derp.subdomain, &txt(n), None), Ok(()));
+    }
+    assert_eq!(accounts.txt(&derp.subdomain), vec![txt(1), txt(2)]);
+    assert_eq!(accounts.txt("_acme-challenge.DERP"), vec![txt(1), txt(2)]);
+    assert!(accounts.txt("_acme-challenge.herp").is_empty());
+
+    assert_eq!(accounts.update(&derp.username, "wrong", &derp.subdomain, &txt(0), None), Err("forbidden"));
+    assert_eq!(accounts.update(&derp.username, &password, &limited.subdomain, &txt(0), None), Err("forbidden"));
+    assert_eq!(accounts.update(&derp.username, &password, &derp.subdomain, "short", None), Err("bad_txt"));
+    let (inside, outside) = ("10.1.2.3".parse().ok(), "192.0.2.1".parse().ok());
+    assert_eq!(accounts.update(&limited.username, &limited_password, &limited.subdomain, &txt(0), outside), Err("forbidden"));
+    assert_eq!(accounts.update(&limited.username, &limited_password, &limited.subdomain, &txt(0), inside), Ok(()));
+
+    let loaded = Accounts::open(&path).unwrap();
+    assert_eq!(*loaded.accounts.read(), *accounts.accounts.read());
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn accounts_capped() {
+    let path = std::env::temp_dir().join(format!("d5-acme-cap-{}.json", std::process::id()));
+    let accounts = Accounts::open(&path).unwrap();
+    let (account, _) = accounts.register(None, Vec::new()).unwrap();
+    accounts.accounts.write().resize(MAX_ACCOUNTS - 1, account);
+    accounts.register(None, Vec::new()).unwrap();
+    assert_eq!(accounts.register(None, Vec::new()).unwrap_err(), "too_many_accounts");
+    assert_eq!(Accounts::open(&path).unwrap().accounts.read().len(), MAX_ACCOUNTS);
+    fs::remove_file(&path).unwrap();
+}
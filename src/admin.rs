@@ -0,0 +1,411 @@
+#[cfg(test)]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::iter;
+use std::net::IpAddr;
+
+use serde_json::Value;
+
+use crate::db::{self, Entry, Records};
+use crate::dns::{self, Zone};
+use crate::id::Id;
+use crate::snapshot;
+use crate::Err::{self, *};
+
+/// Find the single record belonging to `user`
+fn find_user(db: &Records, user: &str) -> Result<Id, Err> {
+    match db.user_ids(user).as_slice() {
+        [id] => Ok((*id).clone()),
+        [] => Err(NotFound),
+        _ => Err(Conflict),
+    }
+}
+
+/// Exchange the IPs of users `a` and `b`, both families at once, returning
+/// the resulting assignments.  Nothing is changed unless both users exist.
+pub fn swap(db: &mut Records, a: &str, b: &str, now: u64) -> Result<Vec<(String, String)>, Err> {
+    let id_a = find_user(db, a)?;
+    let id_b = find_user(db, b)?;
+
+    let (mut entry_a, mut entry_b) = (db[&id_a].clone(), db[&id_b].clone());
+    std::mem::swap(&mut entry_a.ip, &mut entry_b.ip);
+    std::mem::swap(&mut entry_a.other_ip, &mut entry_b.other_ip);
+    entry_a.updated_at = now;
+    entry_b.updated_at = now;
+    let swapped = vec![(id_a.user.clone(), entry_a.ip.to_string()), (id_b.user.clone(), entry_b.ip.to_string())];
+    db::put(db, id_a, entry_a);
+    db::put(db, id_b, entry_b);
+
+    Ok(swapped)
+}
+
+/// Render the records as an `nsupdate` script for `zone`, replacing each
+/// user's `USER.ZONE` records of each family.  Users that aren't valid DNS
+/// labels are left out.
+pub fn nsupdate(db: &Records, zone: &str, server: Option<&str>, ttl: u32) -> String {
+    let zone = zone.trim_end_matches('.');
+    let mut records = db.live()
+        .map(|(id, e)| (id.user.to_ascii_lowercase(), e))
+        .filter(|(user, _)| db::valid_host_name(user))
+        .flat_map(|(user, e)| iter::once(e.ip).chain(e.other_ip).map(move |ip| (user.clone(), ip)))
+        .collect::<Vec<_>>();
+    records.sort();
+
+    let mut script = String::new();
+    if let Some(server) = server {
+        script.push_str(&format!("server {}\n", server));
+    }
+    script.push_str(&format!("zone {}.\n", zone));
+
+    for (user, ip) in records {
+        let rtype = match ip {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        };
+        script.push_str(&format!("update delete {}.{}. {}\n", user, zone, rtype));
+        script.push_str(&format!("update add {}.{}. {} {} {}\n", user, zone, ttl, rtype, ip));
+    }
+
+    script.push_str("send\n");
+    script
+}
+
+/// Render the records `zone` serves as a BIND zone file: its SOA and NS
+/// records, then each `USER` and `NAME.USER` address.  Names that aren't
+/// valid DNS labels are left out.
+pub fn zone_file(db: &Records, zone: &Zone, now: u64) -> String {
+    let mut records = zone.live(db, now)
+        .map(|(id, e)| (id.user.to_ascii_lowercase(), e))
+        .filter(|(user, _)| db::valid_host_name(user))
+        .flat_map(|(user, e)| {
+            let hosts = e.hosts.iter()
+                .filter(|(name, _)| db::valid_host_name(name))
+                .map(|(name, h)| (format!("{}.{}", name, user), h.ip, h.other_ip))
+                .collect::<Vec<_>>();
+            iter::once((user, e.ip, e.other_ip)).chain(hosts)
+        })
+        .flat_map(|(name, ip, other_ip)| iter::once(ip).chain(other_ip).map(move |ip| (name.clone(), ip)))
+        .collect::<Vec<_>>();
+    records.sort();
+    records.dedup();
+
+    let [refresh, retry, expire] = dns::SOA_TIMERS;
+    let mut file = format!("$ORIGIN {}.\n$TTL {}\n", zone.origin, zone.ttl);
+    file.push_str(&format!(
+        "@\tIN\tSOA\t{}. {}. {} {} {} {} {}\n",
+        zone.nameservers[0], zone.hostmaster, zone.serial(db, now), refresh, retry, expire, zone.ttl,
+    ));
+    for ns in &zone.nameservers {
+        file.push_str(&format!("@\tIN\tNS\t{}.\n", ns));
+    }
+    for (name, ip) in records {
+        let rtype = match ip {
+            IpAddr::V4(_) => "A",
+            IpAddr::V6(_) => "AAAA",
+        };
+        file.push_str(&format!("{}\tIN\t{}\t{}\n", name, rtype, ip));
+    }
+    file
+}
+
+/// Import `user,password,ip` rows, returning the number of records stored.
+/// Every row is validated first, so a single bad row imports nothing.
+pub fn import_csv(db: &mut Records, csv: &str, now: u64) -> Result<usize, Err> {
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+
+    for (n, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (n == 0 && line.eq_ignore_ascii_case("user,password,ip")) {
+            continue;
+        }
+        match parse_csv_row(line) {
+            Ok(record) => records.push(record),
+            Err(e) => errors.push(format!("line {}: {}", n + 1, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Import(errors.join("\n")));
+    }
+
+    let count = records.len();
+    for (id, ip) in records {
+        db::upsert(db, id, ip, now);
+    }
+    Ok(count)
+}
+
+fn parse_csv_row(line: &str) -> Result<(Id, IpAddr), &'static str> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    match fields.as_slice() {
+        [user, password, ip] => {
+            if user.is_empty() {
+                return Err("empty user");
+            }
+            let id = Id::try_from(format!("{}:{}", user, password).as_str())
+                .map_err(|_| "invalid user or password")?;
+            let ip = ip.parse::<IpAddr>().map_err(|_| "invalid IP")?;
+            Ok((id, ip))
+        }
+        _ => Err("expected user,password,ip"),
+    }
+}
+
+/// Load records in the `/admin/export` format, returning how many were
+/// loaded and how many existing records were removed.  With `replace`, records
+/// missing from the dump are removed; otherwise they are kept.  Every record
+/// is validated first, so a single bad one loads nothing.
+pub fn import_json(db: &mut Records, json: &[u8], replace: bool) -> Result<(usize, usize), Err> {
+    let records = match serde_json::from_slice::<Value>(json) {
+        Ok(Value::Array(records)) => records,
+        _ => return Err(Import("expected a JSON array of records".into())),
+    };
+    let mut parsed = Vec::new();
+    let mut errors = Vec::new();
+    for (n, v) in records.iter().enumerate() {
+        match snapshot::parse_record(v) {
+            Some(record) => parsed.push(record),
+            None => errors.push(format!("record {}: invalid record", n + 1)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Import(errors.join("\n")));
+    }
+
+    let mut removed = 0;
+    if replace {
+        let keep = parsed.iter().map(|(id, _)| id).collect::<HashSet<_>>();
+        let stale = db.list()
+            .map(|(id, _)| id)
+            .filter(|id| !keep.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for id in &stale {
+            db::remove(db, id);
+        }
+        removed = stale.len();
+    }
+    let count = parsed.len();
+    for (id, entry) in parsed {
+        db::put(db, id, entry);
+    }
+    Ok((count, removed))
+}
+
+/// All records that haven't been deleted, sorted by user, as `(user, entry)`
+/// pairs
+pub fn list(db: &Records) -> Vec<(&str, &Entry)> {
+    let mut records = db.live().map(|(id, e)| (id.user.as_str(), e)).collect::<Vec<_>>();
+    records.sort_by(|a, b| a.0.cmp(b.0).then(a.1.ip.cmp(&b.1.ip)));
+    records
+}
+
+/// How often a user's address changes, from its history
+#[derive(Debug, PartialEq)]
+pub struct Frequency {
+    /// The number of changes remembered
+    pub changes: usize,
+    /// The average number of seconds between them, if there are two or more
+    pub average_interval: Option<u64>,
+    /// How many of them are to IPv4 and to IPv6 addresses
+    pub v4: usize,
+    pub v6: usize,
+}
+
+/// The update frequency of every record that hasn't been deleted, sorted by
+/// user like `list`
+pub fn frequency(db: &Records) -> Vec<(&str, Frequency)> {
+    list(db).into_iter()
+        .map(|(user, e)| {
+            let (first, last) = (e.history.first(), e.history.last());
+            let changes = e.history.len();
+            let average_interval = first.zip(last)
+                .filter(|_| changes > 1)
+                .map(|(first, last)| last.at.saturating_sub(first.at) / (changes as u64 - 1));
+            let v4 = e.history.iter().filter(|c| c.ip.is_ipv4()).count();
+            (user, Frequency { changes, average_interval, v4, v6: changes - v4 })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn seed(entries: &[(&str, &str, &str)]) -> Records {
+    entries.iter()
+        .map(|(user, password, ip)| (Id::new(user, password), Entry::new(ip.parse().unwrap(), 0)))
+        .collect()
+}
+
+#[cfg(test)]
+fn ips(db: &Records) -> HashMap<Id, IpAddr> {
+    db.list().map(|(id, e)| (id.clone(), e.ip)).collect()
+}
+
+#[test]
+fn swap_users() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("herp", "blerp", "2.2.2.2")]);
+    let swapped = swap(&mut db, "derp", "herp", 0).unwrap();
+
+    assert_eq!(swapped, vec![
+        ("derp".to_string(), "2.2.2.2".to_string()),
+        ("herp".to_string(), "1.1.1.1".to_string()),
+    ]);
+    assert_eq!(db[&Id::new("derp", "flerp")].ip.to_string(), "2.2.2.2");
+    assert_eq!(db[&Id::new("herp", "blerp")].ip.to_string(), "1.1.1.1");
+}
+
+#[test]
+fn swap_keeps_uuids() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("herp", "blerp", "2.2.2.2")]);
+    let uuid = db[&Id::new("derp", "flerp")].uuid.clone();
+    swap(&mut db, "derp", "herp", 0).unwrap();
+    assert_eq!(db[&Id::new("derp", "flerp")].uuid, uuid);
+}
+
+#[test]
+fn swap_missing_user() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1")]);
+    assert!(swap(&mut db, "derp", "herp", 0).is_err());
+    assert!(swap(&mut db, "herp", "derp", 0).is_err());
+    assert_eq!(ips(&db), ips(&seed(&[("derp", "flerp", "1.1.1.1")])));
+}
+
+#[test]
+fn nsupdate_script() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("herp", "blerp", "::1")]);
+    db::upsert(&mut db, Id::new("derp", "flerp"), "2001:db8::1".parse().unwrap(), 0);
+    let script = nsupdate(&db, "example.com.", Some("ns1.example.com"), 60);
+
+    assert!(script.starts_with("server ns1.example.com\nzone example.com.\n"));
+    assert!(script.contains("update delete derp.example.com. A\n"));
+    assert!(script.contains("update add derp.example.com. 60 A 1.1.1.1\n"));
+    assert!(script.contains("update add derp.example.com. 60 AAAA 2001:db8::1\n"));
+    assert!(script.contains("update add herp.example.com. 60 AAAA ::1\n"));
+    assert!(!script.contains("update add herp.example.com. 60 A "));
+    assert!(script.ends_with("\nsend\n"));
+}
+
+#[test]
+fn nsupdate_skips_invalid_users() {
+    let db = seed(&[("Derp", "flerp", "1.1.1.1"), ("x.example.com. A 6.6.6.6\nsend\nupdate add y", "blerp", "2.2.2.2")]);
+    let script = nsupdate(&db, "example.com", None, 60);
+
+    assert_eq!(script, "\
+zone example.com.
+update delete derp.example.com. A
+update add derp.example.com. 60 A 1.1.1.1
+send
+");
+}
+
+#[test]
+fn zone_file_records() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("Herp", "blerp", "::1"), ("gone", "gone", "3.3.3.3")]);
+    db::upsert(&mut db, Id::new("derp", "flerp"), "2001:db8::1".parse().unwrap(), 5);
+    db::set_host(&mut db, Id::new("derp", "flerp"), "vps", "1.2.3.4".parse().unwrap(), 7, None).unwrap();
+    db::delete(&mut db, &Id::new("gone", "gone"), 8, None);
+    let zone = Zone::new("dyn.example.com.", Some(vec!["ns1.example.com".into(), "ns2.example.com".into()]), None, 60);
+
+    assert_eq!(zone_file(&db, &zone, 10), "\
+$ORIGIN dyn.example.com.
+$TTL 60
+@\tIN\tSOA\tns1.example.com. hostmaster.dyn.example.com. 7 3600 600 86400 60
+@\tIN\tNS\tns1.example.com.
+@\tIN\tNS\tns2.example.com.
+derp\tIN\tA\t1.1.1.1
+derp\tIN\tAAAA\t2001:db8::1
+herp\tIN\tAAAA\t::1
+vps.derp\tIN\tA\t1.2.3.4
+");
+}
+
+#[test]
+fn zone_file_skips_invalid_names() {
+    let db = seed(&[("derp", "flerp", "1.1.1.1"), ("x\tIN\tNS\tns.evil.\ny", "blerp", "2.2.2.2"), ("a.b", "c", "3.3.3.3")]);
+    let zone = Zone::new("dyn.example.com", None, None, 60);
+
+    let file = zone_file(&db, &zone, 10);
+    assert!(file.ends_with("@\tIN\tNS\tns.dyn.example.com.\nderp\tIN\tA\t1.1.1.1\n"));
+    assert!(!file.contains("evil"));
+}
+
+#[test]
+fn import_csv_rows() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1")]);
+    let csv = "user,password,ip\nherp,blerp,2.2.2.2\r\n\nlerp,merp,::1\n";
+
+    assert_eq!(import_csv(&mut db, csv, 0), Ok(2));
+    assert_eq!(ips(&db), ips(&seed(&[
+        ("derp", "flerp", "1.1.1.1"),
+        ("herp", "blerp", "2.2.2.2"),
+        ("lerp", "merp", "::1"),
+    ])));
+}
+
+#[test]
+fn import_csv_bad_row() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1")]);
+    let csv = "herp,blerp,2.2.2.2\nlerp,merp,999.1.1.1\n,nobody,3.3.3.3\n";
+
+    match import_csv(&mut db, csv, 0) {
+        Err(Import(e)) => assert_eq!(e, "line 2: invalid IP\nline 3: empty user"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(ips(&db), ips(&seed(&[("derp", "flerp", "1.1.1.1")])));
+}
+
+#[test]
+fn import_csv_header_only_on_first_line() {
+    let mut db = Records::new();
+    match import_csv(&mut db, "herp,blerp,2.2.2.2\nuser,password,ip\n", 0) {
+        Err(Import(e)) => assert_eq!(e, "line 2: invalid IP"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(db.list().next().is_none());
+}
+
+#[test]
+fn import_json_modes() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("herp", "blerp", "2.2.2.2")]);
+    let dump = snapshot::to_json(&seed(&[("herp", "blerp", "3.3.3.3"), ("lerp", "merp", "::1")])).to_string();
+
+    assert_eq!(import_json(&mut db, dump.as_bytes(), false), Ok((2, 0)));
+    assert_eq!(ips(&db), ips(&seed(&[
+        ("derp", "flerp", "1.1.1.1"),
+        ("herp", "blerp", "3.3.3.3"),
+        ("lerp", "merp", "::1"),
+    ])));
+
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1"), ("herp", "blerp", "2.2.2.2")]);
+    assert_eq!(import_json(&mut db, dump.as_bytes(), true), Ok((2, 1)));
+    assert_eq!(ips(&db), ips(&seed(&[("herp", "blerp", "3.3.3.3"), ("lerp", "merp", "::1")])));
+}
+
+#[test]
+fn import_json_bad_record() {
+    let mut db = seed(&[("derp", "flerp", "1.1.1.1")]);
+    let dump = r#"[{"user": "herp", "password": "blerp", "ip": "2.2.2.2"}]"#;
+
+    match import_json(&mut db, dump.as_bytes(), true) {
+        Err(Import(e)) => assert_eq!(e, "record 1: invalid record"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(import_json(&mut db, b"{}", true).is_err());
+    assert_eq!(ips(&db), ips(&seed(&[("derp", "flerp", "1.1.1.1")])));
+}
+
+#[test]
+fn update_frequency() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    for (ip, at) in [("1.1.1.1", 100), ("2001:db8::1", 160), ("2.2.2.2", 220), ("3.3.3.3", 400)] {
+        db::upsert(&mut db, derp.clone(), ip.parse().unwrap(), at);
+    }
+    db::upsert(&mut db, Id::new("herp", "blerp"), "::1".parse().unwrap(), 50);
+
+    assert_eq!(frequency(&db), vec![
+        ("derp", Frequency { changes: 4, average_interval: Some(100), v4: 3, v6: 1 }),
+        ("herp", Frequency { changes: 1, average_interval: None, v4: 0, v6: 1 }),
+    ]);
+}
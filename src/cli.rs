@@ -0,0 +1,182 @@
+use std::{convert::TryFrom, env, path::PathBuf};
+
+use crate::{config, Key};
+
+/// A flag that sets a variable, overriding the environment and config file
+struct Flag {
+    name: &'static str,
+    var: &'static str,
+    value: &'static str,
+    help: &'static str,
+    validate: fn(&str) -> Result<(), String>,
+}
+
+const FLAGS: &[Flag] = &[
+    Flag { name: "--host", var: "HOST", value: "ADDRESS", help: "addresses to listen on", validate: valid_host },
+    Flag { name: "--port", var: "PORT", value: "PORT", help: "port to listen on", validate: valid_port },
+    Flag {
+        name: "--key",
+        var: "KEY",
+        value: "USER:PASSWORD",
+        help: "run in single-user mode with this key",
+        validate: valid_key,
+    },
+    Flag {
+        name: "--admin-key",
+        var: "ADMIN_KEY",
+        value: "USER:PASSWORD",
+        help: "enable the admin routes with this key",
+        validate: valid_key,
+    },
+];
+
+/// What the command line asks for
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Run the server with these settings
+    Run(Args),
+    Help,
+    Version,
+}
+
+/// The settings given on the command line
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// The `--config` file
+    pub config: Option<PathBuf>,
+    /// Variables set by flags, as `(variable, value)`
+    pub vars: Vec<(&'static str, String)>,
+}
+
+impl Args {
+    /// Parse the process's arguments, printing help or the version and
+    /// exiting if asked to, or explaining the problem and exiting if they
+    /// are invalid
+    pub fn from_env() -> Self {
+        let args = env::args_os().skip(1).map(|arg| arg.to_string_lossy().into_owned());
+        match parse(args) {
+            Ok(Command::Run(args)) => args,
+            Ok(Command::Help) => {
+                print!("{}", help());
+                std::process::exit(0);
+            }
+            Ok(Command::Version) => {
+                println!("d5 {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("error: {}\n\nRun `d5 --help` for usage.", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Set the flags' variables, overriding the environment
+    pub fn apply(&self) {
+        for (var, value) in &self.vars {
+            env::set_var(var, value);
+        }
+    }
+}
+
+/// Parse `args`, not including the program name.  Flag values may follow as
+/// the next argument (`--port 8080`) or after `=` (`--port=8080`).
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.find('=') {
+            Some(eq) if arg.starts_with("--") => (arg[..eq].to_string(), Some(arg[eq + 1..].to_string())),
+            _ => (arg, None),
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            _ => {}
+        }
+        let mut value = || {
+            inline.clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} requires a value", flag))
+        };
+        if flag == "--config" {
+            parsed.config = Some(PathBuf::from(value()?));
+            continue;
+        }
+        let known = FLAGS.iter()
+            .find(|known| known.name == flag)
+            .ok_or_else(|| format!("unknown argument '{}'", flag))?;
+        let value = value()?;
+        (known.validate)(&value).map_err(|e| format!("invalid value '{}' for {}: {}", value, flag, e))?;
+        parsed.vars.retain(|(var, _)| *var != known.var);
+        parsed.vars.push((known.var, value));
+    }
+    Ok(Command::Run(parsed))
+}
+
+/// The `--help` text
+pub fn help() -> String {
+    let mut help = format!(
+        "d5 {}\n{}\n\nUSAGE:\n    d5 [OPTIONS]\n\nOPTIONS:\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("CARGO_PKG_DESCRIPTION"),
+    );
+    let mut options = vec![(
+        "--config <FILE>".to_string(),
+        "read settings from this TOML file (D5_CONFIG)".to_string(),
+    )];
+    options.extend(FLAGS.iter().map(|flag| {
+        (format!("{} <{}>", flag.name, flag.value), format!("{} ({})", flag.help, flag.var))
+    }));
+    options.push(("-h, --help".to_string(), "print this help and exit".to_string()));
+    options.push(("-V, --version".to_string(), "print the version and exit".to_string()));
+    for (option, text) in options {
+        help.push_str(&format!("    {:<30}{}\n", option, text));
+    }
+    help.push_str("\nEvery other setting is read from the environment; see the README.\n");
+    help
+}
+
+fn valid_host(value: &str) -> Result<(), String> {
+    match config::parse_addrs(value, 0) {
+        Ok(addrs) if !addrs.is_empty() => Ok(()),
+        _ => Err("expected comma-separated IP addresses, optionally with ports".to_string()),
+    }
+}
+
+fn valid_port(value: &str) -> Result<(), String> {
+    value.parse::<u16>().map(|_| ()).map_err(|_| "expected a port number from 0 to 65535".to_string())
+}
+
+fn valid_key(value: &str) -> Result<(), String> {
+    Key::try_from(value).map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+fn args(args: &[&str]) -> Result<Command, String> {
+    parse(args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn parse_flags() {
+    let expected = Args {
+        config: Some(PathBuf::from("/etc/d5.toml")),
+        vars: vec![("HOST", "0.0.0.0".to_string()), ("KEY", "derp:flerp".to_string()), ("PORT", "8080".to_string())],
+    };
+    let parsed = args(&["--host", "0.0.0.0", "--port=1", "--config", "/etc/d5.toml", "--key=derp:flerp", "--port", "8080"]);
+    assert_eq!(parsed, Ok(Command::Run(expected)));
+
+    assert_eq!(args(&[]), Ok(Command::Run(Args::default())));
+    assert_eq!(args(&["--port", "8080", "--help"]), Ok(Command::Help));
+    assert_eq!(args(&["-V"]), Ok(Command::Version));
+}
+
+#[test]
+fn parse_errors() {
+    assert_eq!(args(&["--port", "http"]), Err("invalid value 'http' for --port: expected a port number from 0 to 65535".to_string()));
+    assert_eq!(args(&["--host", "d5.local"]), Err("invalid value 'd5.local' for --host: expected comma-separated IP addresses, optionally with ports".to_string()));
+    assert!(args(&["--key", "derp"]).unwrap_err().starts_with("invalid value 'derp' for --key: "));
+    assert_eq!(args(&["--port"]), Err("--port requires a value".to_string()));
+    assert_eq!(args(&["--prot", "80"]), Err("unknown argument '--prot'".to_string()));
+    assert_eq!(args(&["8080"]), Err("unknown argument '8080'".to_string()));
+    assert!(help().contains("--port <PORT>"));
+}
@@ -0,0 +1,747 @@
+use std::{
+    convert::TryFrom,
+    env,
+    fmt,
+    fs,
+    net,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use warp::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::{db, metrics, rfc2136::TsigKey, toml, Key};
+
+/// Configuration file read at startup, if it exists, unless another is named
+const DEFAULT_CONFIG_FILE: &str = "d5.toml";
+
+/// Port listened on when neither `PORT` nor `HOST` names one
+const DEFAULT_PORT: u16 = 3030;
+
+/// Port DNS queries are answered on, and updates sent to, when `DNS_ADDR` or
+/// `NSUPDATE_SERVER` doesn't name one
+const DEFAULT_DNS_PORT: u16 = 53;
+
+/// Headers added to every response unless overridden by `RESPONSE_HEADERS`
+const DEFAULT_RESPONSE_HEADERS: &[(&str, &str)] = &[("x-content-type-options", "nosniff")];
+
+/// Address families that may be registered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Families {
+    pub v4: bool,
+    pub v6: bool,
+}
+
+impl Families {
+    /// Whether `ip` may be stored
+    pub fn allows(&self, ip: net::IpAddr) -> bool {
+        match ip {
+            net::IpAddr::V4(_) => self.v4,
+            net::IpAddr::V6(_) => self.v6,
+        }
+    }
+}
+
+impl Default for Families {
+    fn default() -> Self {
+        Families { v4: true, v6: true }
+    }
+}
+
+/// Which users may do something: nobody, everyone (`true`), or a
+/// comma-separated list of usernames
+#[derive(Debug, Clone, PartialEq)]
+pub enum Permit {
+    Nobody,
+    Everyone,
+    Users(Vec<String>),
+}
+
+impl Permit {
+    pub fn allows(&self, user: &str) -> bool {
+        match self {
+            Permit::Nobody => false,
+            Permit::Everyone => true,
+            Permit::Users(users) => users.iter().any(|u| u == user),
+        }
+    }
+}
+
+/// Read a `Permit`; unset or `false` permits nobody
+fn env_permit(var: &str) -> Permit {
+    match env_list(var) {
+        None => Permit::Nobody,
+        Some(list) => match list.as_slice() {
+            [] => Permit::Nobody,
+            [flag] if flag.eq_ignore_ascii_case("false") => Permit::Nobody,
+            [flag] if flag.eq_ignore_ascii_case("true") => Permit::Everyone,
+            _ => Permit::Users(list),
+        },
+    }
+}
+
+/// Which hop of a forwarded chain is the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XffPolicy {
+    /// The first, as the client (or the first proxy) reported itself
+    First,
+    /// The last, as added by the proxy nearest d5
+    Last,
+    /// The last not added by one of `TRUSTED_PROXIES`
+    RightmostUntrusted,
+}
+
+impl std::str::FromStr for XffPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(XffPolicy::First),
+            "last" => Ok(XffPolicy::Last),
+            "rightmost-untrusted" => Ok(XffPolicy::RightmostUntrusted),
+            _ => Err(format!("invalid policy '{}'; expected `first`, `last` or `rightmost-untrusted`", s)),
+        }
+    }
+}
+
+/// A network such as `10.0.0.0/8`; a bare address is a network of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: net::IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the network
+    pub fn contains(&self, ip: net::IpAddr) -> bool {
+        let mask = |bits: u32| (!0u128).checked_shl(bits - u32::from(self.prefix)).unwrap_or(0);
+        match (self.addr, ip) {
+            (net::IpAddr::V4(net), net::IpAddr::V4(ip)) => {
+                (u128::from(u32::from(net)) ^ u128::from(u32::from(ip))) & mask(32) == 0
+            }
+            (net::IpAddr::V6(net), net::IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(ip)) & mask(128) == 0,
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid network '{}'", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<net::IpAddr>().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&p| p <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Runtime configuration, read from env variables
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Addresses to listen on, each served by its own server
+    pub addrs: Vec<net::SocketAddr>,
+    /// Unix domain socket to listen on instead of `addr` and `port`
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of `unix_socket`
+    pub unix_socket_mode: u32,
+    /// How many times to retry binding the port while it is in use
+    pub bind_retries: u32,
+    /// Keys for single-user mode, `USER:PASSWORD`; empty in multi-user mode
+    pub keys: Vec<Key>,
+    /// How long a key replaced via `/admin/key` keeps working, in seconds
+    pub key_rotation_grace: Option<u64>,
+    /// Optional key for the admin routes; `USER:PASSWORD`
+    pub admin_key: Option<Key>,
+    /// File listing the only users allowed to store an IP
+    pub credentials_file: Option<PathBuf>,
+    /// Optional cap on the number of records a single username may create
+    pub max_records: Option<usize>,
+    /// How many address changes each record remembers for `GET /history`
+    pub history_size: usize,
+    /// Recovery window for deleted records, in seconds; disabled when unset
+    pub soft_delete: Option<u64>,
+    /// How long a record lasts without being refreshed, in seconds; records
+    /// last forever when unset
+    pub record_ttl: Option<u64>,
+    /// Minimum time between notifications of a record's IP changes, in
+    /// seconds; changes arriving sooner are stored but held back
+    pub min_update_interval: u64,
+    /// Shell command run on every confirmed IP change; off by default
+    pub on_change_cmd: Option<String>,
+    /// How long `on_change_cmd` may run before it is killed
+    pub on_change_timeout: Duration,
+    /// Append-only log every mutation is written to and replayed from
+    pub event_log: Option<PathBuf>,
+    /// How often to fold the event log into its snapshot, in seconds
+    pub compact_interval: u64,
+    /// JSON file the records are periodically saved to and loaded from
+    pub snapshot_path: Option<PathBuf>,
+    /// How often to save `snapshot_path`, in seconds
+    pub snapshot_interval: u64,
+    /// Write a digest after each snapshot, and refuse one without a matching
+    /// digest on load
+    pub snapshot_checksum: bool,
+    /// Keep an index of each username's records for faster admin lookups
+    pub user_index: bool,
+    /// Cap on the number of records exposed by `/metrics/entries`
+    pub metrics_max_entries: usize,
+    /// MaxMind DB files to annotate stored IPs with their country and ASN
+    pub geoip_db: Option<Vec<PathBuf>>,
+    /// UDP address to answer DNS queries for `dns_zone` on
+    pub dns_addr: Option<net::SocketAddr>,
+    /// Zone whose `USER.ZONE` names resolve to the stored addresses
+    pub dns_zone: Option<String>,
+    /// The zone's nameservers; `ns.ZONE` when unset
+    pub dns_nameservers: Option<Vec<String>>,
+    /// The zone's contact; `hostmaster.ZONE` when unset
+    pub dns_hostmaster: Option<String>,
+    /// TTL of the DNS records served, in seconds
+    pub dns_ttl: u32,
+    /// File the acme-dns API's accounts are saved to; the API is only
+    /// served when set
+    pub acme_dns_file: Option<PathBuf>,
+    /// Let `POST /acme-dns/register` create accounts without credentials
+    pub acme_dns_open_registration: bool,
+    /// Answer the HTTP connector of PowerDNS's remote backend under `/dns`
+    pub pdns_backend: bool,
+    /// Peers allowed to use `pdns_backend`'s routes
+    pub pdns_backend_allow: Vec<Cidr>,
+    /// Authoritative server `dns_zone`'s address changes are sent to as
+    /// RFC 2136 updates
+    pub nsupdate_server: Option<net::SocketAddr>,
+    /// TSIG key the updates are signed with
+    pub nsupdate_key: Option<TsigKey>,
+    /// Names of tenants with their own independent stores, selected by the
+    /// `X-Tenant` header or the first label of `Host`
+    pub tenants: Option<Vec<String>>,
+    /// When set, only requests with one of these `Host` headers are served
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Paths served regardless of `allowed_hosts` (e.g. health checks)
+    pub host_check_exempt: Vec<String>,
+    /// Fraction (0.0–1.0) of successful reads to log; errors and writes are
+    /// always logged
+    pub log_sample_rate: f64,
+    /// Longest `X-Forwarded-For` chain accepted, in entries
+    pub max_xff_entries: Option<usize>,
+    /// Proxies whose forwarded headers are believed; every peer's are when unset
+    pub trusted_proxies: Option<Vec<Cidr>>,
+    /// Which hop of the forwarded chain to record; by default the rightmost
+    /// untrusted with `trusted_proxies`, else the first
+    pub xff_policy: Option<XffPolicy>,
+    /// Requests a minute allowed from each client IP; unlimited when unset
+    pub rate_limit: Option<u32>,
+    /// Failed authentications after which a client IP or username is locked
+    /// out; never when unset
+    pub lockout_threshold: Option<u32>,
+    /// How long the first lockout lasts, in seconds; each further failure
+    /// doubles it
+    pub lockout_duration: u64,
+    /// Client networks exempt from `rate_limit` and the lockout
+    pub ratelimit_exempt: Vec<Cidr>,
+    /// Address families `post` accepts
+    pub allowed_families: Families,
+    /// Refuse to store private, loopback, link-local and other reserved addresses
+    pub reject_private: bool,
+    /// Who may store an address other than their own with `POST /?myip=IP`
+    pub allow_ip_override: Permit,
+    /// Who may store an address other than their own in a POST's body
+    pub allow_ip_body: Permit,
+    /// Allow `GET /?redirect=URL` to redirect callers to a URL containing their IP
+    pub show_redirect: bool,
+    /// Respond to `show` with an empty `204` instead of echoing the IP
+    pub show_no_content: bool,
+    /// Redirect requests the reverse proxy received over plain HTTP to HTTPS
+    pub https_redirect: bool,
+    /// "Echo my IP" service used to detect the server's own public IP
+    pub server_ip_url: Option<String>,
+    /// How often to detect the server's public IP again
+    pub server_ip_refresh: Option<Duration>,
+    /// How long to wait for in-flight requests when shutting down
+    pub shutdown_timeout: Duration,
+    /// File the record table is written to on `SIGUSR1`, instead of stderr
+    pub dump_path: Option<PathBuf>,
+    /// Message for the branded 404 served for unknown paths
+    pub custom_404: Option<String>,
+    /// Headers added to every response, including errors
+    pub response_headers: HeaderMap,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addrs: vec![net::SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT))],
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            bind_retries: 0,
+            keys: Vec::new(),
+            key_rotation_grace: None,
+            admin_key: None,
+            credentials_file: None,
+            max_records: None,
+            history_size: db::DEFAULT_HISTORY,
+            soft_delete: None,
+            record_ttl: None,
+            min_update_interval: 0,
+            on_change_cmd: None,
+            on_change_timeout: Duration::from_secs(10),
+            event_log: None,
+            compact_interval: 3600,
+            snapshot_path: None,
+            snapshot_interval: 300,
+            snapshot_checksum: false,
+            user_index: false,
+            metrics_max_entries: metrics::DEFAULT_MAX_ENTRIES,
+            geoip_db: None,
+            dns_addr: None,
+            dns_zone: None,
+            dns_nameservers: None,
+            dns_hostmaster: None,
+            dns_ttl: crate::DEFAULT_TTL,
+            acme_dns_file: None,
+            acme_dns_open_registration: false,
+            pdns_backend: false,
+            pdns_backend_allow: vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            nsupdate_server: None,
+            nsupdate_key: None,
+            tenants: None,
+            allowed_hosts: None,
+            host_check_exempt: Vec::new(),
+            log_sample_rate: 1.0,
+            max_xff_entries: None,
+            trusted_proxies: None,
+            xff_policy: None,
+            rate_limit: None,
+            lockout_threshold: None,
+            lockout_duration: 60,
+            ratelimit_exempt: Vec::new(),
+            allowed_families: Families::default(),
+            reject_private: false,
+            allow_ip_override: Permit::Nobody,
+            allow_ip_body: Permit::Nobody,
+            show_redirect: false,
+            show_no_content: false,
+            https_redirect: false,
+            server_ip_url: None,
+            server_ip_refresh: None,
+            shutdown_timeout: Duration::from_secs(30),
+            dump_path: None,
+            custom_404: None,
+            response_headers: DEFAULT_RESPONSE_HEADERS.iter()
+                .map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value)))
+                .collect(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Config::default();
+        Config {
+            addrs: env_addrs("HOST", env_parse("PORT").unwrap_or(DEFAULT_PORT)).unwrap_or(default.addrs),
+            unix_socket: env_unix_socket("LISTEN"),
+            unix_socket_mode: env::var("LISTEN_MODE").ok()
+                .and_then(|mode| u32::from_str_radix(mode.trim(), 8).ok())
+                .unwrap_or(default.unix_socket_mode),
+            bind_retries: env_parse("BIND_RETRIES").unwrap_or(default.bind_retries),
+            keys: try_env_keys().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+            key_rotation_grace: env_parse("KEY_ROTATION_GRACE"),
+            admin_key: env_key("ADMIN_KEY"),
+            credentials_file: env::var_os("CREDENTIALS_FILE").map(PathBuf::from),
+            max_records: env_parse("MAX_RECORDS_PER_USER"),
+            history_size: env_parse("HISTORY_SIZE").unwrap_or(default.history_size),
+            soft_delete: env_parse("SOFT_DELETE"),
+            record_ttl: env_parse("RECORD_TTL"),
+            min_update_interval: env_parse("MIN_UPDATE_INTERVAL").unwrap_or(default.min_update_interval),
+            on_change_cmd: env::var("ON_CHANGE_CMD").ok().filter(|cmd| !cmd.trim().is_empty()),
+            on_change_timeout: env_parse("ON_CHANGE_TIMEOUT")
+                .map_or(default.on_change_timeout, Duration::from_secs),
+            event_log: env::var_os("EVENT_LOG").map(PathBuf::from),
+            compact_interval: env_parse("COMPACT_INTERVAL").unwrap_or(default.compact_interval),
+            snapshot_path: env::var_os("SNAPSHOT_PATH").map(PathBuf::from),
+            snapshot_interval: env_parse("SNAPSHOT_INTERVAL").unwrap_or(default.snapshot_interval),
+            snapshot_checksum: env_parse("SNAPSHOT_CHECKSUM").unwrap_or(default.snapshot_checksum),
+            user_index: env_parse("USER_INDEX").unwrap_or(default.user_index),
+            metrics_max_entries: env_parse("METRICS_MAX_ENTRIES").unwrap_or(default.metrics_max_entries),
+            geoip_db: env_list("GEOIP_DB").map(|paths| paths.into_iter().map(PathBuf::from).collect()),
+            dns_addr: env_addrs("DNS_ADDR", DEFAULT_DNS_PORT).and_then(|addrs| addrs.into_iter().next()),
+            dns_zone: env::var("DNS_ZONE").ok().filter(|zone| !zone.trim().is_empty()),
+            dns_nameservers: env_list("DNS_NS"),
+            dns_hostmaster: env::var("DNS_HOSTMASTER").ok(),
+            dns_ttl: env_parse("DNS_TTL").unwrap_or(default.dns_ttl),
+            acme_dns_file: env::var_os("ACME_DNS_FILE").map(PathBuf::from),
+            acme_dns_open_registration: env_parse("ACME_DNS_OPEN_REGISTRATION").unwrap_or(default.acme_dns_open_registration),
+            pdns_backend: env_parse("PDNS_BACKEND").unwrap_or(default.pdns_backend),
+            pdns_backend_allow: env_cidrs("PDNS_BACKEND_ALLOW").unwrap_or(default.pdns_backend_allow),
+            nsupdate_server: env_addrs("NSUPDATE_SERVER", DEFAULT_DNS_PORT).and_then(|addrs| addrs.into_iter().next()),
+            nsupdate_key: env::var("NSUPDATE_KEY").ok().map(|key| key.parse().unwrap_or_else(|e| {
+                eprintln!("Invalid NSUPDATE_KEY: {}!", e);
+                std::process::exit(1);
+            })),
+            tenants: env_list("TENANTS"),
+            allowed_hosts: env_list("ALLOWED_HOSTS"),
+            host_check_exempt: env_list("HOST_CHECK_EXEMPT").unwrap_or_default(),
+            log_sample_rate: env_parse::<f64>("LOG_SAMPLE_RATE")
+                .map_or(default.log_sample_rate, |r| r.clamp(0.0, 1.0)),
+            max_xff_entries: env_parse("MAX_XFF_ENTRIES"),
+            trusted_proxies: env_cidrs("TRUSTED_PROXIES"),
+            xff_policy: env::var("XFF_POLICY").ok().map(|policy| policy.parse().unwrap_or_else(|e| {
+                eprintln!("Invalid XFF_POLICY: {}!", e);
+                std::process::exit(1);
+            })),
+            rate_limit: env_parse("RATE_LIMIT").filter(|&n| n > 0),
+            lockout_threshold: env_parse("LOCKOUT_THRESHOLD").filter(|&n| n > 0),
+            lockout_duration: env_parse("LOCKOUT_DURATION").unwrap_or(default.lockout_duration),
+            ratelimit_exempt: env_cidrs("RATELIMIT_EXEMPT_NETWORKS").unwrap_or_default(),
+            allowed_families: env_families("ALLOWED_FAMILIES").unwrap_or(default.allowed_families),
+            reject_private: env_parse("REJECT_PRIVATE").unwrap_or(default.reject_private),
+            allow_ip_override: env_permit("ALLOW_IP_OVERRIDE"),
+            allow_ip_body: env_permit("ALLOW_IP_BODY"),
+            show_redirect: env_parse("SHOW_REDIRECT").unwrap_or(default.show_redirect),
+            show_no_content: env_parse("SHOW_NO_CONTENT").unwrap_or(default.show_no_content),
+            https_redirect: env_parse("HTTPS_REDIRECT").unwrap_or(default.https_redirect),
+            server_ip_url: env::var("SERVER_IP_URL").ok(),
+            server_ip_refresh: env_parse("SERVER_IP_REFRESH").map(Duration::from_secs),
+            shutdown_timeout: env_parse("SHUTDOWN_TIMEOUT")
+                .map_or(default.shutdown_timeout, Duration::from_secs),
+            dump_path: env::var_os("DUMP_PATH").map(PathBuf::from),
+            custom_404: env::var("CUSTOM_404").ok(),
+            response_headers: with_hsts(
+                env_headers("RESPONSE_HEADERS", default.response_headers),
+                env_parse("HSTS_MAX_AGE"),
+            ),
+        }
+    }
+}
+
+/// The configuration file, and the variables it has set
+#[derive(Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    vars: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Read the file, setting each of its variables that isn't otherwise set
+    /// in the environment, so env variables override the file.  Variables a
+    /// previous read set but the file no longer does are unset.
+    pub fn reload(&mut self) -> Result<(), String> {
+        let src = fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+        let vars = toml::parse(&src)?;
+        for var in self.vars.drain(..) {
+            env::remove_var(var);
+        }
+        for (var, value) in vars {
+            if env::var_os(&var).is_none() {
+                env::set_var(&var, value);
+                self.vars.push(var);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load the configuration file: `path`, or else `D5_CONFIG`, or else
+/// `d5.toml` if it exists.  Exits if the file can't be read or parsed.
+pub fn load_file(path: Option<PathBuf>) -> Option<ConfigFile> {
+    let path = match path.or_else(|| env::var_os("D5_CONFIG").map(PathBuf::from)) {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => PathBuf::from(DEFAULT_CONFIG_FILE),
+        None => return None,
+    };
+    let mut file = ConfigFile { path, vars: Vec::new() };
+    if let Err(e) = file.reload() {
+        eprintln!("Invalid config file {}: {}", file.path.display(), e);
+        std::process::exit(1);
+    }
+    Some(file)
+}
+
+/// Parse an env variable, exiting if it is invalid
+fn env_parse<T: std::str::FromStr>(var: &str) -> Option<T> {
+    try_env_parse(var).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Parse an env variable; an unset or empty one is `None`
+fn try_env_parse<T: std::str::FromStr>(var: &str) -> Result<Option<T>, String> {
+    match env::var(var).ok().filter(|v| !v.trim().is_empty()) {
+        Some(v) => v.trim().parse().map(Some).map_err(|_| format!("Invalid {}: '{}'!", var, v)),
+        None => Ok(None),
+    }
+}
+
+/// Read a comma- (or newline-) separated list from an env variable
+fn env_list(var: &str) -> Option<Vec<String>> {
+    env::var(var).ok().map(|v| {
+        v.split(&[',', '\n'][..])
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Read a list of `v4`/`v6` address families, exiting if it is malformed
+fn env_families(var: &str) -> Option<Families> {
+    let list = env_list(var)?;
+    let mut families = Families { v4: false, v6: false };
+    for family in &list {
+        match family.to_ascii_lowercase().as_str() {
+            "v4" => families.v4 = true,
+            "v6" => families.v6 = true,
+            _ => {
+                eprintln!("Invalid {} family '{}'!", var, family);
+                std::process::exit(1);
+            }
+        }
+    }
+    Some(families).filter(|_| !list.is_empty())
+}
+
+/// Read a list of networks, exiting if it is malformed
+fn env_cidrs(var: &str) -> Option<Vec<Cidr>> {
+    let list = env_list(var)?;
+    match list.iter().map(|cidr| cidr.parse()).collect() {
+        Ok(cidrs) => Some(cidrs),
+        Err(e) => {
+            eprintln!("Invalid {}: {}!", var, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parse a comma-separated list of addresses, each an IP (listening on
+/// `port`), `localhost`, or an IP and port such as `[::1]:8080`
+pub fn parse_addrs(value: &str, port: u16) -> Result<Vec<net::SocketAddr>, String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| match addr {
+            "localhost" => Ok(net::SocketAddr::from(([127, 0, 0, 1], port))),
+            _ => addr.parse::<net::SocketAddr>()
+                .or_else(|_| addr.parse::<net::IpAddr>().map(|ip| net::SocketAddr::new(ip, port)))
+                .map_err(|_| format!("invalid address '{}'", addr)),
+        })
+        .collect()
+}
+
+/// Read a list of addresses to listen on, exiting if it is malformed
+fn env_addrs(var: &str, port: u16) -> Option<Vec<net::SocketAddr>> {
+    let value = env::var(var).ok()?;
+    match parse_addrs(&value, port) {
+        Ok(addrs) => Some(addrs).filter(|addrs| !addrs.is_empty()),
+        Err(e) => {
+            eprintln!("Invalid {}: {}!", var, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Read a `unix:PATH` listen address, exiting if it is malformed
+fn env_unix_socket(var: &str) -> Option<PathBuf> {
+    let value = env::var(var).ok()?;
+    match value.trim().strip_prefix("unix:") {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => {
+            eprintln!("Invalid {} '{}'; expected `unix:PATH`!", var, value);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Add the `Name: value` lines of an env variable to `headers`, exiting if
+/// a line is malformed
+fn env_headers(var: &str, mut headers: HeaderMap) -> HeaderMap {
+    for line in env::var(var).unwrap_or_default().lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match parse_header(line) {
+            Some((name, value)) => {
+                headers.insert(name, value);
+            }
+            None => {
+                eprintln!("Invalid {} header '{}'!", var, line);
+                std::process::exit(1);
+            }
+        }
+    }
+    headers
+}
+
+/// Add a `Strict-Transport-Security` header with `max_age`, unless
+/// `RESPONSE_HEADERS` already sets one.  Clients ignore it over plain HTTP, so
+/// it only takes effect on secure responses.
+fn with_hsts(mut headers: HeaderMap, max_age: Option<u64>) -> HeaderMap {
+    if let Some(max_age) = max_age {
+        if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+            headers.entry("strict-transport-security").map(|e| e.or_insert(value)).ok();
+        }
+    }
+    headers
+}
+
+/// Parse a `Name: value` header line
+fn parse_header(line: &str) -> Option<(HeaderName, HeaderValue)> {
+    let mut split = line.splitn(2, ':');
+    let name = HeaderName::from_bytes(split.next()?.trim().as_bytes()).ok()?;
+    let value = HeaderValue::from_str(split.next()?.trim()).ok()?;
+    Some((name, value))
+}
+
+/// Read a `USER:PASSWORD` key from an env variable, exiting if it is malformed
+fn env_key(var: &str) -> Option<Key> {
+    try_env_key(var).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Read the comma-separated `KEY` list and the `USER:PASSWORD` lines of
+/// `KEY_FILE`
+pub fn try_env_keys() -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    for k in env_list("KEY").unwrap_or_default() {
+        keys.push(Key::try_from(k.as_str()).map_err(|_| "Invalid KEY!".to_string())?);
+    }
+    if let Some(path) = env::var_os("KEY_FILE").map(PathBuf::from) {
+        let src = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read KEY_FILE {}: {}", path.display(), e))?;
+        let lines = src.lines().map(str::trim).enumerate().filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+        for (n, line) in lines {
+            keys.push(Key::try_from(line).map_err(|_| format!("Invalid KEY_FILE line {}!", n + 1))?);
+        }
+    }
+    keys.dedup();
+    Ok(keys)
+}
+
+/// Read a `USER:PASSWORD` key from an env variable
+pub fn try_env_key(var: &str) -> Result<Option<Key>, String> {
+    match env::var(var) {
+        Ok(k) => Key::try_from(k.as_str()).map(Some).map_err(|_| format!("Invalid {}!", var)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[test]
+fn parse_response_header() {
+    let (name, value) = parse_header("Strict-Transport-Security: max-age=63072000; preload").unwrap();
+    assert_eq!(name, "strict-transport-security");
+    assert_eq!(value, "max-age=63072000; preload");
+
+    assert!(parse_header("X-Empty:").is_some());
+    assert!(parse_header("no colon").is_none());
+    assert!(parse_header("Bad Name: value").is_none());
+}
+
+#[test]
+fn allowed_families() {
+    let (a, aaaa) = ("1.1.1.1".parse().unwrap(), "::1".parse().unwrap());
+    let v4 = Families { v4: true, v6: false };
+    assert!(v4.allows(a));
+    assert!(!v4.allows(aaaa));
+
+    let v6 = Families { v4: false, v6: true };
+    assert!(v6.allows(aaaa));
+    assert!(!v6.allows(a));
+
+    assert!(Families::default().allows(a));
+    assert!(Families::default().allows(aaaa));
+}
+
+#[test]
+fn cidr_contains() {
+    let contains = |cidr: &str, ip: &str| cidr.parse::<Cidr>().unwrap().contains(ip.parse().unwrap());
+    assert!(contains("10.0.0.0/8", "10.255.0.1"));
+    assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+    assert!(contains("172.16.0.0/12", "172.31.255.255"));
+    assert!(!contains("172.16.0.0/12", "172.32.0.0"));
+    assert!(contains("127.0.0.1", "127.0.0.1"));
+    assert!(!contains("127.0.0.1", "127.0.0.2"));
+    assert!(contains("0.0.0.0/0", "8.8.8.8"));
+    assert!(!contains("0.0.0.0/0", "::1"));
+    assert!(contains("fd00::/8", "fd12::1"));
+    assert!(contains("::/0", "2001:db8::1"));
+    assert!(!contains("::1", "::2"));
+    assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err("invalid network '10.0.0.0/33'".to_string()));
+    assert!("proxy.local".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn parse_env() {
+    let with = |value: &str| {
+        env::set_var("D5_TEST_PARSE", value);
+        try_env_parse::<u32>("D5_TEST_PARSE")
+    };
+    assert_eq!(with("5"), Ok(Some(5)));
+    assert_eq!(with(" 5 "), Ok(Some(5)));
+    assert_eq!(with(""), Ok(None));
+    assert_eq!(with("abc"), Err("Invalid D5_TEST_PARSE: 'abc'!".to_string()));
+    env::set_var("D5_TEST_PARSE", "1");
+    assert!(try_env_parse::<bool>("D5_TEST_PARSE").is_err());
+    env::remove_var("D5_TEST_PARSE");
+    assert_eq!(try_env_parse::<u32>("D5_TEST_PARSE"), Ok(None));
+}
+
+#[test]
+fn permits() {
+    let with = |value: &str| {
+        env::set_var("D5_TEST_PERMIT", value);
+        env_permit("D5_TEST_PERMIT")
+    };
+    assert_eq!(with("true"), Permit::Everyone);
+    assert_eq!(with("FALSE"), Permit::Nobody);
+    assert_eq!(with(""), Permit::Nobody);
+    assert_eq!(with("derp, herp"), Permit::Users(vec!["derp".into(), "herp".into()]));
+    env::remove_var("D5_TEST_PERMIT");
+    assert_eq!(env_permit("D5_TEST_PERMIT"), Permit::Nobody);
+
+    assert!(Permit::Users(vec!["derp".into()]).allows("derp"));
+    assert!(!Permit::Users(vec!["derp".into()]).allows("herp"));
+    assert!(Permit::Everyone.allows("herp"));
+    assert!(!Permit::Nobody.allows("derp"));
+}
+
+#[test]
+fn xff_policy() {
+    assert_eq!("first".parse(), Ok(XffPolicy::First));
+    assert_eq!(" Last".parse(), Ok(XffPolicy::Last));
+    assert_eq!("rightmost-untrusted".parse(), Ok(XffPolicy::RightmostUntrusted));
+    assert!("rightmost".parse::<XffPolicy>().is_err());
+}
+
+#[test]
+fn listen_addrs() {
+    let addrs = parse_addrs("192.168.1.5, [::1]:8080,localhost, ::", 3030).unwrap();
+    let expected = ["192.168.1.5:3030", "[::1]:8080", "127.0.0.1:3030", "[::]:3030"];
+    assert_eq!(addrs, expected.iter().map(|a| a.parse().unwrap()).collect::<Vec<net::SocketAddr>>());
+    assert_eq!(parse_addrs("0.0.0.0:http", 3030), Err("invalid address '0.0.0.0:http'".to_string()));
+}
+
+#[test]
+fn hsts_header() {
+    let headers = with_hsts(HeaderMap::new(), Some(63_072_000));
+    assert_eq!(headers["strict-transport-security"], "max-age=63072000");
+    let (name, value) = parse_header("Strict-Transport-Security: max-age=1; preload").unwrap();
+    let headers = with_hsts(std::iter::once((name, value)).collect(), Some(63_072_000));
+    assert_eq!(headers["strict-transport-security"], "max-age=1; preload");
+    assert!(with_hsts(HeaderMap::new(), None).is_empty());
+}
@@ -0,0 +1,1100 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::iter::{self, FromIterator};
+use std::net::IpAddr;
+use std::ops::{Deref, Index};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use crate::events::Journal;
+use crate::id::{constant_time_eq, Id};
+use crate::storage::Storage;
+use crate::Err::{self, *};
+
+/// Most named hosts a record may have
+const MAX_HOSTS: usize = 100;
+/// Default number of address changes each record remembers
+pub const DEFAULT_HISTORY: usize = 20;
+
+/// The record store, optionally indexed by username.  Reads go through
+/// `Deref`; every mutation goes through this module so the index stays in step.
+#[derive(Debug)]
+pub struct Records {
+    records: Box<dyn Storage>,
+    by_user: Option<HashMap<String, HashSet<Id>>>,
+    /// The only credentials allowed to store an IP in single-user mode; any
+    /// of them may
+    keys: Vec<Id>,
+    /// Where every mutation is appended, when `EVENT_LOG` is set
+    journal: Option<Journal>,
+    /// How many address changes each record remembers
+    history: usize,
+}
+
+impl Records {
+    pub fn new() -> Self {
+        Records::with_storage(HashMap::new())
+    }
+
+    /// A store keeping its records in `storage` rather than in memory
+    pub fn with_storage(storage: impl Storage + 'static) -> Self {
+        Records { records: Box::new(storage), by_user: None, keys: Vec::new(), journal: None, history: DEFAULT_HISTORY }
+    }
+
+    /// A store that also keeps an index of each username's records
+    pub fn indexed() -> Self {
+        Records { by_user: Some(HashMap::new()), ..Records::new() }
+    }
+
+    /// Run in single-user mode, allowing only `keys` to store an IP; an empty
+    /// list means multi-user mode
+    pub fn with_keys(self, keys: Vec<Id>) -> Self {
+        Records { keys, ..self }
+    }
+
+    /// Remember the last `history` address changes of each record
+    pub fn with_history(self, history: usize) -> Self {
+        Records { history, ..self }
+    }
+
+    /// Append every mutation to `journal`
+    pub fn with_journal(self, journal: Journal) -> Self {
+        Records { journal: Some(journal), ..self }
+    }
+
+    /// The single-user keys, empty unless running in single-user mode
+    pub fn keys(&self) -> &[Id] {
+        &self.keys
+    }
+
+    /// Every record that hasn't been soft-deleted
+    pub fn live(&self) -> impl Iterator<Item = (&Id, &Entry)> {
+        self.records.list().filter(|(_, e)| !e.is_deleted())
+    }
+
+    /// The record for `id`, unless it has been soft-deleted
+    pub fn get_live(&self, id: &Id) -> Option<&Entry> {
+        self.records.get(id).filter(|e| !e.is_deleted())
+    }
+
+    /// The ids of every record belonging to `user`
+    pub fn user_ids(&self, user: &str) -> Vec<&Id> {
+        match &self.by_user {
+            Some(index) => index.get(user).map_or_else(Vec::new, |ids| ids.iter().collect()),
+            None => self.records.list().map(|(id, _)| id).filter(|id| id.user == user).collect(),
+        }
+    }
+
+    /// The credentials of the live record that minted `token`
+    pub fn token_owner(&self, token: &str) -> Option<&Id> {
+        let hash = token_hash(token);
+        // Check every token, so the time taken doesn't reveal which matched
+        self.live().fold(None, |found, (id, e)| {
+            let minted = e.tokens.iter().fold(false, |m, t| constant_time_eq(t.hash.as_bytes(), hash.as_bytes()) | m);
+            if minted { Some(id) } else { found }
+        })
+    }
+
+    /// The credentials of the live record whose update URL has `key`
+    pub fn update_key_owner(&self, key: &str) -> Option<&Id> {
+        let hash = token_hash(key);
+        self.live().fold(None, |found, (id, e)| {
+            let matched = e.update_key.as_ref().is_some_and(|k| constant_time_eq(k.as_bytes(), hash.as_bytes()));
+            if matched { Some(id) } else { found }
+        })
+    }
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Records::new()
+    }
+}
+
+impl Deref for Records {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.records
+    }
+}
+
+impl Index<&Id> for Records {
+    type Output = Entry;
+
+    fn index(&self, id: &Id) -> &Entry {
+        self.records.get(id).expect("no record for id")
+    }
+}
+
+impl FromIterator<(Id, Entry)> for Records {
+    fn from_iter<I: IntoIterator<Item = (Id, Entry)>>(iter: I) -> Self {
+        Records::with_storage(iter.into_iter().collect::<HashMap<_, _>>())
+    }
+}
+
+/// A stored record
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The address stored most recently, of either family
+    pub ip: IpAddr,
+    /// The latest address of the other family, if one has been stored
+    pub other_ip: Option<IpAddr>,
+    /// Stable identifier, assigned when the record is created
+    pub uuid: String,
+    /// When the record was first created, in Unix seconds
+    pub created_at: u64,
+    /// When the record was last updated or touched, in Unix seconds
+    pub updated_at: u64,
+    /// The address the last update or touch came from, if known
+    pub updated_from: Option<IpAddr>,
+    /// When the record was soft-deleted, in Unix seconds
+    pub deleted_at: Option<u64>,
+    /// When the record's credentials were last used successfully, on any
+    /// route, in Unix seconds
+    pub last_auth: u64,
+    /// Credentials replaced by a key rotation, still accepted until the
+    /// given Unix time
+    pub previous_key: Option<(Id, u64)>,
+    /// Update tokens minted by the record's owner
+    pub tokens: Vec<Token>,
+    /// The hash of the key of the record's `/u/KEY` update URL, if it has one
+    pub update_key: Option<String>,
+    /// Named records, such as one per machine, kept alongside the record's
+    /// own address
+    pub hosts: BTreeMap<String, Host>,
+    /// The record's latest address changes, oldest first
+    pub history: Vec<Change>,
+}
+
+/// An address a record changed to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub ip: IpAddr,
+    /// When the record changed to `ip`, in Unix seconds
+    pub at: u64,
+}
+
+/// A named record's addresses, one of each family at most
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    /// The address stored most recently, of either family
+    pub ip: IpAddr,
+    /// The latest address of the other family, if one has been stored
+    pub other_ip: Option<IpAddr>,
+    /// When the host was last updated, in Unix seconds
+    pub updated_at: u64,
+}
+
+impl Host {
+    /// The host's address of `family`, if it has one
+    pub fn address(&self, family: Family) -> Option<IpAddr> {
+        address_of(self.ip, self.other_ip, family)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "ip": self.ip,
+            "ipv4": self.address(Family::V4),
+            "ipv6": self.address(Family::V6),
+            "updated_at": self.updated_at,
+        })
+    }
+}
+
+/// A long-lived token that stands in for a record's credentials
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// Names the token for revoking it
+    pub id: String,
+    /// The token's SHA-1 digest, in hex; the token itself is never stored
+    pub hash: String,
+    pub created_at: u64,
+}
+
+/// An IP address family, each of which has its own slot in a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(ip: IpAddr, now: u64) -> Self {
+        Entry {
+            ip,
+            other_ip: None,
+            uuid: uuid(),
+            created_at: now,
+            updated_at: now,
+            updated_from: Some(ip),
+            deleted_at: None,
+            last_auth: now,
+            previous_key: None,
+            tokens: Vec::new(),
+            update_key: None,
+            hosts: BTreeMap::new(),
+            history: Vec::new(),
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// The record's address of `family`, if it has one
+    pub fn address(&self, family: Family) -> Option<IpAddr> {
+        address_of(self.ip, self.other_ip, family)
+    }
+
+    /// Whether neither the record nor any of its hosts has been refreshed
+    /// within `ttl` seconds of `now`
+    pub fn is_expired(&self, now: u64, ttl: u64) -> bool {
+        let refreshed_at = self.hosts.values().map(|h| h.updated_at).fold(self.updated_at, u64::max);
+        now >= refreshed_at.saturating_add(ttl)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "ip": self.ip,
+            "ipv4": self.address(Family::V4),
+            "ipv6": self.address(Family::V6),
+            "uuid": self.uuid,
+            "created_at": self.created_at,
+            "updated_at": self.updated_at,
+            "updated_from": self.updated_from,
+        })
+    }
+}
+
+/// Whichever of `ip` and `other_ip` is of `family`
+fn address_of(ip: IpAddr, other_ip: Option<IpAddr>, family: Family) -> Option<IpAddr> {
+    iter::once(ip).chain(other_ip).find(|ip| Family::of(ip) == family)
+}
+
+/// Store `new` in its family's slot of `ip` and `other_ip`, returning the
+/// address it replaced
+fn store_ip(ip: &mut IpAddr, other_ip: &mut Option<IpAddr>, new: IpAddr) -> Option<IpAddr> {
+    if Family::of(&new) == Family::of(ip) {
+        return Some(std::mem::replace(ip, new));
+    }
+    other_ip.replace(std::mem::replace(ip, new))
+}
+
+/// The current time, in Unix seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Unix time `secs` as an RFC 3339 UTC timestamp
+pub fn rfc3339(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs / 86400);
+    let rest = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+/// Unix time `secs` as an HTTP date, as in `Last-Modified`
+pub fn http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = secs / 86400;
+    let (year, month, day) = civil_date(days);
+    let rest = secs % 86400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year, rest / 3600, rest / 60 % 60, rest % 60,
+    )
+}
+
+/// The `(year, month, day)` `days` after the Unix epoch
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // After Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let (era, doe) = (z / 146_097, z % 146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Generate a random (version 4) UUID
+pub fn uuid() -> String {
+    let mut b: [u8; 16] = rand::thread_rng().gen();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = b.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// `n` random bytes, in hex
+pub fn random_hex(n: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..n).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+/// The digest `Token::hash` holds for `token`
+pub fn token_hash(token: &str) -> String {
+    Sha1::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Store `ip` for `id`, returning the previous IP of the same family.  An
+/// existing record keeps its UUID and its address of the other family.
+pub fn upsert(db: &mut Records, id: Id, ip: IpAddr, now: u64) -> Option<IpAddr> {
+    apply(db, id, ip, now).0
+}
+
+/// Store `ip` for `id`, returning the previous IP of the same family along
+/// with the stored entry
+fn apply(db: &mut Records, id: Id, ip: IpAddr, now: u64) -> (Option<IpAddr>, &Entry) {
+    let history = db.history;
+    let previous = match db.records.get_mut(&id) {
+        Some(entry) => {
+            entry.updated_at = now;
+            entry.updated_from = Some(ip);
+            entry.last_auth = now;
+            entry.deleted_at = None;
+            let previous = store_ip(&mut entry.ip, &mut entry.other_ip, ip);
+            if previous != Some(ip) {
+                remember(entry, ip, now, history);
+            }
+            previous
+        }
+        None => {
+            if let Some(index) = &mut db.by_user {
+                index.entry(id.user.clone()).or_default().insert(id.clone());
+            }
+            let mut entry = Entry::new(ip, now);
+            remember(&mut entry, ip, now, history);
+            db.records.put(id.clone(), entry);
+            None
+        }
+    };
+    journal(db, &id);
+    (previous, &db[&id])
+}
+
+/// Add a change to `ip` to `entry`'s history, keeping only the latest
+/// `history` changes
+fn remember(entry: &mut Entry, ip: IpAddr, now: u64, history: usize) {
+    entry.history.push(Change { ip, at: now });
+    let excess = entry.history.len().saturating_sub(history);
+    entry.history.drain(..excess);
+}
+
+/// Append the current state of `id`'s record to the event log, if any
+fn journal(db: &mut Records, id: &Id) {
+    if let Some(journal) = &mut db.journal {
+        journal.append(id, db.records.get(id));
+    }
+}
+
+/// Put back a record replayed from the event log, `None` meaning it was
+/// removed.  Nothing is appended to the log.
+pub fn restore(db: &mut Records, id: Id, entry: Option<Entry>) {
+    match entry {
+        Some(entry) => {
+            if let Some(index) = &mut db.by_user {
+                index.entry(id.user.clone()).or_default().insert(id.clone());
+            }
+            db.records.put(id, entry);
+        }
+        None => {
+            take(db, &id);
+        }
+    }
+}
+
+/// Store `entry` for `id` exactly as given, replacing any existing record
+pub fn put(db: &mut Records, id: Id, entry: Entry) {
+    restore(db, id.clone(), Some(entry));
+    journal(db, &id);
+}
+
+/// Fold the event log into its snapshot, if there is an event log
+pub fn compact(db: &mut Records) -> std::io::Result<()> {
+    match &mut db.journal {
+        Some(journal) => journal.compact(db.records.list()),
+        None => Ok(()),
+    }
+}
+
+/// Record that the credentials for `id` were used at `now`, whatever the
+/// request goes on to do, returning the id of the record they map to: the
+/// current key for a rotated-out key still in its grace period, otherwise `id`
+pub fn authenticate(db: &mut Records, id: Id, now: u64) -> Id {
+    let rotated = db.keys.iter().find(|key| {
+        db.records.get(key)
+            .and_then(|e| e.previous_key.as_ref())
+            .is_some_and(|(old, until)| old.ct_eq(&id) && now < *until)
+    });
+    let id = rotated.cloned().unwrap_or(id);
+    if let Some(e) = db.records.get_mut(&id) {
+        e.last_auth = now;
+    }
+    id
+}
+
+/// Remove the record for `id`, returning it
+pub fn remove(db: &mut Records, id: &Id) -> Option<Entry> {
+    let entry = take(db, id)?;
+    journal(db, id);
+    Some(entry)
+}
+
+/// Remove the record for `id` from the map and index, without journaling
+fn take(db: &mut Records, id: &Id) -> Option<Entry> {
+    let entry = db.records.delete(id)?;
+    if let Some(index) = &mut db.by_user {
+        if let Some(ids) = index.get_mut(&id.user) {
+            ids.remove(id);
+            if ids.is_empty() {
+                index.remove(&id.user);
+            }
+        }
+    }
+    Some(entry)
+}
+
+/// Move the record for `old` to the `new` credentials, after which `old`
+/// no longer has a record
+pub fn rekey(db: &mut Records, old: &Id, new: Id) -> Result<(), Err> {
+    if db.get_live(old).is_none() {
+        return Err(NotFound);
+    }
+    if db.records.contains_key(&new) {
+        return Err(Conflict);
+    }
+    let entry = remove(db, old).ok_or(NotFound)?;
+    put(db, new, entry);
+    Ok(())
+}
+
+/// Replace the single-user key with `key`, moving the old key's record (and
+/// its UUID) over.  The old credentials stop working at once, or after
+/// `grace` seconds.  Refused unless there is exactly one key.
+pub fn rotate_key(db: &mut Records, key: Id, now: u64, grace: Option<u64>) -> Result<(), Err> {
+    let old = match db.keys.as_slice() {
+        [old] => old.clone(),
+        [] => return Err(NotFound),
+        _ => return Err(Conflict),
+    };
+    db.keys = vec![key.clone()];
+    if let Some(mut entry) = remove(db, &old) {
+        entry.previous_key = grace.map(|grace| (old, now.saturating_add(grace)));
+        if let Some(index) = &mut db.by_user {
+            index.entry(key.user.clone()).or_default().insert(key.clone());
+        }
+        db.records.put(key.clone(), entry);
+        journal(db, &key);
+    }
+    Ok(())
+}
+
+/// Switch to single-user mode with `keys`, or to multi-user mode with none.
+/// Replacing a single key with another rotates it, as `rotate_key` does.
+pub fn set_keys(db: &mut Records, mut keys: Vec<Id>, now: u64, grace: Option<u64>) {
+    match (db.keys.as_slice(), keys.as_slice()) {
+        ([old], [key]) if old != key => {
+            rotate_key(db, keys.remove(0), now, grace).ok();
+        }
+        _ => db.keys = keys,
+    }
+}
+
+/// Store `ip` for `id`, returning the previous IP of the same family and a
+/// copy of the stored entry, both taken under the same borrow of the map.
+/// Creating a new record is refused once the user already has `max_records`
+/// records; updating an existing one is always allowed.
+pub fn insert(
+    db: &mut Records,
+    id: Id,
+    ip: IpAddr,
+    now: u64,
+    max_records: Option<usize>,
+) -> Result<(Option<IpAddr>, Entry), Err> {
+    if let Some(max) = max_records {
+        if !db.contains_key(&id) && db.user_ids(&id.user).len() >= max {
+            return Err(RecordLimit);
+        }
+    }
+    let (previous, entry) = apply(db, id, ip, now);
+    Ok((previous, entry.clone()))
+}
+
+/// Mark the record for `id` as updated at `now`, by a request `from` that
+/// address if known, without changing its IP
+pub fn touch(db: &mut Records, id: &Id, now: u64, from: Option<IpAddr>) -> Result<(), Err> {
+    match db.records.get_mut(id) {
+        Some(e) if !e.is_deleted() => {
+            e.updated_at = now;
+            e.updated_from = from.or(e.updated_from);
+        }
+        _ => return Err(NotFound),
+    }
+    journal(db, id);
+    Ok(())
+}
+
+/// Delete the record for `id`, returning it.  With a recovery `window` (in
+/// seconds) the record is only marked deleted, so `undelete` can restore it
+/// until `purge` removes it.
+pub fn delete(db: &mut Records, id: &Id, now: u64, window: Option<u64>) -> Option<Entry> {
+    if window.is_none() {
+        return remove(db, id);
+    }
+    let entry = db.records.get_mut(id).filter(|e| !e.is_deleted())?;
+    entry.deleted_at = Some(now);
+    let entry = entry.clone();
+    journal(db, id);
+    Some(entry)
+}
+
+/// Restore a record soft-deleted less than `window` seconds ago
+pub fn undelete(db: &mut Records, id: &Id, now: u64, window: u64) -> Result<Entry, Err> {
+    let entry = match db.records.get_mut(id) {
+        Some(e) if e.deleted_at.is_some_and(|t| now < t.saturating_add(window)) => {
+            e.deleted_at = None;
+            e.clone()
+        }
+        _ => return Err(NotFound),
+    };
+    journal(db, id);
+    Ok(entry)
+}
+
+/// Mint a token for `id`'s record, returning its id and the token, which is
+/// only ever shown this once
+pub fn mint_token(db: &mut Records, id: &Id, now: u64) -> Result<(String, String), Err> {
+    let entry = db.records.get_mut(id).filter(|e| !e.is_deleted()).ok_or(NotFound)?;
+    let (token_id, token) = (random_hex(4), random_hex(20));
+    entry.tokens.push(Token { id: token_id.clone(), hash: token_hash(&token), created_at: now });
+    journal(db, id);
+    Ok((token_id, token))
+}
+
+/// Revoke the token named `token_id` from `id`'s record
+pub fn revoke_token(db: &mut Records, id: &Id, token_id: &str) -> Result<(), Err> {
+    let entry = db.records.get_mut(id).ok_or(NotFound)?;
+    let n = entry.tokens.iter().position(|t| t.id == token_id).ok_or(NotFound)?;
+    entry.tokens.remove(n);
+    journal(db, id);
+    Ok(())
+}
+
+/// Give `id`'s record a new update URL key, replacing any it had, and return
+/// it; like a token, it is only ever shown this once
+pub fn mint_update_key(db: &mut Records, id: &Id) -> Result<String, Err> {
+    let entry = db.records.get_mut(id).filter(|e| !e.is_deleted()).ok_or(NotFound)?;
+    let key = random_hex(16);
+    entry.update_key = Some(token_hash(&key));
+    journal(db, id);
+    Ok(key)
+}
+
+/// Revoke the update URL of `id`'s record
+pub fn revoke_update_key(db: &mut Records, id: &Id) -> Result<(), Err> {
+    let entry = db.records.get_mut(id).ok_or(NotFound)?;
+    entry.update_key.take().ok_or(NotFound)?;
+    journal(db, id);
+    Ok(())
+}
+
+/// Whether `name` may name a host: a DNS label of lowercase letters, digits
+/// and inner hyphens
+pub fn valid_host_name(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// Store `ip` for `id`'s host `name`, returning the host's previous IP of the
+/// same family.  Without a live record for `id`, one is first created with
+/// `ip` as its own address, subject to `max_records`, which also caps the
+/// record's hosts; updating an existing host is always allowed.
+pub fn set_host(
+    db: &mut Records,
+    id: Id,
+    name: &str,
+    ip: IpAddr,
+    now: u64,
+    max_records: Option<usize>,
+) -> Result<Option<IpAddr>, Err> {
+    if !valid_host_name(name) {
+        return Err(BadRequest);
+    }
+    if db.get_live(&id).is_none() {
+        insert(db, id.clone(), ip, now, max_records)?;
+    }
+    let entry = db.records.get_mut(&id).ok_or(NotFound)?;
+    let full = entry.hosts.len() >= max_records.unwrap_or(MAX_HOSTS).min(MAX_HOSTS);
+    let previous = match entry.hosts.get_mut(name) {
+        Some(host) => {
+            host.updated_at = now;
+            store_ip(&mut host.ip, &mut host.other_ip, ip)
+        }
+        None if full => return Err(RecordLimit),
+        None => {
+            entry.hosts.insert(name.to_string(), Host { ip, other_ip: None, updated_at: now });
+            None
+        }
+    };
+    journal(db, &id);
+    Ok(previous)
+}
+
+/// Remove `id`'s host `name`, returning it
+pub fn remove_host(db: &mut Records, id: &Id, name: &str) -> Result<Host, Err> {
+    let entry = db.records.get_mut(id).filter(|e| !e.is_deleted()).ok_or(NotFound)?;
+    let host = entry.hosts.remove(name).ok_or(NotFound)?;
+    journal(db, id);
+    Ok(host)
+}
+
+/// Delete every live record that has expired its `ttl`, as `delete` does with
+/// the recovery `window`, returning the ids and IPs of those deleted
+pub fn expire(db: &mut Records, now: u64, ttl: u64, window: Option<u64>) -> Vec<(Id, IpAddr)> {
+    let expired = db.live()
+        .filter(|(_, e)| e.is_expired(now, ttl))
+        .map(|(id, e)| (id.clone(), e.ip))
+        .collect::<Vec<_>>();
+    for (id, _) in &expired {
+        delete(db, id, now, window);
+    }
+    expired
+}
+
+/// Remove records soft-deleted at least `window` seconds ago, returning how
+/// many were removed
+pub fn purge(db: &mut Records, now: u64, window: u64) -> usize {
+    let expired = db.records.list()
+        .filter(|(_, e)| e.deleted_at.is_some_and(|t| now >= t.saturating_add(window)))
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+    for id in &expired {
+        remove(db, id);
+    }
+    expired.len()
+}
+
+#[test]
+fn insert_record_limit() {
+    let mut db = Records::new();
+    let ip = || "1.1.1.1".parse::<IpAddr>().unwrap();
+
+    assert!(insert(&mut db, Id::new("derp", "one"), ip(), 0, Some(2)).is_ok());
+    assert!(insert(&mut db, Id::new("derp", "two"), ip(), 0, Some(2)).is_ok());
+    assert_eq!(insert(&mut db, Id::new("derp", "three"), ip(), 0, Some(2)), Err(RecordLimit));
+    assert!(!db.contains_key(&Id::new("derp", "three")));
+
+    // Updates and other users are unaffected by the cap
+    let (previous, entry) = insert(&mut db, Id::new("derp", "one"), "2.2.2.2".parse().unwrap(), 0, Some(2)).unwrap();
+    assert_eq!((previous, entry.ip), (Some(ip()), "2.2.2.2".parse().unwrap()));
+    assert!(insert(&mut db, Id::new("herp", "one"), ip(), 0, Some(2)).is_ok());
+    assert!(insert(&mut db, Id::new("derp", "three"), ip(), 0, None).is_ok());
+}
+
+#[test]
+fn rotate_single_user_key() {
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    assert_eq!(rotate_key(&mut Records::new(), new.clone(), 0, None), Err(NotFound));
+
+    let mut db = Records::indexed().with_keys(vec![old.clone()]);
+    upsert(&mut db, old.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let uuid = db[&old].uuid.clone();
+
+    assert_eq!(rotate_key(&mut db, new.clone(), 0, None), Ok(()));
+    assert_eq!(db.keys(), vec![new.clone()]);
+    assert!(!db.contains_key(&old));
+    assert_eq!(db[&new].uuid, uuid);
+    assert_eq!(db.user_ids("derp"), Vec::<&Id>::new());
+    assert_eq!(db.user_ids("herp"), vec![&new]);
+}
+
+#[test]
+fn rotate_key_grace() {
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    let mut db = Records::new().with_keys(vec![old.clone()]);
+    upsert(&mut db, old.clone(), "1.1.1.1".parse().unwrap(), 0);
+    rotate_key(&mut db, new.clone(), 100, Some(60)).unwrap();
+
+    // Both keys map to the same record during the grace period
+    assert_eq!(authenticate(&mut db, old.clone(), 159), new);
+    assert_eq!(db[&new].last_auth, 159);
+    assert_eq!(authenticate(&mut db, new.clone(), 159), new);
+
+    // ...and only the new one afterwards
+    assert_eq!(authenticate(&mut db, old.clone(), 160), old);
+    assert_eq!(db[&new].last_auth, 159);
+    assert_eq!(authenticate(&mut db, new.clone(), 160), new);
+}
+
+#[test]
+fn set_single_user_keys() {
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    let mut db = Records::new();
+    upsert(&mut db, old.clone(), "1.1.1.1".parse().unwrap(), 0);
+
+    set_keys(&mut db, vec![old.clone()], 10, None);
+    assert_eq!(db.keys(), vec![old.clone()]);
+    set_keys(&mut db, vec![new.clone()], 20, None);
+    assert_eq!((db.keys().to_vec(), db[&new].ip.to_string()), (vec![new.clone()], "1.1.1.1".to_string()));
+
+    // With several keys there's no single record to move
+    let both = vec![new.clone(), old.clone()];
+    set_keys(&mut db, both.clone(), 30, None);
+    assert_eq!(db.keys(), both.as_slice());
+    assert_eq!(rotate_key(&mut db, old.clone(), 30, None), Err(Conflict));
+    set_keys(&mut db, Vec::new(), 40, None);
+    assert!(db.keys().is_empty());
+    assert!(db.contains_key(&new));
+}
+
+#[test]
+fn created_at_is_stable() {
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    let mut db = Records::new().with_keys(vec![old.clone()]);
+    upsert(&mut db, old.clone(), "1.1.1.1".parse().unwrap(), 10);
+    upsert(&mut db, old.clone(), "2.2.2.2".parse().unwrap(), 20);
+    touch(&mut db, &old, 30, None).unwrap();
+    upsert(&mut db, old.clone(), "3.3.3.3".parse().unwrap(), 40);
+    assert_eq!((db[&old].created_at, db[&old].updated_at), (10, 40));
+
+    rotate_key(&mut db, new.clone(), 50, None).unwrap();
+    upsert(&mut db, new.clone(), "4.4.4.4".parse().unwrap(), 60);
+    assert_eq!((db[&new].created_at, db[&new].updated_at), (10, 60));
+    assert_eq!(db[&new].to_json()["created_at"], 10);
+}
+
+#[test]
+fn uuid_is_stable() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let uuid = db[&derp].uuid.clone();
+    upsert(&mut db, derp.clone(), "2.2.2.2".parse().unwrap(), 0);
+    upsert(&mut db, derp.clone(), "3.3.3.3".parse().unwrap(), 0);
+    assert_eq!(db[&derp].uuid, uuid);
+    assert_eq!(db[&derp].ip.to_string(), "3.3.3.3");
+
+    upsert(&mut db, Id::new("herp", "blerp"), "1.1.1.1".parse().unwrap(), 0);
+    assert_ne!(db[&Id::new("herp", "blerp")].uuid, uuid);
+}
+
+#[test]
+fn touch_record() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+
+    assert_eq!(touch(&mut db, &derp, 10, None), Err(NotFound));
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 10);
+    assert_eq!(touch(&mut db, &derp, 20, None), Ok(()));
+    assert_eq!(db[&derp].updated_at, 20);
+    assert_eq!(db[&derp].ip.to_string(), "1.1.1.1");
+}
+
+#[test]
+fn soft_delete() {
+    let mut db = Records::indexed();
+    let derp = Id::new("derp", "flerp");
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+
+    assert!(delete(&mut db, &derp, 100, Some(60)).is_some());
+    assert!(db.get_live(&derp).is_none());
+    assert_eq!(touch(&mut db, &derp, 110, None), Err(NotFound));
+    assert!(delete(&mut db, &derp, 110, Some(60)).is_none());
+
+    assert_eq!(undelete(&mut db, &derp, 159, 60).map(|e| e.ip.to_string()), Ok("1.1.1.1".to_string()));
+    assert_eq!(db.get_live(&derp).map(|e| e.ip.to_string()), Some("1.1.1.1".to_string()));
+    assert_eq!(undelete(&mut db, &derp, 159, 60), Err(NotFound));
+}
+
+#[test]
+fn soft_delete_window_expires() {
+    let mut db = Records::indexed();
+    let derp = Id::new("derp", "flerp");
+    let herp = Id::new("herp", "blerp");
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    upsert(&mut db, herp.clone(), "2.2.2.2".parse().unwrap(), 0);
+
+    delete(&mut db, &derp, 100, Some(60));
+    delete(&mut db, &herp, 130, Some(60));
+    assert_eq!(undelete(&mut db, &derp, 160, 60), Err(NotFound));
+
+    assert_eq!(purge(&mut db, 160, 60), 1);
+    assert!(!db.contains_key(&derp));
+    assert!(db.contains_key(&herp));
+    assert_index(&db);
+    assert_eq!(undelete(&mut db, &derp, 160, 60), Err(NotFound));
+}
+
+#[test]
+fn hard_delete() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+
+    assert!(delete(&mut db, &derp, 100, None).is_some());
+    assert!(!db.contains_key(&derp));
+}
+
+#[test]
+fn post_revives_soft_deleted() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    delete(&mut db, &derp, 100, Some(60));
+
+    upsert(&mut db, derp.clone(), "2.2.2.2".parse().unwrap(), 110);
+    assert_eq!(db.get_live(&derp).map(|e| e.ip.to_string()), Some("2.2.2.2".to_string()));
+}
+
+#[test]
+fn family_slots() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    let (a, b, aaaa): (IpAddr, IpAddr, IpAddr) = ("1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap(), "::1".parse().unwrap());
+
+    assert_eq!(upsert(&mut db, derp.clone(), a, 0), None);
+    assert_eq!(upsert(&mut db, derp.clone(), aaaa, 10), None);
+    assert_eq!((db[&derp].address(Family::V4), db[&derp].address(Family::V6)), (Some(a), Some(aaaa)));
+    assert_eq!(db[&derp].ip, aaaa);
+
+    // An update only replaces the address of its own family
+    assert_eq!(upsert(&mut db, derp.clone(), b, 20), Some(a));
+    assert_eq!((db[&derp].address(Family::V4), db[&derp].address(Family::V6)), (Some(b), Some(aaaa)));
+    assert_eq!(db[&derp].to_json()["ipv6"], "::1");
+}
+
+#[test]
+fn named_hosts() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    let (a, b, aaaa): (IpAddr, IpAddr, IpAddr) = ("1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap(), "::1".parse().unwrap());
+
+    // The first host creates the record, with the same address
+    assert_eq!(set_host(&mut db, derp.clone(), "home", a, 10, None), Ok(None));
+    assert_eq!(db[&derp].ip, a);
+    assert_eq!(set_host(&mut db, derp.clone(), "vps", b, 20, None), Ok(None));
+    assert_eq!(set_host(&mut db, derp.clone(), "vps", aaaa, 30, None), Ok(None));
+    assert_eq!(set_host(&mut db, derp.clone(), "home", b, 40, None), Ok(Some(a)));
+    assert_eq!(db[&derp].ip, a);
+    assert_eq!(db[&derp].hosts["vps"].address(Family::V4), Some(b));
+    assert_eq!((db[&derp].hosts["vps"].ip, db[&derp].hosts["vps"].updated_at), (aaaa, 30));
+
+    assert_eq!(set_host(&mut db, derp.clone(), "Not-A-Label", a, 50, None), Err(BadRequest));
+    assert_eq!(set_host(&mut db, Id::new("herp", "blerp"), "home", a, 50, Some(0)), Err(RecordLimit));
+    assert_eq!(remove_host(&mut db, &derp, "home").map(|h| h.ip), Ok(b));
+    assert_eq!(remove_host(&mut db, &derp, "home"), Err(NotFound));
+    assert_eq!(db[&derp].hosts.keys().collect::<Vec<_>>(), vec!["vps"]);
+}
+
+#[test]
+fn host_names() {
+    assert!(valid_host_name("laptop-2"));
+    assert!(!valid_host_name(""));
+    assert!(!valid_host_name("-laptop"));
+    assert!(!valid_host_name("lap.top"));
+    assert!(!valid_host_name(&"a".repeat(64)));
+}
+
+#[test]
+fn bounded_history() {
+    let mut db = Records::new().with_history(3);
+    let derp = Id::new("derp", "flerp");
+    for (now, ip) in ["1.1.1.1", "1.1.1.1", "2.2.2.2", "::1", "3.3.3.3", "3.3.3.3"].iter().enumerate() {
+        upsert(&mut db, derp.clone(), ip.parse().unwrap(), now as u64);
+    }
+    let history = db[&derp].history.iter().map(|c| (c.ip.to_string(), c.at)).collect::<Vec<_>>();
+    assert_eq!(history, vec![("2.2.2.2".to_string(), 2), ("::1".to_string(), 3), ("3.3.3.3".to_string(), 4)]);
+
+    let mut db = Records::new().with_history(0);
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    assert!(db[&derp].history.is_empty());
+}
+
+#[test]
+fn expire_stale_records() {
+    let mut db = Records::new();
+    let (derp, herp, lerp) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"), Id::new("lerp", "merp"));
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    upsert(&mut db, herp.clone(), "2.2.2.2".parse().unwrap(), 50);
+    set_host(&mut db, lerp.clone(), "vps", "3.3.3.3".parse().unwrap(), 0, None).unwrap();
+    set_host(&mut db, lerp.clone(), "vps", "3.3.3.4".parse().unwrap(), 80, None).unwrap();
+
+    assert!(!db[&derp].is_expired(99, 100));
+    assert_eq!(expire(&mut db, 100, 100, Some(60)), vec![(derp.clone(), "1.1.1.1".parse().unwrap())]);
+    assert!(db.get_live(&derp).is_none());
+    assert!(undelete(&mut db, &derp, 110, 60).is_ok());
+
+    assert_eq!(expire(&mut db, 150, 100, None).len(), 2);
+    assert!(!db.contains_key(&herp));
+    assert!(db.contains_key(&lerp));
+}
+
+#[cfg(test)]
+fn assert_index(db: &Records) {
+    let mut scanned = HashMap::<String, HashSet<Id>>::new();
+    for (id, _) in db.list() {
+        scanned.entry(id.user.clone()).or_default().insert(id.clone());
+    }
+    assert_eq!(db.by_user.as_ref(), Some(&scanned));
+}
+
+#[test]
+fn user_index() {
+    let mut db = Records::indexed();
+    let (derp1, derp2, herp) = (Id::new("derp", "one"), Id::new("derp", "two"), Id::new("herp", "one"));
+
+    upsert(&mut db, derp1.clone(), "1.1.1.1".parse().unwrap(), 0);
+    upsert(&mut db, derp2.clone(), "1.1.1.1".parse().unwrap(), 0);
+    upsert(&mut db, herp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    assert_index(&db);
+    assert_eq!(db.user_ids("derp").len(), 2);
+
+    upsert(&mut db, derp1.clone(), "2.2.2.2".parse().unwrap(), 0);
+    assert_index(&db);
+    assert_eq!(db.user_ids("derp").len(), 2);
+
+    remove(&mut db, &derp1);
+    assert_index(&db);
+    assert_eq!(db.user_ids("derp"), vec![&derp2]);
+
+    remove(&mut db, &derp2);
+    remove(&mut db, &derp2);
+    assert_index(&db);
+    assert!(db.user_ids("derp").is_empty());
+    assert_eq!(db.user_ids("herp"), vec![&herp]);
+    assert!(Records::new().user_ids("herp").is_empty());
+}
+
+#[test]
+fn rfc3339_format() {
+    assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+    assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(rfc3339(1_790_000_000), "2026-09-21T14:13:20Z");
+    assert_eq!(http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+}
+
+#[test]
+fn uuid_format() {
+    let uuid = uuid();
+    assert_eq!(uuid.len(), 36);
+    assert_eq!(uuid.chars().nth(14), Some('4'));
+    assert!("89ab".contains(uuid.chars().nth(19).unwrap()));
+}
+
+#[test]
+fn insert_racing_updates() {
+    use parking_lot::RwLock;
+    use std::sync::Arc;
+
+    let db = Arc::new(RwLock::new(Records::new()));
+    let derp = Id::new("derp", "flerp");
+
+    let threads = (0..8)
+        .map(|t| {
+            let (db, derp) = (db.clone(), derp.clone());
+            std::thread::spawn(move || {
+                (0..100)
+                    .map(|i| {
+                        let ip = format!("10.0.{}.{}", t, i).parse::<IpAddr>().unwrap();
+                        let (previous, entry) = insert(&mut db.write(), derp.clone(), ip, 0, None).unwrap();
+                        assert_eq!(entry.ip, ip);
+                        (previous, ip)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let updates = threads.into_iter()
+        .flat_map(|t| t.join().unwrap())
+        .collect::<HashMap<_, _>>();
+
+    // Every update saw exactly the value left by the one before it, so the
+    // updates form a single chain ending at the stored value
+    assert_eq!(updates.len(), 800);
+    let (mut ip, mut applied) = (None, 0);
+    while let Some(next) = updates.get(&ip) {
+        ip = Some(*next);
+        applied += 1;
+    }
+    assert_eq!(applied, 800);
+    assert_eq!(ip.as_ref(), Some(&db.read()[&derp].ip));
+}
+
+#[test]
+fn mint_and_revoke_tokens() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    assert_eq!(mint_token(&mut db, &derp, 0), Err(NotFound));
+
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let (one, token) = mint_token(&mut db, &derp, 10).unwrap();
+    let (two, other) = mint_token(&mut db, &derp, 20).unwrap();
+    assert_eq!((one.len(), token.len()), (8, 40));
+    assert_eq!(db.token_owner(&token), Some(&derp));
+    assert_eq!(db.token_owner(&other), Some(&derp));
+    assert!(!db[&derp].tokens.iter().any(|t| t.hash == token));
+
+    assert_eq!(revoke_token(&mut db, &derp, &one), Ok(()));
+    assert_eq!(db.token_owner(&token), None);
+    assert_eq!(db.token_owner(&other), Some(&derp));
+    assert_eq!(revoke_token(&mut db, &derp, &one), Err(NotFound));
+    assert_eq!(revoke_token(&mut db, &Id::new("herp", "blerp"), &two), Err(NotFound));
+
+    delete(&mut db, &derp, 30, Some(60));
+    assert_eq!(db.token_owner(&other), None);
+}
+
+#[test]
+fn update_keys() {
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    assert_eq!(mint_update_key(&mut db, &derp), Err(NotFound));
+
+    upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let old = mint_update_key(&mut db, &derp).unwrap();
+    let key = mint_update_key(&mut db, &derp).unwrap();
+    assert_eq!(key.len(), 32);
+    assert_eq!(db.update_key_owner(&old), None);
+    assert_eq!(db.update_key_owner(&key), Some(&derp));
+
+    assert_eq!(revoke_update_key(&mut db, &derp), Ok(()));
+    assert_eq!(db.update_key_owner(&key), None);
+    assert_eq!(revoke_update_key(&mut db, &derp), Err(NotFound));
+}
+
+#[test]
+fn rekey_record() {
+    let mut db = Records::indexed();
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("derp", "blerp"));
+    assert_eq!(rekey(&mut db, &old, new.clone()), Err(NotFound));
+
+    upsert(&mut db, old.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let uuid = db[&old].uuid.clone();
+    assert_eq!(rekey(&mut db, &old, new.clone()), Ok(()));
+    assert!(!db.contains_key(&old));
+    assert_eq!((db[&new].ip.to_string(), &db[&new].uuid), ("1.1.1.1".to_string(), &uuid));
+    assert_eq!(db.user_ids("derp"), vec![&new]);
+
+    upsert(&mut db, old.clone(), "2.2.2.2".parse().unwrap(), 0);
+    assert_eq!(rekey(&mut db, &old, new), Err(Conflict));
+    assert_index(&db);
+}
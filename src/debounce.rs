@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::db::{Family, Records};
+use crate::id::Id;
+
+/// Coalesces a record's rapid IP changes into at most one notification per
+/// `interval` seconds for each address family, so a flapping client doesn't
+/// cause churn
+#[derive(Debug, Clone, Default)]
+pub struct Debounce(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    interval: u64,
+    records: HashMap<(Id, Family), Record>,
+}
+
+#[derive(Debug)]
+struct Record {
+    notified_at: u64,
+    notified_ip: IpAddr,
+    changed_at: u64,
+    pending: bool,
+}
+
+impl Debounce {
+    /// With an `interval` of `0`, every change is notified immediately
+    pub fn new(interval: u64) -> Self {
+        Debounce(Arc::new(Mutex::new(State { interval, records: HashMap::new() })))
+    }
+
+    /// Record that `id` changed to `ip` at `now`, returning whether to notify
+    /// now.  Changes within `interval` of the last notification are held back
+    /// for `flush`.
+    pub fn change(&self, id: &Id, ip: IpAddr, now: u64) -> bool {
+        let mut state = match self.0.lock() {
+            Ok(state) => state,
+            Err(_) => return true,
+        };
+        let interval = state.interval;
+        let key = (id.clone(), Family::of(&ip));
+        match state.records.get_mut(&key) {
+            Some(r) if now < r.notified_at.saturating_add(interval) => {
+                r.changed_at = now;
+                r.pending = true;
+                false
+            }
+            _ => {
+                if interval > 0 {
+                    state.records.insert(key, Record {
+                        notified_at: now,
+                        notified_ip: ip,
+                        changed_at: now,
+                        pending: false,
+                    });
+                }
+                true
+            }
+        }
+    }
+
+    /// Held-back changes that have been stable for `interval`, as `(id, old
+    /// ip, new ip)` to notify now.  A record that flapped back to the IP it was
+    /// last notified with isn't notified again.
+    pub fn flush(&self, db: &Records, now: u64) -> Vec<(Id, IpAddr, IpAddr)> {
+        let mut state = match self.0.lock() {
+            Ok(state) => state,
+            Err(_) => return Vec::new(),
+        };
+        let interval = state.interval;
+        let mut notify = Vec::new();
+        state.records.retain(|(id, family), r| {
+            if r.pending && now >= r.changed_at.saturating_add(interval) {
+                r.pending = false;
+                match db.get_live(id).and_then(|e| e.address(*family)) {
+                    Some(ip) if ip != r.notified_ip => {
+                        r.notified_at = now;
+                        let old = std::mem::replace(&mut r.notified_ip, ip);
+                        notify.push((id.clone(), old, ip));
+                    }
+                    _ => {}
+                }
+            }
+            r.pending || now < r.notified_at.saturating_add(interval)
+        });
+        notify
+    }
+}
+
+#[test]
+fn debounce_flapping() {
+    use crate::db;
+
+    let debounce = Debounce::new(60);
+    let derp = Id::new("derp", "flerp");
+    let mut db = Records::new();
+    let mut notified = 0;
+
+    // Alternate between two IPs every second for five minutes
+    for now in 0..300 {
+        let ip = if now % 2 == 0 { "1.1.1.1" } else { "2.2.2.2" }.parse().unwrap();
+        db::upsert(&mut db, derp.clone(), ip, now);
+        if debounce.change(&derp, ip, now) {
+            notified += 1;
+        }
+        notified += debounce.flush(&db, now).len();
+        assert_eq!(db[&derp].ip, ip);
+    }
+    assert!(notified <= 300 / 60 + 1, "notified {} times", notified);
+
+    // Once the value settles, the latest IP is notified
+    let flushed = debounce.flush(&db, 299 + 60);
+    assert_eq!(flushed, vec![(derp.clone(), "1.1.1.1".parse().unwrap(), "2.2.2.2".parse().unwrap())]);
+    assert!(debounce.flush(&db, 1000).is_empty());
+}
+
+#[test]
+fn debounce_disabled() {
+    let debounce = Debounce::default();
+    let derp = Id::new("derp", "flerp");
+    assert!((0..10).all(|now| debounce.change(&derp, "1.1.1.1".parse().unwrap(), now)));
+    assert!(debounce.flush(&Records::new(), 10).is_empty());
+}
+
+#[test]
+fn debounce_families() {
+    let debounce = Debounce::new(60);
+    let derp = Id::new("derp", "flerp");
+
+    // A dual-stack client's addresses are notified independently
+    assert!(debounce.change(&derp, "1.1.1.1".parse().unwrap(), 0));
+    assert!(debounce.change(&derp, "::1".parse().unwrap(), 1));
+    assert!(!debounce.change(&derp, "2.2.2.2".parse().unwrap(), 2));
+}
@@ -0,0 +1,484 @@
+use std::{
+    io, iter,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    thread,
+};
+
+use crate::acme::Accounts;
+use crate::config::Config;
+use crate::db::{self, Entry, Records};
+use crate::id::Id;
+use crate::DB;
+
+/// Largest response sent over UDP; longer ones are truncated so the client
+/// retries over TCP
+const MAX_UDP_SIZE: usize = 512;
+/// Size of a message's header
+const HEADER_SIZE: usize = 12;
+/// A compression pointer to the question's name, right after the header
+const QUESTION_NAME: [u8; 2] = [0xc0, HEADER_SIZE as u8];
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+/// The SOA's refresh, retry and expire intervals, in seconds, for secondary
+/// nameservers
+pub const SOA_TIMERS: [u32; 3] = [3600, 600, 86400];
+
+const FORMERR: u16 = 1;
+const NXDOMAIN: u16 = 3;
+const NOTIMP: u16 = 4;
+const REFUSED: u16 = 5;
+
+/// The zone d5 is authoritative for, whose `USER.ZONE` and `NAME.USER.ZONE`
+/// names resolve to the stored addresses
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zone {
+    /// The zone's name, lowercase and without the trailing dot
+    pub origin: String,
+    /// The zone's nameservers, the first of which is its primary
+    pub nameservers: Vec<String>,
+    /// The zone's contact, as a domain name (`hostmaster.example.com`)
+    pub hostmaster: String,
+    /// TTL of the records served, in seconds
+    pub ttl: u32,
+    /// Records not refreshed within this many seconds aren't served
+    pub record_ttl: Option<u64>,
+}
+
+impl Zone {
+    /// The zone `origin`, served by `ns.ORIGIN` and run by
+    /// `hostmaster.ORIGIN` unless `nameservers` and `hostmaster` say
+    /// otherwise.  `hostmaster` may be an email address.
+    pub fn new(origin: &str, nameservers: Option<Vec<String>>, hostmaster: Option<&str>, ttl: u32) -> Self {
+        let name = |name: &str| name.trim().trim_end_matches('.').to_ascii_lowercase();
+        let origin = name(origin);
+        Zone {
+            nameservers: nameservers.map_or_else(|| vec![format!("ns.{}", origin)], |ns| ns.iter().map(|n| name(n)).collect()),
+            hostmaster: hostmaster.map_or_else(|| format!("hostmaster.{}", origin), |h| name(&h.replacen('@', ".", 1))),
+            ttl,
+            record_ttl: None,
+            origin,
+        }
+    }
+
+    /// The `DNS_ZONE`, if set, as the other `DNS_*` settings describe it
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let zone = config.dns_zone.as_deref()?;
+        let zone = Zone::new(zone, config.dns_nameservers.clone(), config.dns_hostmaster.as_deref(), config.dns_ttl);
+        Some(Zone { record_ttl: config.record_ttl, ..zone })
+    }
+
+    /// `name` relative to the origin: empty for the apex, and `None` outside
+    /// the zone
+    fn relative<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if name == self.origin {
+            return Some("");
+        }
+        name.strip_suffix(self.origin.as_str())?.strip_suffix('.')
+    }
+
+    /// The records served, leaving out those stale under `record_ttl`
+    pub fn live<'a>(&'a self, db: &'a Records, now: u64) -> impl Iterator<Item = (&'a Id, &'a Entry)> {
+        db.live().filter(move |(_, e)| !self.record_ttl.is_some_and(|ttl| e.is_expired(now, ttl)))
+    }
+
+    /// The SOA's serial: when a record served last changed, in Unix seconds
+    pub fn serial(&self, db: &Records, now: u64) -> u32 {
+        self.live(db, now)
+            .map(|(_, e)| e.hosts.values().map(|h| h.updated_at).fold(e.updated_at, u64::max))
+            .max()
+            .unwrap_or_default() as u32
+    }
+
+    /// Every name in the zone that has records, the apex first
+    pub fn names(&self, db: &Records, acme: &Accounts, now: u64) -> Vec<String> {
+        let mut names = self.live(db, now)
+            .flat_map(|(id, e)| iter::once(id.user.clone()).chain(e.hosts.keys().map(move |host| format!("{}.{}", host, id.user))))
+            .chain(acme.names())
+            .map(|name| format!("{}.{}", name.to_ascii_lowercase(), self.origin))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names.insert(0, self.origin.clone());
+        names
+    }
+
+    /// Every record of `name`, or `None` if it doesn't exist
+    pub fn records(&self, db: &Records, acme: &Accounts, name: &str, now: u64) -> Option<Vec<Data>> {
+        let relative = self.relative(name)?;
+        if relative.is_empty() {
+            let mut records = vec![Data::Soa(self.serial(db, now))];
+            records.extend(self.nameservers.iter().cloned().map(Data::Ns));
+            return Some(records);
+        }
+        // The acme-dns challenges of `SUBDOMAIN` and `_acme-challenge.USER`
+        let txt = acme.txt(relative).into_iter().map(Data::Txt);
+        let (user, host) = match relative.split_once('.') {
+            None => (relative, None),
+            Some((host, user)) if !user.contains('.') => (user, Some(host)),
+            Some(_) => return None,
+        };
+        let owned = self.live(db, now).filter(|(id, _)| id.user.eq_ignore_ascii_case(user)).map(|(_, e)| e);
+        let mut ips = match host {
+            None => owned.flat_map(|e| iter::once(e.ip).chain(e.other_ip)).collect::<Vec<_>>(),
+            Some(host) => owned
+                .filter_map(|e| e.hosts.iter().find(|(name, _)| name.eq_ignore_ascii_case(host)))
+                .flat_map(|(_, h)| iter::once(h.ip).chain(h.other_ip))
+                .collect(),
+        };
+        ips.sort();
+        ips.dedup();
+        let records = ips.into_iter().map(Data::Addr).chain(txt).collect::<Vec<_>>();
+        Some(records).filter(|records| !records.is_empty())
+    }
+}
+
+/// The data of a record in the zone
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    /// The zone's SOA, with its serial
+    Soa(u32),
+    Ns(String),
+    /// An `A` or `AAAA` record
+    Addr(IpAddr),
+    Txt(String),
+}
+
+impl Data {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            Data::Soa(_) => TYPE_SOA,
+            Data::Ns(_) => TYPE_NS,
+            Data::Addr(IpAddr::V4(_)) => TYPE_A,
+            Data::Addr(IpAddr::V6(_)) => TYPE_AAAA,
+            Data::Txt(_) => TYPE_TXT,
+        }
+    }
+
+    /// The record's type, as zone files name it
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Data::Soa(_) => "SOA",
+            Data::Ns(_) => "NS",
+            Data::Addr(IpAddr::V4(_)) => "A",
+            Data::Addr(IpAddr::V6(_)) => "AAAA",
+            Data::Txt(_) => "TXT",
+        }
+    }
+
+    /// The record's data as zone files write it
+    pub fn content(&self, zone: &Zone) -> String {
+        match self {
+            Data::Soa(serial) => {
+                let [refresh, retry, expire] = SOA_TIMERS;
+                format!(
+                    "{}. {}. {} {} {} {} {}",
+                    zone.nameservers[0], zone.hostmaster, serial, refresh, retry, expire, zone.ttl,
+                )
+            }
+            Data::Ns(ns) => format!("{}.", ns),
+            Data::Addr(ip) => ip.to_string(),
+            Data::Txt(txt) => format!("\"{}\"", txt),
+        }
+    }
+
+    /// The record's data in wire format, or `None` if it names a name that
+    /// can't be encoded
+    fn encode(&self, zone: &Zone) -> Option<Vec<u8>> {
+        Some(match self {
+            Data::Soa(serial) => {
+                let mut rdata = encode_name(&zone.nameservers[0])?;
+                rdata.extend(encode_name(&zone.hostmaster)?);
+                let [refresh, retry, expire] = SOA_TIMERS;
+                for n in [*serial, refresh, retry, expire, zone.ttl] {
+                    rdata.extend_from_slice(&n.to_be_bytes());
+                }
+                rdata
+            }
+            Data::Ns(ns) => encode_name(ns)?,
+            Data::Addr(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Data::Addr(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            Data::Txt(txt) => {
+                let mut rdata = vec![txt.len() as u8];
+                rdata.extend_from_slice(txt.as_bytes());
+                rdata
+            }
+        })
+    }
+}
+
+/// A query's question
+struct Question {
+    /// The name asked about, lowercase and without the trailing dot
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// Where the question ends in the query
+    end: usize,
+}
+
+/// Answer queries for `zone` from `db` and the acme-dns `acme` accounts on
+/// UDP `addr`, in the background
+pub fn serve(addr: SocketAddr, zone: Zone, db: DB, acme: Accounts) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    thread::spawn(move || {
+        let mut buf = [0; MAX_UDP_SIZE];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("[ERROR] Unable to receive a DNS query: {}", e);
+                    continue;
+                }
+            };
+            let response = answer(&zone, &db.read(), &acme, &buf[..len], db::now());
+            if let Some(response) = response {
+                if let Err(e) = socket.send_to(&response, peer) {
+                    eprintln!("[ERROR] Unable to answer a DNS query from {}: {}", peer, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The response to `query`, or `None` if it isn't a query at all
+pub fn answer(zone: &Zone, db: &Records, acme: &Accounts, query: &[u8], now: u64) -> Option<Vec<u8>> {
+    let flags = u16::from_be_bytes([*query.get(2)?, *query.get(3)?]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    // Echo the opcode and recursion desired, as an authoritative answer
+    let opcode = flags & 0x7800;
+    let reply = |rcode: u16, authoritative: bool, question: Option<&Question>, answers: &[Vec<u8>], authority: &[Vec<u8>]| {
+        let aa = if authoritative { 0x0400 } else { 0 };
+        let question = question.map(|q| &query[HEADER_SIZE..q.end]);
+        response(&query[..2], 0x8000 | opcode | aa | flags & 0x0100 | rcode, question, answers, authority)
+    };
+
+    let question = match parse_question(query) {
+        Some(question) => question,
+        None => return Some(reply(FORMERR, false, None, &[], &[])),
+    };
+    if opcode != 0 {
+        return Some(reply(NOTIMP, false, Some(&question), &[], &[]));
+    }
+    if question.qclass != CLASS_IN && question.qclass != CLASS_ANY || zone.relative(&question.name).is_none() {
+        return Some(reply(REFUSED, false, Some(&question), &[], &[]));
+    }
+
+    let soa = || {
+        let soa = Data::Soa(zone.serial(db, now));
+        encode_name(&zone.origin).zip(soa.encode(zone))
+            .map(|(owner, rdata)| encode_record(&owner, TYPE_SOA, zone.ttl, &rdata))
+            .into_iter()
+            .collect::<Vec<_>>()
+    };
+    let records = match zone.records(db, acme, &question.name, now) {
+        Some(records) => records,
+        None => return Some(reply(NXDOMAIN, true, Some(&question), &[], &soa())),
+    };
+    let answers = records.iter()
+        .filter(|data| question.qtype == TYPE_ANY || data.rtype() == question.qtype)
+        .filter_map(|data| Some(encode_record(&QUESTION_NAME, data.rtype(), zone.ttl, &data.encode(zone)?)))
+        .collect::<Vec<_>>();
+    // A name without records of the type asked for has no data, which the
+    // zone's SOA says how long to remember
+    let authority = if answers.is_empty() { soa() } else { Vec::new() };
+    Some(reply(0, true, Some(&question), &answers, &authority))
+}
+
+fn parse_question(query: &[u8]) -> Option<Question> {
+    let count = |at: usize| u16::from_be_bytes([query[at], query[at + 1]]);
+    if query.len() < HEADER_SIZE || count(4) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = HEADER_SIZE;
+    loop {
+        // Compression pointers (and the reserved label types) have no place
+        // in a question, the first name in the message
+        let len = usize::from(*query.get(pos)?);
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 || pos + len - HEADER_SIZE > 255 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(query.get(pos..pos + len)?).to_ascii_lowercase());
+        pos += len;
+    }
+    query.get(pos..pos + 4)?;
+    Some(Question { name: labels.join("."), qtype: count(pos), qclass: count(pos + 2), end: pos + 4 })
+}
+
+/// Build a response with the `id` of the query, dropping its records if it
+/// is too large for UDP
+fn response(id: &[u8], mut flags: u16, question: Option<&[u8]>, answers: &[Vec<u8>], authority: &[Vec<u8>]) -> Vec<u8> {
+    let size = HEADER_SIZE + question.map_or(0, <[u8]>::len) + answers.iter().chain(authority).map(Vec::len).sum::<usize>();
+    let (answers, authority) = if size > MAX_UDP_SIZE {
+        flags |= 0x0200;
+        (&[][..], &[][..])
+    } else {
+        (answers, authority)
+    };
+    let mut out = id.to_vec();
+    for n in [flags, question.is_some() as u16, answers.len() as u16, authority.len() as u16, 0] {
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+    out.extend_from_slice(question.unwrap_or_default());
+    for record in answers.iter().chain(authority) {
+        out.extend_from_slice(record);
+    }
+    out
+}
+
+/// `name` in wire format, or `None` if a label is longer than 63 bytes or
+/// the name longer than 255
+pub fn encode_name(name: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return None;
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Some(out).filter(|out| out.len() <= 255)
+}
+
+fn encode_record(owner: &[u8], rtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+    let mut out = owner.to_vec();
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+    out
+}
+
+#[cfg(test)]
+fn query(name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = vec![0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend(encode_name(name).unwrap());
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+#[cfg(test)]
+fn test_zone() -> (Zone, Records) {
+    use crate::id::Id;
+
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 10);
+    db::upsert(&mut db, derp.clone(), "2001:db8::1".parse().unwrap(), 20);
+    db::set_host(&mut db, derp, "vps", "1.2.3.4".parse().unwrap(), 30, None).unwrap();
+    db::upsert(&mut db, Id::new("herp", "blerp"), "2.2.2.2".parse().unwrap(), 40);
+    (Zone::new("Dyn.Example.com.", None, Some("admin@example.com"), 60), db)
+}
+
+#[test]
+fn answer_addresses() {
+    let (zone, db) = test_zone();
+    let res = answer(&zone, &db, &Accounts::default(), &query("DERP.dyn.example.com", TYPE_A), 50).unwrap();
+    // Same id, an authoritative answer with recursion desired echoed
+    assert_eq!(res[..4], [0xbe, 0xef, 0x85, 0x00]);
+    assert_eq!(res[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+    let question = query("DERP.dyn.example.com", TYPE_A)[HEADER_SIZE..].to_vec();
+    assert_eq!(res[HEADER_SIZE..HEADER_SIZE + question.len()], question[..]);
+    assert_eq!(res[HEADER_SIZE + question.len()..], encode_record(&QUESTION_NAME, TYPE_A, 60, &[1, 1, 1, 1])[..]);
+
+    let res = answer(&zone, &db, &Accounts::default(), &query("derp.dyn.example.com", TYPE_AAAA), 50).unwrap();
+    assert!(res.ends_with(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets()));
+    let res = answer(&zone, &db, &Accounts::default(), &query("derp.dyn.example.com", TYPE_ANY), 50).unwrap();
+    assert_eq!(res[6..8], [0, 2]);
+    let res = answer(&zone, &db, &Accounts::default(), &query("vps.derp.dyn.example.com", TYPE_A), 50).unwrap();
+    assert!(res.ends_with(&[0, 4, 1, 2, 3, 4]));
+
+    // Stale records aren't served
+    let zone = Zone { record_ttl: Some(30), ..zone };
+    let res = answer(&zone, &db, &Accounts::default(), &query("herp.dyn.example.com", TYPE_A), 100).unwrap();
+    assert_eq!(res[3] & 0x0f, NXDOMAIN as u8);
+}
+
+#[test]
+fn answer_zone_records() {
+    let (zone, db) = test_zone();
+    assert_eq!(zone.nameservers, vec!["ns.dyn.example.com"]);
+    assert_eq!(zone.hostmaster, "admin.example.com");
+
+    let res = answer(&zone, &db, &Accounts::default(), &query("dyn.example.com", TYPE_SOA), 50).unwrap();
+    let mut soa = encode_name("ns.dyn.example.com").unwrap();
+    soa.extend(encode_name("admin.example.com").unwrap());
+    for n in [40u32, 3600, 600, 86400, 60] {
+        soa.extend_from_slice(&n.to_be_bytes());
+    }
+    assert!(res.ends_with(&encode_record(&QUESTION_NAME, TYPE_SOA, 60, &soa)));
+    let res = answer(&zone, &db, &Accounts::default(), &query("dyn.example.com", TYPE_NS), 50).unwrap();
+    assert!(res.ends_with(&encode_record(&QUESTION_NAME, TYPE_NS, 60, &encode_name("ns.dyn.example.com").unwrap())));
+
+    // No data and unknown names carry the SOA in the authority section
+    let res = answer(&zone, &db, &Accounts::default(), &query("herp.dyn.example.com", TYPE_AAAA), 50).unwrap();
+    assert_eq!((res[3] & 0x0f, &res[6..10]), (0, &[0, 0, 0, 1][..]));
+    let res = answer(&zone, &db, &Accounts::default(), &query("nobody.dyn.example.com", TYPE_A), 50).unwrap();
+    assert_eq!((res[3] & 0x0f, &res[6..10]), (NXDOMAIN as u8, &[0, 0, 0, 1][..]));
+    assert!(res.ends_with(&encode_record(&encode_name("dyn.example.com").unwrap(), TYPE_SOA, 60, &soa)));
+
+    // Names that can't be encoded are left out rather than sent malformed
+    assert_eq!(encode_name(&"a".repeat(63)).map(|name| name.len()), Some(65));
+    assert_eq!(encode_name(&"a".repeat(64)), None);
+    assert_eq!(encode_name(&vec!["a".repeat(63); 4].join(".")), None);
+    let zone = Zone { nameservers: vec![format!("{}.example.com", "a".repeat(64))], ..zone };
+    let res = answer(&zone, &db, &Accounts::default(), &query("dyn.example.com", TYPE_NS), 50).unwrap();
+    assert_eq!((res[3] & 0x0f, &res[6..10]), (0, &[0, 0, 0, 0][..]));
+}
+
+#[test]
+fn answer_errors() {
+    let (zone, db) = test_zone();
+    let rcode = |query: &[u8]| answer(&zone, &db, &Accounts::default(), query, 50).map(|res| res[3] & 0x0f);
+    assert_eq!(rcode(&query("derp.example.org", TYPE_A)), Some(REFUSED as u8));
+    let mut update = query("derp.dyn.example.com", TYPE_A);
+    update[2] = 5 << 3;
+    assert_eq!(rcode(&update), Some(NOTIMP as u8));
+    let mut pointer = query("derp.dyn.example.com", TYPE_A);
+    pointer[HEADER_SIZE] = 0xc0;
+    assert_eq!(rcode(&pointer), Some(FORMERR as u8));
+    assert_eq!(rcode(&query("derp.dyn.example.com", TYPE_A)[..20]), Some(FORMERR as u8));
+    // Responses are ignored
+    let mut response = query("derp.dyn.example.com", TYPE_A);
+    response[2] |= 0x80;
+    assert_eq!(rcode(&response), None);
+}
+
+#[test]
+fn answer_challenges() {
+    let (zone, db) = test_zone();
+    let path = std::env::temp_dir().join(format!("d5-dns-acme-{}.json", std::process::id()));
+    let acme = Accounts::open(&path).unwrap();
+    let (account, password) = acme.register(Some("derp".to_string()), Vec::new()).unwrap();
+    acme.update(&account.username, &password, &account.subdomain, &"x".repeat(43), None).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut txt = vec![43];
+    txt.extend_from_slice("x".repeat(43).as_bytes());
+    let challenge = encode_record(&QUESTION_NAME, TYPE_TXT, 60, &txt);
+    let name = format!("{}.dyn.example.com", account.subdomain);
+    assert!(answer(&zone, &db, &acme, &query(&name, TYPE_TXT), 50).unwrap().ends_with(&challenge));
+    let res = answer(&zone, &db, &acme, &query("_acme-challenge.derp.dyn.example.com", TYPE_TXT), 50).unwrap();
+    assert!(res.ends_with(&challenge));
+    // The owner's addresses are unaffected
+    let res = answer(&zone, &db, &acme, &query("derp.dyn.example.com", TYPE_TXT), 50).unwrap();
+    assert_eq!((res[3] & 0x0f, &res[6..10]), (0, &[0, 0, 0, 1][..]));
+}
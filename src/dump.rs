@@ -0,0 +1,80 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use crate::admin;
+use crate::db::Records;
+use crate::DB;
+
+/// How often to check for a dump request
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set by the signal handler
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The live records of the store for `tenant` as `USER IP UPDATED_AT` lines,
+/// after a header line counting them
+pub fn table(tenant: Option<&str>, db: &Records) -> String {
+    let records = admin::list(db);
+    let users = records.iter().map(|(user, _)| user).collect::<HashSet<_>>().len();
+    let mut out = match tenant {
+        Some(tenant) => format!("# tenant {}: ", tenant),
+        None => "# default: ".to_string(),
+    };
+    out.push_str(&format!("{} records, {} users\n", records.len(), users));
+    for (user, e) in records {
+        out.push_str(&format!("{} {} {}\n", user, e.ip, e.updated_at));
+    }
+    out
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// On `SIGUSR1`, write the table of each of `stores` to `path`, or to stderr
+/// when unset, without interrupting the server
+pub fn on_signal_dump(stores: Vec<(Option<String>, DB)>, path: Option<PathBuf>) {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if !REQUESTED.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        let mut out = String::new();
+        for (tenant, db) in &stores {
+            let db = db.read();
+            out.push_str(&table(tenant.as_deref(), &db));
+        }
+        match &path {
+            Some(path) => match fs::write(path, out) {
+                Ok(()) => eprintln!("Dumped state to {}", path.display()),
+                Err(e) => eprintln!("[ERROR] Unable to dump state to {}: {}", path.display(), e),
+            },
+            None => eprint!("{}", out),
+        }
+    });
+}
+
+#[test]
+fn dump_table() {
+    use crate::db;
+    use crate::id::Id;
+
+    let mut db = Records::new();
+    db::upsert(&mut db, Id::new("herp", "blerp"), "2.2.2.2".parse().unwrap(), 20);
+    db::upsert(&mut db, Id::new("derp", "one"), "1.1.1.1".parse().unwrap(), 10);
+    db::upsert(&mut db, Id::new("derp", "two"), "::1".parse().unwrap(), 30);
+    db::delete(&mut db, &Id::new("derp", "two"), 40, Some(60));
+
+    assert_eq!(table(None, &db), "# default: 2 records, 2 users\nderp 1.1.1.1 10\nherp 2.2.2.2 20\n");
+    assert!(table(Some("acme"), &Records::new()).starts_with("# tenant acme: 0 records"));
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+
+use crate::db::{self, Entry, Records};
+use crate::id::Id;
+use crate::snapshot;
+
+/// The append-only `EVENT_LOG`: one JSON line per record mutation, holding
+/// the record's new state (`put`) or its removal (`remove`).  Compaction
+/// folds the log into a snapshot beside it (`PATH.snapshot`).
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Open the log at `path`, creating it readable only by its owner
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+        Ok(Journal { path: path.to_path_buf(), file })
+    }
+
+    /// Append the new state of `id`'s record, `None` meaning it was removed.
+    /// Each event is written (and flushed) as a single line, so a crash loses
+    /// at most the event being written.
+    pub fn append(&mut self, id: &Id, entry: Option<&Entry>) {
+        let mut line = event(id, entry).to_string();
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()).and_then(|_| self.file.flush()) {
+            eprintln!("[ERROR] Unable to append to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Write every record to the snapshot and empty the log
+    pub fn compact<'a, I>(&mut self, records: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (&'a Id, &'a Entry)>,
+    {
+        let snapshot = snapshot_path(&self.path);
+        let tmp = snapshot.with_extension("snapshot.tmp");
+        let mut out = io::BufWriter::new(snapshot::create_private(&tmp)?);
+        for (id, entry) in records {
+            writeln!(out, "{}", event(id, Some(entry)))?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &snapshot)?;
+        self.file.set_len(0)
+    }
+}
+
+fn snapshot_path(path: &Path) -> PathBuf {
+    let mut snapshot = path.as_os_str().to_owned();
+    snapshot.push(".snapshot");
+    snapshot.into()
+}
+
+fn event(id: &Id, entry: Option<&Entry>) -> Value {
+    match entry {
+        Some(e) => {
+            let mut event = snapshot::record(id, e);
+            event["op"] = json!("put");
+            event
+        }
+        None => json!({ "op": "remove", "user": id.user, "password": id.password }),
+    }
+}
+
+/// Parse an event line into the record id and its new state
+fn parse(line: &str) -> Option<(Id, Option<Entry>)> {
+    let v = serde_json::from_str::<Value>(line).ok()?;
+    match v["op"].as_str()? {
+        "remove" => Some((Id::new(v["user"].as_str()?, v["password"].as_str()?), None)),
+        "put" => snapshot::parse_record(&v).map(|(id, entry)| (id, Some(entry))),
+        _ => None,
+    }
+}
+
+/// Rebuild `db` by replaying the snapshot and then the log at `path`.
+/// Missing files are treated as empty; unreadable lines (such as one cut off
+/// by a crash) are skipped with a warning.
+pub fn replay(db: &mut Records, path: &Path) -> io::Result<()> {
+    for path in &[snapshot_path(path), path.to_path_buf()] {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse(&line) {
+                Some((id, entry)) => db::restore(db, id, entry),
+                None => eprintln!("Skipping unreadable event at {}:{}", path.display(), n + 1),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn temp_path(name: &str) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    std::env::temp_dir().join(format!("d5-{}-{}-{}", name, std::process::id(), nanos))
+}
+
+#[cfg(test)]
+fn contents(db: &Records) -> std::collections::HashMap<Id, Entry> {
+    db.list().map(|(id, e)| (id.clone(), e.clone())).collect()
+}
+
+#[test]
+fn replay_and_compact() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = temp_path("events");
+    let (derp, herp) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+
+    let mut db = Records::new().with_journal(Journal::open(&path).unwrap());
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    db::upsert(&mut db, derp.clone(), "2.2.2.2".parse().unwrap(), 2);
+    db::upsert(&mut db, herp.clone(), "3.3.3.3".parse().unwrap(), 3);
+    db::touch(&mut db, &herp, 4, None).unwrap();
+    db::delete(&mut db, &herp, 5, Some(60));
+    db::upsert(&mut db, Id::new("lerp", "merp"), "4.4.4.4".parse().unwrap(), 6);
+    db::delete(&mut db, &Id::new("lerp", "merp"), 7, None);
+
+    // Restarting rebuilds exactly the same store
+    let mut replayed = Records::new();
+    replay(&mut replayed, &path).unwrap();
+    assert_eq!(contents(&replayed), contents(&db));
+
+    // Compacting shrinks the log without losing state
+    let before = fs::metadata(&path).unwrap().len();
+    db::compact(&mut db).unwrap();
+    assert!(fs::metadata(&path).unwrap().len() < before);
+    db::upsert(&mut db, derp.clone(), "5.5.5.5".parse().unwrap(), 8);
+
+    let mut replayed = Records::indexed();
+    replay(&mut replayed, &path).unwrap();
+    assert_eq!(contents(&replayed), contents(&db));
+    assert_eq!(replayed.user_ids("derp"), vec![&derp]);
+
+    // Both files hold passwords, so only the owner may read them
+    for path in [path.clone(), snapshot_path(&path)] {
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(snapshot_path(&path)).unwrap();
+}
+
+#[test]
+fn replay_skips_torn_line() {
+    let path = temp_path("torn");
+    let event = event(&Id::new("derp", "flerp"), Some(&Entry::new("1.1.1.1".parse().unwrap(), 0)));
+    fs::write(&path, format!("{}\n{{\"op\":\"put\",\"us", event)).unwrap();
+
+    let mut db = Records::new();
+    replay(&mut db, &path).unwrap();
+    assert_eq!(db[&Id::new("derp", "flerp")].ip.to_string(), "1.1.1.1");
+    assert_eq!(db.list().count(), 1);
+    fs::remove_file(&path).unwrap();
+}
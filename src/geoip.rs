@@ -0,0 +1,299 @@
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use serde_json::{json, Map, Value};
+
+/// Marks the start of a MaxMind DB's metadata, near the end of the file
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Size of the separator between the search tree and the data section
+const DATA_SEPARATOR: usize = 16;
+
+/// The `GEOIP_DB` databases, such as GeoLite2-Country and GeoLite2-ASN, each
+/// consulted in turn; with none loaded every lookup finds nothing
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp(Vec<Arc<Reader>>);
+
+/// What the databases know about an address
+#[derive(Debug, Default, PartialEq)]
+pub struct Geo {
+    /// The ISO 3166 code of the country the address is registered in
+    pub country: Option<String>,
+    /// The number of the autonomous system announcing the address
+    pub asn: Option<u64>,
+    /// The name of the organization behind `asn`
+    pub as_org: Option<String>,
+}
+
+impl GeoIp {
+    /// Load the database at each of `paths`
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        paths.iter()
+            .map(|path| Reader::new(fs::read(path)?).map(Arc::new))
+            .collect::<io::Result<_>>()
+            .map(GeoIp)
+    }
+
+    /// What the databases know about `ip`, if anything
+    pub fn lookup(&self, ip: IpAddr) -> Option<Geo> {
+        let mut geo = Geo::default();
+        for record in self.0.iter().filter_map(|reader| reader.lookup(ip)) {
+            geo.country = geo.country.or_else(|| record["country"]["iso_code"].as_str().map(String::from));
+            geo.asn = geo.asn.or_else(|| record["autonomous_system_number"].as_u64());
+            geo.as_org = geo.as_org.or_else(|| record["autonomous_system_organization"].as_str().map(String::from));
+        }
+        Some(geo).filter(|geo| *geo != Geo::default())
+    }
+
+    /// `ip` as it appears in logs, followed by its country and ASN when known
+    pub fn annotate(&self, ip: IpAddr) -> String {
+        match self.lookup(ip) {
+            Some(geo) => format!("{} {}", ip, geo),
+            None => ip.to_string(),
+        }
+    }
+}
+
+impl Geo {
+    pub fn to_json(&self) -> Value {
+        json!({ "country": self.country, "asn": self.asn, "as_org": self.as_org })
+    }
+}
+
+impl fmt::Display for Geo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "COUNTRY:{}", self.country.as_deref().unwrap_or("-"))?;
+        match self.asn {
+            Some(asn) => write!(f, " ASN:{}", asn),
+            None => write!(f, " ASN:-"),
+        }
+    }
+}
+
+/// A MaxMind DB file, read into memory
+#[derive(Debug)]
+struct Reader {
+    db: Vec<u8>,
+    node_count: usize,
+    /// Bits per record; each node holds two
+    record_size: usize,
+    ip_version: u64,
+}
+
+impl Reader {
+    fn new(db: Vec<u8>) -> io::Result<Self> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, format!("not a MaxMind DB: {}", e));
+        let start = db.windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| invalid("no metadata"))?
+            + METADATA_MARKER.len();
+        let (metadata, _) = decode(&db[start..], 0, 0).ok_or_else(|| invalid("unreadable metadata"))?;
+        let field = |name: &str| metadata[name].as_u64().ok_or_else(|| invalid(&format!("no {}", name)));
+        let reader = Reader {
+            node_count: field("node_count")? as usize,
+            record_size: field("record_size")? as usize,
+            ip_version: field("ip_version")?,
+            db,
+        };
+        if ![24, 28, 32].contains(&reader.record_size) {
+            return Err(invalid("unsupported record size"));
+        }
+        if reader.data_start() > start {
+            return Err(invalid("truncated search tree"));
+        }
+        Ok(reader)
+    }
+
+    /// Where the data section starts, after the search tree and separator
+    fn data_start(&self) -> usize {
+        self.node_count * self.record_size / 4 + DATA_SEPARATOR
+    }
+
+    /// The record for the network containing `ip`
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let bytes = match (ip, self.ip_version) {
+            (IpAddr::V4(ip), 4) => ip.octets().to_vec(),
+            // IPv4 networks live under ::/96 in an IPv6 database
+            (IpAddr::V4(ip), _) => ip.to_ipv6_compatible().octets().to_vec(),
+            (IpAddr::V6(ip), 6) => ip.octets().to_vec(),
+            (IpAddr::V6(_), _) => return None,
+        };
+        let mut node = 0;
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bytes[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit)?;
+        }
+        // A pointer past the tree points into the data section; exactly
+        // `node_count` means the address isn't in the database
+        let offset = node.checked_sub(self.node_count + DATA_SEPARATOR)?;
+        let data = self.db.get(self.data_start()..)?;
+        decode(data, offset, 0).map(|(value, _)| value)
+    }
+
+    /// The left (`bit` 0) or right record of `node`
+    fn record(&self, node: usize, bit: u8) -> Option<usize> {
+        let b = self.db.get(node * self.record_size / 4..(node + 1) * self.record_size / 4)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| n << 8 | b as usize);
+        Some(match (self.record_size, bit) {
+            (24, 0) => be(&b[..3]),
+            (24, _) => be(&b[3..]),
+            (28, 0) => (b[3] as usize & 0xf0) << 20 | be(&b[..3]),
+            (28, _) => (b[3] as usize & 0x0f) << 24 | be(&b[4..]),
+            (_, 0) => be(&b[..4]),
+            _ => be(&b[4..]),
+        })
+    }
+}
+
+/// Decode the value at `offset` of the data section `data`, returning it and
+/// the offset after it.  `depth` bounds the nesting of a malformed database.
+fn decode(data: &[u8], offset: usize, depth: usize) -> Option<(Value, usize)> {
+    if depth > 32 {
+        return None;
+    }
+    let bytes = |from: usize, n: usize| data.get(from..from + n);
+    let be = |bytes: &[u8]| bytes.iter().fold(0u64, |n, &b| n << 8 | u64::from(b));
+    let ctrl = *data.get(offset)?;
+    let mut pos = offset + 1;
+    let mut kind = ctrl >> 5;
+
+    if kind == 1 {
+        let size = (ctrl >> 3) & 3;
+        let high = u64::from(ctrl & 7);
+        let b = bytes(pos, usize::from(size) + 1)?;
+        let target = match size {
+            0 => high << 8 | be(b),
+            1 => (high << 16 | be(b)) + 2048,
+            2 => (high << 24 | be(b)) + 526_336,
+            _ => be(b),
+        };
+        let (value, _) = decode(data, target as usize, depth + 1)?;
+        return Some((value, pos + usize::from(size) + 1));
+    }
+    if kind == 0 {
+        kind = data.get(pos)?.checked_add(7)?;
+        pos += 1;
+    }
+    let mut size = usize::from(ctrl & 0x1f);
+    if size >= 29 {
+        let n = size - 28;
+        let extra = be(bytes(pos, n)?) as usize;
+        size = [29, 285, 65_821][n - 1] + extra;
+        pos += n;
+    }
+
+    let value = match kind {
+        2 => Value::String(String::from_utf8_lossy(bytes(pos, size)?).into_owned()),
+        3 => json!(f64::from_bits(be(bytes(pos, 8)?))),
+        4 => Value::Null,
+        5 | 6 | 9 => json!(be(bytes(pos, size)?)),
+        10 if size <= 8 => json!(be(bytes(pos, size)?)),
+        10 => Value::Null,
+        8 => json!(be(bytes(pos, size)?) as u32 as i32),
+        15 => json!(f32::from_bits(be(bytes(pos, 4)?) as u32)),
+        14 => return Some((Value::Bool(size != 0), pos)),
+        7 => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, after) = decode(data, pos, depth + 1)?;
+                let (value, after) = decode(data, after, depth + 1)?;
+                map.insert(key.as_str()?.to_string(), value);
+                pos = after;
+            }
+            return Some((Value::Object(map), pos));
+        }
+        11 => {
+            let mut items = Vec::new();
+            for _ in 0..size {
+                let (item, after) = decode(data, pos, depth + 1)?;
+                items.push(item);
+                pos = after;
+            }
+            return Some((Value::Array(items), pos));
+        }
+        _ => return None,
+    };
+    let width = match kind {
+        3 => 8,
+        15 => 4,
+        _ => size,
+    };
+    Some((value, pos + width))
+}
+
+#[cfg(test)]
+fn encode(kind: u8, payload: &[u8], size: usize) -> Vec<u8> {
+    let (low, extra) = if size < 29 { (size as u8, None) } else { (29, Some((size - 29) as u8)) };
+    let mut out = if kind > 7 { vec![low, kind - 7] } else { vec![kind << 5 | low] };
+    out.extend(extra);
+    out.extend_from_slice(payload);
+    out
+}
+
+#[cfg(test)]
+fn encode_map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut out = encode(7, &[], entries.len());
+    for (key, value) in entries {
+        out.extend(encode(2, key.as_bytes(), key.len()));
+        out.extend_from_slice(value);
+    }
+    out
+}
+
+/// An IPv4 database with 24-bit records mapping `1.0.0.0/8` to `record`
+#[cfg(test)]
+fn test_db(record: Vec<u8>) -> Vec<u8> {
+    let node_count = 8u32;
+    let mut db = Vec::new();
+    // Follow the bits of 1 (0b00000001) down to the record
+    for node in 0..node_count {
+        let (next, empty) = if node < 7 { (node + 1, node_count) } else { (node_count + 16, node_count) };
+        let (left, right) = if node < 7 { (next, empty) } else { (empty, next) };
+        db.extend_from_slice(&left.to_be_bytes()[1..]);
+        db.extend_from_slice(&right.to_be_bytes()[1..]);
+    }
+    db.extend_from_slice(&[0; DATA_SEPARATOR]);
+    db.extend(record);
+    db.extend_from_slice(METADATA_MARKER);
+    db.extend(encode_map(&[
+        ("node_count", encode(6, &node_count.to_be_bytes(), 4)),
+        ("record_size", encode(5, &[24], 1)),
+        ("ip_version", encode(5, &[4], 1)),
+    ]));
+    db
+}
+
+#[test]
+fn lookup_country_and_asn() {
+    let record = encode_map(&[
+        ("country", encode_map(&[("iso_code", encode(2, b"AU", 2))])),
+        ("autonomous_system_number", encode(6, &[0x34, 0x17], 2)),
+        ("autonomous_system_organization", encode(2, b"CLOUDFLARENET", 13)),
+        ("is_anycast", encode(14, &[], 1)),
+    ]);
+    let path = std::env::temp_dir().join(format!("d5-geoip-{}.mmdb", std::process::id()));
+    fs::write(&path, test_db(record)).unwrap();
+    let geoip = GeoIp::open(&[&path]).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let geo = geoip.lookup("1.1.1.1".parse().unwrap()).unwrap();
+    assert_eq!(geo, Geo { country: Some("AU".into()), asn: Some(13335), as_org: Some("CLOUDFLARENET".into()) });
+    assert_eq!(geoip.annotate("1.1.1.1".parse().unwrap()), "1.1.1.1 COUNTRY:AU ASN:13335");
+    assert_eq!(geoip.lookup("2.2.2.2".parse().unwrap()), None);
+    assert_eq!(geoip.lookup("::1".parse().unwrap()), None);
+    assert_eq!(GeoIp::default().annotate("1.1.1.1".parse().unwrap()), "1.1.1.1");
+    assert!(Reader::new(b"junk".to_vec()).is_err());
+}
+
+#[test]
+fn malformed_extended_type() {
+    // An extended type byte past 248 would overflow the type number
+    assert_eq!(decode(&[0x00, 0xff], 0, 0), None);
+    assert_eq!(decode(&encode(14, &[], 1), 0, 0), Some((Value::Bool(true), 2)));
+}
@@ -0,0 +1,94 @@
+use std::{
+    io,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How many hook commands may run at once; further changes skip the hook
+const MAX_RUNNING: usize = 8;
+
+/// How often to check whether a hook command has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An `ON_CHANGE_CMD` shell command, run when a record's IP changes
+#[derive(Debug, Clone)]
+pub struct Hook {
+    cmd: String,
+    timeout: Duration,
+    running: Arc<AtomicUsize>,
+}
+
+impl Hook {
+    pub fn new(cmd: String, timeout: Duration) -> Self {
+        Hook { cmd, timeout, running: Arc::default() }
+    }
+
+    /// Run the command on a background thread with `D5_USER`, `D5_OLD_IP`
+    /// and `D5_NEW_IP` set, logging its output.  Returns `None` if too many
+    /// hooks are already running.
+    pub fn on_change(&self, user: &str, old_ip: Option<&str>, new_ip: &str) -> Option<JoinHandle<()>> {
+        if self.running.fetch_add(1, Ordering::SeqCst) >= MAX_RUNNING {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("[HOOK] USER:{} skipped: too many hooks running", user);
+            return None;
+        }
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(&self.cmd)
+            .env("D5_USER", user)
+            .env("D5_OLD_IP", old_ip.unwrap_or_default())
+            .env("D5_NEW_IP", new_ip)
+            .stdin(Stdio::null());
+        let (user, timeout, running) = (user.to_string(), self.timeout, self.running.clone());
+        Some(thread::spawn(move || {
+            match run(cmd, timeout) {
+                Ok(Some(output)) => {
+                    let out = String::from_utf8_lossy(&output.stdout);
+                    let err = String::from_utf8_lossy(&output.stderr);
+                    for line in out.lines().chain(err.lines()) {
+                        println!("[HOOK] USER:{} {}", user, line);
+                    }
+                    if !output.status.success() {
+                        eprintln!("[HOOK] USER:{} failed: {}", user, output.status);
+                    }
+                }
+                Ok(None) => eprintln!("[HOOK] USER:{} killed after {:?}", user, timeout),
+                Err(e) => eprintln!("[HOOK] USER:{} failed to start: {}", user, e),
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        }))
+    }
+}
+
+/// Run `cmd`, killing it if it hasn't exited after `timeout`
+fn run(mut cmd: Command, timeout: Duration) -> io::Result<Option<Output>> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let start = Instant::now();
+    while child.try_wait()?.is_none() {
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    child.wait_with_output().map(Some)
+}
+
+#[test]
+fn hook_timeout() {
+    let mut sleep = Command::new("sleep");
+    sleep.arg("5");
+    let output = run(sleep, Duration::from_millis(50)).unwrap();
+    assert!(output.is_none());
+
+    let mut echo = Command::new("sh");
+    echo.args(["-c", "echo $D5_NEW_IP"]).env("D5_NEW_IP", "1.1.1.1");
+    let output = run(echo, Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(output.stdout, b"1.1.1.1\n");
+}
@@ -1,6 +1,11 @@
 use std::convert::TryFrom;
 use std::fmt;
 
+use crate::Err::{self, *};
+
+/// Longest accepted `user:password` string, in bytes
+pub const MAX_LEN: usize = 256;
+
 #[derive(Debug, Hash, Clone, Eq, PartialEq)]
 pub struct Id {
     pub user: String,
@@ -21,29 +26,42 @@ impl Id {
         format!("Basic {}", self.encoded)
     }
 
-    pub fn from_basic(s: &str) -> Self {
+    /// Whether `other` holds the same credentials, compared in constant time
+    pub fn ct_eq(&self, other: &Id) -> bool {
+        constant_time_eq(self.user.as_bytes(), other.user.as_bytes())
+            & constant_time_eq(self.password.as_bytes(), other.password.as_bytes())
+    }
+
+    /// Parse a `Basic` authorization header, telling apart a value that isn't
+    /// base64 from one that decodes to something other than `user:password`
+    pub fn from_basic(s: &str) -> Result<Self, Err> {
         let parsed = s.trim().trim_start_matches("Basic ").trim();
-        let decoded = String::from_utf8_lossy(&base64::decode(parsed)
-            .expect("base64 decode error"))
-            .to_string();
-        Id::try_from(decoded.as_str()).expect("Invalid Basic Id")
+        let decoded = base64::decode(parsed).map_err(|_| InvalidBase64)?;
+        Id::try_from(String::from_utf8_lossy(&decoded).as_ref()).map_err(|_| NotUserPassword)
     }
 }
 
-impl std::str::FromStr for Id {
-    type Err = std::io::Error;
-    fn from_str(s: &str) ->  Result<Self, Self::Err> {
-        Ok(Id::from_basic(s))
+/// Compare `a` and `b` in time depending only on their lengths, so the time
+/// taken doesn't reveal how much of a secret was guessed right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    std::hint::black_box(diff) == 0
 }
 
 impl TryFrom<&str> for Id {
     type Error = std::io::Error;
     fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        if s.trim().len() > MAX_LEN {
+            return Err(invalid(format!("longer than {} bytes", MAX_LEN)));
+        }
         let split = s.trim().split(':').collect::<Vec<&str>>();
         match split.len() {
             2 => Ok(Id::new(split[0], split[1])),
-            _ => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+            _ => Err(invalid("expected `user:password`, with exactly one colon".into())),
         }
     }
 }
@@ -73,6 +91,7 @@ fn convert_id_err() {
     assert!(Id::try_from("").is_err());
     assert!(Id::try_from("derpflerp").is_err());
     assert!(Id::try_from(":derpflerp:").is_err());
+    assert!(Id::try_from(format!("derp:{}", "f".repeat(MAX_LEN)).as_str()).is_err());
     assert!(Id::try_from(":derpflerp").is_ok());
     assert!(Id::try_from("derpflerp:").is_ok());
 
@@ -82,3 +101,22 @@ fn convert_id_err() {
     let id = Id::try_from("derpflerp:").unwrap();
     assert!(!id.user.is_empty() && id.password.is_empty());
 }
+
+#[test]
+fn from_basic_err() {
+    assert_eq!(Id::from_basic("Basic ZGVycDpmbGVycA=="), Ok(Id::new("derp", "flerp")));
+    // "derpflerp"
+    assert_eq!(Id::from_basic("Basic ZGVycGZsZXJw"), Err(NotUserPassword));
+    assert_eq!(Id::from_basic("Basic not base64!"), Err(InvalidBase64));
+    assert_ne!(NotUserPassword.to_string(), InvalidBase64.to_string());
+}
+
+#[test]
+fn compare_in_constant_time() {
+    assert!(constant_time_eq(b"flerp", b"flerp"));
+    assert!(!constant_time_eq(b"flerp", b"flerq"));
+    assert!(!constant_time_eq(b"flerp", b"flerpy"));
+    assert!(Id::new("derp", "flerp").ct_eq(&Id::new("derp", "flerp")));
+    assert!(!Id::new("derp", "flerp").ct_eq(&Id::new("herp", "flerp")));
+    assert!(!Id::new("derp", "flerp").ct_eq(&Id::new("derp", "blerp")));
+}
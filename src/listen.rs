@@ -0,0 +1,211 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    fs,
+    io,
+    mem,
+    net::{self, SocketAddr},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, RawFd},
+        net::{self as unix, UnixStream},
+    },
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use tokio::net::{TcpListener, UnixListener};
+use tokio::reactor::Handle;
+
+/// Exit code used when the listening socket can't be bound
+pub const EXIT_BIND: i32 = 2;
+
+/// Delay before the first bind retry; doubled after each attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The first file descriptor systemd passes to a socket-activated service
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket passed by systemd socket activation
+pub enum Activated {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Failure to bind the listening socket
+#[derive(Debug)]
+pub struct BindError {
+    addr: SocketAddr,
+    kind: Option<io::ErrorKind>,
+    message: String,
+}
+
+impl BindError {
+    fn new(addr: SocketAddr, err: &(dyn Error + 'static)) -> Self {
+        // The underlying `io::Error` may be wrapped by the server's error type
+        let mut source = Some(err);
+        let mut io_err = None;
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                io_err = Some(e);
+                break;
+            }
+            source = e.source();
+        }
+        BindError {
+            addr,
+            kind: io_err.map(io::Error::kind),
+            message: io_err.map_or_else(|| err.to_string(), io::Error::to_string),
+        }
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unable to listen on {}: {}", self.addr, self.message)?;
+        match self.kind {
+            Some(io::ErrorKind::AddrInUse) => {
+                write!(f, "\nIs d5 already running? Set PORT to use another port.")
+            }
+            Some(io::ErrorKind::PermissionDenied) => {
+                write!(f, "\nPorts below 1024 usually require root. Set PORT to use another port.")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error for BindError {}
+
+/// Run `bind` for `addr`, retrying up to `retries` times with exponential
+/// backoff while the address is in use
+pub fn bind<T, E, F>(addr: SocketAddr, retries: u32, bind: F) -> Result<T, BindError>
+where
+    E: Error + 'static,
+    F: Fn() -> Result<T, E>,
+{
+    let mut delay = RETRY_DELAY;
+    for attempt in 0.. {
+        match bind() {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                let err = BindError::new(addr, &e);
+                if attempt >= retries || err.kind != Some(io::ErrorKind::AddrInUse) {
+                    return Err(err);
+                }
+                eprintln!("Unable to listen on {}; retrying in {:?}", addr, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    unreachable!()
+}
+
+/// Listen on a Unix domain socket at `path` with permissions `mode`,
+/// replacing a socket file left behind by a server that is no longer running
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a socket"));
+        }
+        Ok(_) if UnixStream::connect(path).is_ok() => {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening there"));
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// How many sockets systemd passed, according to `LISTEN_PID` and
+/// `LISTEN_FDS`: none unless they were meant for the process `pid`
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> RawFd {
+    match (listen_pid.and_then(|p| p.trim().parse::<u32>().ok()), listen_fds) {
+        (Some(listen_pid), Some(fds)) if listen_pid == pid => fds.trim().parse().unwrap_or(0).max(0),
+        _ => 0,
+    }
+}
+
+/// Take the listening sockets systemd passed, if d5 was socket-activated.
+/// The `LISTEN_*` variables are unset so hooks don't see them.
+pub fn activated() -> io::Result<Vec<Activated>> {
+    let var = |var: &str| env::var(var).ok();
+    let count = listen_fds(var("LISTEN_PID").as_deref(), var("LISTEN_FDS").as_deref(), std::process::id());
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).map(activated_socket).collect()
+}
+
+/// Take ownership of the listening socket `fd`
+fn activated_socket(fd: RawFd) -> io::Result<Activated> {
+    let family = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::c_int::from(addr.ss_family)
+    };
+    match family {
+        libc::AF_INET | libc::AF_INET6 => {
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            TcpListener::from_std(listener, &Handle::default()).map(Activated::Tcp)
+        }
+        libc::AF_UNIX => {
+            let listener = unsafe { unix::UnixListener::from_raw_fd(fd) };
+            UnixListener::from_std(listener, &Handle::default()).map(Activated::Unix)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("socket {} is not TCP or Unix", fd))),
+    }
+}
+
+#[test]
+fn bind_in_use() {
+    use warp::Filter;
+
+    let serve = || warp::serve(warp::any().map(warp::reply));
+    let (addr, _server) = serve().try_bind_ephemeral(([127, 0, 0, 1], 0)).unwrap();
+
+    let err = match bind(addr, 1, || serve().try_bind_ephemeral(addr)) {
+        Err(e) => e,
+        Ok(_) => panic!("bound {} twice", addr),
+    };
+    assert_eq!(err.kind, Some(io::ErrorKind::AddrInUse));
+    assert!(err.to_string().starts_with(&format!("Unable to listen on {}: ", addr)));
+    assert!(err.to_string().contains("Set PORT"));
+}
+
+#[test]
+fn bind_unix_socket() {
+    let path = std::env::temp_dir().join(format!("d5-{}.sock", std::process::id()));
+    let listener = bind_unix(&path, 0o660).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+    assert_eq!(bind_unix(&path, 0o660).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+    // The socket file outlives the listener, and is replaced
+    drop(listener);
+    let listener = bind_unix(&path, 0o600).unwrap();
+    drop(listener);
+    fs::remove_file(&path).unwrap();
+
+    fs::write(&path, "").unwrap();
+    assert_eq!(bind_unix(&path, 0o660).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn systemd_listen_fds() {
+    assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+    assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+    assert_eq!(listen_fds(None, Some("2"), 42), 0);
+    assert_eq!(listen_fds(Some("42"), None, 42), 0);
+    assert_eq!(listen_fds(Some("42"), Some("many"), 42), 0);
+}
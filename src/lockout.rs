@@ -0,0 +1,116 @@
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+
+/// Longest lockout, and how long a client's failures are remembered, in
+/// seconds
+const MAX_LOCKOUT: u64 = 24 * 60 * 60;
+/// How many keys' failures to remember before forgetting the expired ones,
+/// and then the least recent
+const MAX_TRACKED: usize = 10_000;
+
+/// Counts failed authentications per client IP and per username, locking a
+/// key out once it reaches `threshold` failures.  The first lockout lasts
+/// `duration` seconds, and each further failure doubles it.
+#[derive(Debug, Clone)]
+pub struct Lockout(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    threshold: u32,
+    duration: u64,
+    failures: HashMap<String, Failures>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last_failure: u64,
+    locked_until: u64,
+}
+
+impl Lockout {
+    pub fn new(threshold: u32, duration: u64) -> Self {
+        Lockout(Arc::new(Mutex::new(State { threshold: threshold.max(1), duration, failures: HashMap::new() })))
+    }
+
+    /// Whether any of `keys` is locked out at `now`
+    pub fn locked(&self, keys: &[String], now: u64) -> bool {
+        let state = self.0.lock();
+        keys.iter().any(|key| state.failures.get(key).is_some_and(|f| now < f.locked_until))
+    }
+
+    /// Count a failure for each of `keys`, returning those it locks out, with
+    /// how long for
+    pub fn failure(&self, keys: &[String], now: u64) -> Vec<(String, u64)> {
+        let mut state = self.0.lock();
+        let (threshold, duration) = (state.threshold, state.duration);
+        if state.failures.len() >= MAX_TRACKED {
+            state.failures.retain(|_, f| now < f.last_failure.saturating_add(MAX_LOCKOUT));
+        }
+        if state.failures.len() >= MAX_TRACKED {
+            // Forget a tenth at once, so the sort isn't repeated every failure
+            let mut oldest = state.failures.iter().map(|(key, f)| (f.last_failure, key.clone())).collect::<Vec<_>>();
+            oldest.sort_unstable();
+            for (_, key) in oldest.into_iter().take(MAX_TRACKED / 10) {
+                state.failures.remove(&key);
+            }
+        }
+        let mut locked = Vec::new();
+        for key in keys {
+            let f = state.failures.entry(key.clone())
+                .or_insert(Failures { count: 0, last_failure: now, locked_until: 0 });
+            f.count = f.count.saturating_add(1);
+            f.last_failure = now;
+            if f.count >= threshold {
+                let doublings = (f.count - threshold).min(32);
+                let lockout = duration.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+                f.locked_until = now.saturating_add(lockout);
+                locked.push((key.clone(), lockout));
+            }
+        }
+        locked
+    }
+
+    /// Forget the failures of each of `keys`
+    pub fn success(&self, keys: &[String]) {
+        let mut state = self.0.lock();
+        for key in keys {
+            state.failures.remove(key);
+        }
+    }
+}
+
+#[test]
+fn exponential_backoff() {
+    let lockout = Lockout::new(3, 60);
+    let (ip, user) = (vec!["ip 1.1.1.1".to_string()], vec!["user derp".to_string()]);
+    let both = [ip.clone(), user.clone()].concat();
+
+    assert!(lockout.failure(&both, 0).is_empty());
+    assert!(lockout.failure(&ip, 1).is_empty());
+    assert_eq!(lockout.failure(&both, 2), vec![("ip 1.1.1.1".to_string(), 60)]);
+    assert!(lockout.locked(&both, 61));
+    assert!(!lockout.locked(&user, 61));
+    assert!(!lockout.locked(&ip, 62));
+
+    assert_eq!(lockout.failure(&ip, 62), vec![("ip 1.1.1.1".to_string(), 120)]);
+    assert!(lockout.locked(&ip, 181));
+    assert!(!lockout.locked(&ip, 182));
+
+    lockout.success(&ip);
+    assert!(!lockout.locked(&ip, 100));
+    assert!(lockout.failure(&ip, 100).is_empty());
+}
+
+#[test]
+fn tracked_keys_capped() {
+    let lockout = Lockout::new(1, 60);
+    lockout.failure(&["user derp".to_string()], 0);
+    for n in 0..MAX_TRACKED {
+        lockout.failure(&[format!("user {}", n)], 1);
+    }
+    let failures = &lockout.0.lock().failures;
+    assert!(failures.len() <= MAX_TRACKED);
+    assert!(!failures.contains_key("user derp"));
+}
@@ -1,165 +1,3765 @@
+// The route filters nest deeper than the default limit allows
+#![recursion_limit = "256"]
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
-    env,
     fmt,
-    net,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     sync::Arc,
-    sync::RwLock,
+    thread,
+    time::{Duration, Instant},
 };
 
+use bytes::Buf;
+use futures::{future, Future, Stream};
+use parking_lot::RwLock;
+use url::Url;
 use warp::{
     Filter,
+    Rejection,
+    Reply,
     header,
     http::StatusCode as Code,
+    path::FullPath,
     reject::custom as warp_err,
-    reply::with_status,
+    reply::{Response, with_status},
 };
 
+mod acme;
+mod admin;
+mod cli;
+mod config;
+mod db;
+mod events;
+mod debounce;
+mod dns;
+mod dump;
+mod geoip;
+mod hook;
 mod id;
+mod listen;
+mod lockout;
+mod reload;
+mod metrics;
+mod pdns;
+mod ratelimit;
+mod rfc2136;
+mod server_ip;
+mod shutdown;
+mod snapshot;
+mod storage;
+mod tenant;
+mod toml;
+mod users;
+use acme::Accounts;
+use config::{Cidr, Config, Families, Permit, XffPolicy};
+use db::{Family, Records};
+use debounce::Debounce;
+use geoip::GeoIp;
+use hook::Hook;
+use ratelimit::RateLimit;
+use rfc2136::Upstream;
 use id::Id;
+use lockout::Lockout;
+use server_ip::ServerIp;
+use shutdown::Drain;
+use tenant::{Store, Tenants};
+use users::Users;
 
-type WarpResult = Result<String, warp::Rejection>;
-type DB = Arc<RwLock<HashMap<Id, String>>>;
+type WarpResult = Result<String, Rejection>;
+type ReplyResult = Result<Response, Rejection>;
+type DB = Arc<RwLock<Records>>;
+type AdminKey = Arc<RwLock<Option<Key>>>;
+type TrustedProxies = Arc<RwLock<Option<Vec<Cidr>>>>;
+type AllowedUsers = Arc<RwLock<Option<Users>>>;
+type Invites = Arc<RwLock<HashSet<String>>>;
 type Key = Id;
 use crate::Err::*;
 use Rest::*;
 
+/// State shared between the routes and background tasks
+#[derive(Debug, Clone, Default)]
+struct Services {
+    server_ip: ServerIp,
+    drain: Drain,
+    debounce: Debounce,
+    /// The `ON_CHANGE_CMD` hook, if configured
+    hook: Option<Hook>,
+    /// Stores for the `TENANTS`, alongside the default store
+    tenants: Tenants,
+    /// The `ADMIN_KEY`, which `SIGHUP` may replace
+    admin_key: AdminKey,
+    /// The `TRUSTED_PROXIES`, which `PUT /admin/trusted-proxies` may replace
+    trusted_proxies: TrustedProxies,
+    /// The users of the `CREDENTIALS_FILE`, if any, reloaded on `SIGHUP`
+    users: AllowedUsers,
+    /// Unused invite codes for `POST /register`
+    invites: Invites,
+    /// The `RATE_LIMIT`, shared by every listener
+    rate_limit: Option<RateLimit>,
+    /// The `LOCKOUT_THRESHOLD` failure counts, shared by every listener
+    lockout: Option<Lockout>,
+    /// The `GEOIP_DB` databases, if any
+    geoip: GeoIp,
+    /// The `ACME_DNS_FILE` accounts, when the acme-dns API is enabled
+    acme: Option<Accounts>,
+    /// The `NSUPDATE_SERVER` changes are pushed to, if configured
+    upstream: Option<Upstream>,
+}
+
+/// Every route, as `(method, path)`; listed by the `CUSTOM_404` fallback,
+/// which only answers paths not in this list
+const ENDPOINTS: &[(&str, &str)] = &[
+    ("GET", "/"),
+    ("POST", "/"),
+    ("DELETE", "/"),
+    ("POST", "/touch"),
+    ("POST", "/undelete"),
+    ("POST", "/register"),
+    ("POST", "/acme-dns/register"),
+    ("POST", "/acme-dns/update"),
+    ("GET", "/dns/METHOD/ARGS"),
+    ("POST", "/dns/METHOD/ARGS"),
+    ("PUT", "/password"),
+    ("POST", "/tokens"),
+    ("DELETE", "/tokens/ID"),
+    ("POST", "/update-url"),
+    ("DELETE", "/update-url"),
+    ("GET", "/u/KEY"),
+    ("POST", "/record/NAME"),
+    ("GET", "/record/NAME"),
+    ("DELETE", "/record/NAME"),
+    ("GET", "/records"),
+    ("GET", "/history"),
+    ("GET", "/server-ip"),
+    ("GET", "/nic/update"),
+    ("GET", "/update"),
+    ("POST", "/admin/swap"),
+    ("GET", "/admin/nsupdate"),
+    ("GET", "/admin/zone"),
+    ("GET", "/admin/list"),
+    ("GET", "/admin/frequency"),
+    ("GET", "/admin/export"),
+    ("POST", "/admin/import"),
+    ("POST", "/admin/purge"),
+    ("POST", "/admin/invite"),
+    ("POST", "/admin/key"),
+    ("GET", "/admin/trusted-proxies"),
+    ("PUT", "/admin/trusted-proxies"),
+    ("POST", "/admin/validate-credential"),
+    ("POST", "/admin/import.csv"),
+    ("GET", "/metrics/entries"),
+];
+
+/// TTL of rendered DNS records, in seconds
+const DEFAULT_TTL: u32 = 300;
+/// Largest accepted import body, in bytes
+const MAX_IMPORT_SIZE: u64 = 1024 * 1024;
+
 fn main() {
-    // Configuration via env variables
-    let port = env::var("PORT").unwrap_or_default().parse().unwrap_or(3030);
-    let addr = env::var("HOST")
-        .unwrap_or_default()
-        .parse()
-        .unwrap_or_else(|_| net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)));
-
-    // Optional key for single-user mode; `USER:PASSWORD`
-    let key = env::var("KEY")
-        .map(|k| {
-            Key::try_from(k.as_str())
-                .map_err(|_| {
-                    eprintln!("Invalid key!");
+    let args = cli::Args::from_env();
+    args.apply();
+    let mut config_file = config::load_file(args.config);
+    let config = Config::from_env();
+    if is_client_key(config.admin_key.as_ref(), &config.keys) {
+        eprintln!("ADMIN_KEY must differ from KEY!");
+        std::process::exit(1);
+    }
+
+    // Store all IP addresses in thread-safe hash maps: the default store,
+    // plus an independent one per tenant
+    let new_store = |tenant: Option<&str>| {
+        let records = if config.user_index { Records::indexed() } else { Records::new() };
+        let mut records = records.with_keys(config.keys.clone()).with_history(config.history_size);
+        if let Some(path) = &config.snapshot_path {
+            let path = tenant_path(path, tenant);
+            if let Err(e) = snapshot::load(&mut records, &path, config.snapshot_checksum) {
+                eprintln!("Unable to load snapshot {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        // Rebuild from the event log, then keep appending to it
+        if let Some(path) = &config.event_log {
+            let path = tenant_path(path, tenant);
+            let journal = events::replay(&mut records, &path)
+                .and_then(|_| events::Journal::open(&path))
+                .unwrap_or_else(|e| {
+                    eprintln!("Unable to load event log {}: {}", path.display(), e);
                     std::process::exit(1);
-                })
-                .unwrap()
+                });
+            records = records.with_journal(journal);
+        }
+        Store::new(records, Debounce::new(config.min_update_interval))
+    };
+    let Store { db, debounce } = new_store(None);
+    let tenants = Tenants::new(config.tenants.as_deref().unwrap_or_default(), |t| new_store(Some(t)));
+    let stores = || {
+        let default = Store { db: db.clone(), debounce: debounce.clone() };
+        std::iter::once(default).chain(tenants.stores().cloned()).collect::<Vec<_>>()
+    };
+    let named_stores = || {
+        std::iter::once((None, db.clone()))
+            .chain(tenants.iter().map(|(name, store)| (Some(name.to_string()), store.db.clone())))
+            .collect::<Vec<_>>()
+    };
+
+    // Remove soft-deleted records once their recovery window has passed
+    if let Some(window) = config.soft_delete {
+        let stores = stores();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(window.clamp(1, 60)));
+            for store in &stores {
+                let mut db = store.db.write();
+                db::purge(&mut db, db::now(), window);
+            }
+        });
+    }
+
+    // Delete records that haven't been refreshed within their `RECORD_TTL`
+    if let Some(ttl) = config.record_ttl {
+        let (stores, window) = (stores(), config.soft_delete);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(ttl.clamp(1, 60)));
+            for store in &stores {
+                let mut db = store.db.write();
+                for (id, ip) in db::expire(&mut db, db::now(), ttl, window) {
+                    println!("[EXPIRE] USER:{} IP:{}", id.user, ip);
+                }
+            }
+        });
+    }
+
+    // Fold the event logs into their snapshots so they don't grow forever
+    if config.event_log.is_some() {
+        let (stores, interval) = (stores(), config.compact_interval.max(1));
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            for store in &stores {
+                let mut db = store.db.write();
+                if let Err(e) = db::compact(&mut db) {
+                    eprintln!("[ERROR] Unable to compact the event log: {}", e);
+                }
+            }
+        });
+    }
+
+    // Save each store to its snapshot file
+    if let Some(path) = &config.snapshot_path {
+        let snapshots = named_stores().into_iter()
+            .map(|(tenant, db)| (db, tenant_path(path, tenant.as_deref())))
+            .collect::<Vec<_>>();
+        let (interval, checksum) = (config.snapshot_interval.max(1), config.snapshot_checksum);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval));
+            for (db, path) in &snapshots {
+                let db = db.read();
+                if let Err(e) = snapshot::save(&db, path, checksum) {
+                    eprintln!("[ERROR] Unable to save snapshot {}: {}", path.display(), e);
+                }
+            }
+        });
+    }
+
+    let hook = config.on_change_cmd.clone().map(|cmd| Hook::new(cmd, config.on_change_timeout));
+    let geoip = GeoIp::open(config.geoip_db.as_deref().unwrap_or_default()).unwrap_or_else(|e| {
+        eprintln!("Unable to load GEOIP_DB: {}", e);
+        std::process::exit(1);
+    });
+
+    let acme = config.acme_dns_file.as_ref().map(|path| Accounts::open(path).unwrap_or_else(|e| {
+        eprintln!("Unable to load ACME_DNS_FILE {}: {}", path.display(), e);
+        std::process::exit(1);
+    }));
+    if acme.is_some() && config.dns_zone.is_none() {
+        eprintln!("ACME_DNS_FILE requires DNS_ZONE!");
+        std::process::exit(1);
+    }
+    if config.pdns_backend && config.dns_zone.is_none() {
+        eprintln!("PDNS_BACKEND requires DNS_ZONE!");
+        std::process::exit(1);
+    }
+    let upstream = match (config.nsupdate_server, &config.dns_zone) {
+        (Some(server), Some(zone)) => Some(Upstream::new(server, zone, config.dns_ttl, config.nsupdate_key.clone())),
+        (Some(_), None) => {
+            eprintln!("NSUPDATE_SERVER requires DNS_ZONE!");
+            std::process::exit(1);
+        }
+        (None, _) => None,
+    };
+
+    // Answer DNS queries for the default store's records, if configured
+    match (config.dns_addr, dns::Zone::from_config(&config)) {
+        (Some(addr), Some(zone)) => {
+            if let Err(e) = dns::serve(addr, zone.clone(), db.clone(), acme.clone().unwrap_or_default()) {
+                eprintln!("Unable to answer DNS on {}: {}", addr, e);
+                std::process::exit(listen::EXIT_BIND);
+            }
+            println!("Answering DNS for {} on {}", zone.origin, addr);
+        }
+        (Some(_), None) => {
+            eprintln!("DNS_ADDR requires DNS_ZONE!");
+            std::process::exit(1);
+        }
+        (None, _) => {}
+    }
+
+    // Notify changes held back by `MIN_UPDATE_INTERVAL` once they settle
+    if config.min_update_interval > 0 {
+        let (stores, hook, upstream, geoip) = (stores(), hook.clone(), upstream.clone(), geoip.clone());
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            for store in &stores {
+                let db = store.db.read();
+                for (id, old, new) in store.debounce.flush(&db, db::now()) {
+                    notify(hook.as_ref(), upstream.as_ref(), &geoip, &id.user, Some(old), new);
+                }
+            }
+        });
+    }
+
+    // Detect the server's own public IP in the background, if configured
+    let server_ip = ServerIp::default();
+    if let Some(url) = config.server_ip_url.clone() {
+        server_ip.spawn(config.server_ip_refresh, move || server_ip::detect(&url));
+    }
+
+    // Write the record table on SIGUSR1
+    dump::on_signal_dump(named_stores(), config.dump_path.clone());
+
+    // Finish in-flight requests and save state before exiting on SIGINT or
+    // SIGTERM
+    let drain = Drain::default();
+    let (flush_stores, snapshot_path, checksum) = (named_stores(), config.snapshot_path.clone(), config.snapshot_checksum);
+    let unix_socket = config.unix_socket.clone();
+    shutdown::on_signal_drain(drain.clone(), config.shutdown_timeout, move || {
+        if let Some(path) = &unix_socket {
+            std::fs::remove_file(path).ok();
+        }
+        // Persist every store, holding its lock so nothing changes afterwards
+        for (tenant, db) in &flush_stores {
+            let mut db = db.write();
+            if let Some(path) = &snapshot_path {
+                let path = tenant_path(path, tenant.as_deref());
+                if let Err(e) = snapshot::save(&db, &path, checksum) {
+                    eprintln!("[ERROR] Unable to save snapshot {}: {}", path.display(), e);
+                }
+            }
+            if let Err(e) = db::compact(&mut db) {
+                eprintln!("[ERROR] Unable to compact the event log: {}", e);
+            }
+        }
+    });
+
+    let load_users = |path: Option<PathBuf>| path.map(|path| Users::load(&path)).transpose();
+    let users = load_users(config.credentials_file.clone()).unwrap_or_else(|e| {
+        eprintln!("Invalid CREDENTIALS_FILE {}", e);
+        std::process::exit(1);
+    });
+
+    // Apply new keys and users from the config and credentials files on SIGHUP
+    let admin_key = Arc::new(RwLock::new(config.admin_key.clone()));
+    let users = Arc::new(RwLock::new(users));
+    let (reload_stores, grace) = (named_stores(), config.key_rotation_grace);
+    let (reload_admin_key, reload_users) = (admin_key.clone(), users.clone());
+    reload::on_signal_reload(move || {
+        if let Some(file) = &mut config_file {
+            if let Err(e) = file.reload() {
+                eprintln!("[ERROR] Unable to reload {}: {}", file.path.display(), e);
+                return;
+            }
+        }
+        let keys = config::try_env_keys();
+        let admin = config::try_env_key("ADMIN_KEY");
+        let users = load_users(std::env::var_os("CREDENTIALS_FILE").map(PathBuf::from));
+        let (keys, admin, users) = match (keys, admin, users) {
+            (Ok(keys), Ok(admin), Ok(users)) => (keys, admin, users),
+            (Err(e), ..) | (_, Err(e), _) | (.., Err(e)) => return eprintln!("[ERROR] Not reloading: {}", e),
+        };
+        if is_client_key(admin.as_ref(), &keys) {
+            return eprintln!("[ERROR] Not reloading: ADMIN_KEY must differ from KEY");
+        }
+        for (_, db) in &reload_stores {
+            let mut db = db.write();
+            db::set_keys(&mut db, keys.clone(), db::now(), grace);
+        }
+        *reload_admin_key.write() = admin;
+        *reload_users.write() = users;
+        eprintln!("Reloaded KEY, ADMIN_KEY and CREDENTIALS_FILE");
+    });
+
+    let invites = Invites::default();
+    let trusted_proxies = Arc::new(RwLock::new(config.trusted_proxies.clone()));
+    let rate_limit = config.rate_limit.map(RateLimit::new);
+    let lockout = config.lockout_threshold.map(|n| Lockout::new(n, config.lockout_duration));
+    let services = Services { server_ip, drain, debounce, hook, tenants, admin_key, trusted_proxies, users, invites, rate_limit, lockout, geoip, acme, upstream };
+    let serve = || warp::serve(routes(&config, db.clone(), services.clone()));
+    let mut servers: Vec<Box<dyn Future<Item = (), Error = ()> + Send>> = Vec::new();
+    // Serve the sockets systemd passed, if socket-activated
+    let activated = listen::activated().unwrap_or_else(|e| {
+        eprintln!("Unable to use the sockets passed by systemd: {}", e);
+        std::process::exit(listen::EXIT_BIND);
+    });
+    for listener in activated {
+        match listener {
+            listen::Activated::Tcp(listener) => {
+                if let Ok(addr) = listener.local_addr() {
+                    eprintln!("d5 running on {} (socket-activated)", addr);
+                }
+                servers.push(Box::new(serve().serve_incoming(listener.incoming())));
+            }
+            listen::Activated::Unix(listener) => {
+                eprintln!("d5 running on a Unix socket (socket-activated)");
+                servers.push(Box::new(serve().serve_incoming(listener.incoming())));
+            }
+        }
+    }
+    // Otherwise listen on `LISTEN`, or on every `HOST` address
+    if servers.is_empty() {
+        match &config.unix_socket {
+            Some(path) => {
+                let listener = listen::bind_unix(path, config.unix_socket_mode).unwrap_or_else(|e| {
+                    eprintln!("Unable to listen on {}: {}", path.display(), e);
+                    std::process::exit(listen::EXIT_BIND);
+                });
+                eprintln!("d5 running on unix:{}", path.display());
+                servers.push(Box::new(serve().serve_incoming(listener.incoming())));
+            }
+            None => {
+                for &addr in &config.addrs {
+                    let bind = || serve().try_bind_ephemeral(addr);
+                    let (addr, server) = listen::bind(addr, config.bind_retries, bind).unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(listen::EXIT_BIND);
+                    });
+                    eprintln!("d5 running on {}", addr);
+                    servers.push(Box::new(server));
+                }
+            }
+        }
+    }
+
+    for k in &config.keys {
+        eprintln!("Using key '{}'", k);
+    }
+
+    tokio::run(futures::future::join_all(servers).map(|_| ()));
+}
+
+/// `path`, suffixed with `.TENANT` for a tenant's store
+fn tenant_path(path: &Path, tenant: Option<&str>) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    if let Some(tenant) = tenant {
+        path.push(format!(".{}", tenant));
+    }
+    path.into()
+}
+
+/// Build the full route tree, including error handling
+fn routes(config: &Config, db: DB, services: Services) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let Services { server_ip, drain, debounce, hook, tenants, admin_key, trusted_proxies, users, invites, rate_limit, lockout, geoip, acme, upstream } = services;
+
+    let max_records = config.max_records;
+    let soft_delete = config.soft_delete;
+    let record_ttl = config.record_ttl;
+    let allowed_families = config.allowed_families;
+    let reject_private = config.reject_private;
+    let allow_ip_override = config.allow_ip_override.clone();
+    let log_sample_rate = config.log_sample_rate;
+    let metrics_max_entries = config.metrics_max_entries;
+
+    // The client's IP as the reverse proxy reports it, ignoring the headers
+    // of peers outside `TRUSTED_PROXIES` (if set), and the peer's address
+    let xff_policy = config.xff_policy;
+    let trusted = trusted_proxies.clone();
+    let reported_ip = header::optional::<String>("x-forwarded-for")
+        .and(header::optional::<String>("forwarded"))
+        .and(header::optional::<String>("remote_addr"))
+        .and(warp::addr::remote())
+        .map(move |xff: Option<String>, forwarded: Option<String>, remote_addr: Option<String>, peer: Option<SocketAddr>| {
+            let chain = forwarded_chain(xff, forwarded);
+            let trusted = trusted.read();
+            let policy = xff_policy.unwrap_or(match *trusted {
+                Some(_) => XffPolicy::RightmostUntrusted,
+                None => XffPolicy::First,
+            });
+            (reported_ip(trusted.as_deref(), policy, chain, remote_addr, peer), peer)
+        })
+        .untuple_one();
+
+    // Whether a client IP is in the `RATELIMIT_EXEMPT_NETWORKS`
+    let ratelimit_exempt = Arc::new(config.ratelimit_exempt.clone());
+    let is_exempt = move |ip: &str| parse_ip(ip).is_some_and(|ip| ratelimit_exempt.iter().any(|net| net.contains(ip)));
+    let attempt_exempt = is_exempt.clone();
+
+    // Reject clients over their `RATE_LIMIT`, keyed on the IP the reverse
+    // proxy reports or else the peer's
+    let check_rate = reported_ip.clone()
+        .and_then(move |reported: Option<String>, peer: Option<SocketAddr>| {
+            let ip = reported.or_else(|| peer.map(|addr| addr.ip().to_string()));
+            match (&rate_limit, ip) {
+                (Some(limit), Some(ip)) if !is_exempt(&ip) && !limit.allow(&ip, Instant::now()) => Err(warp_err(TooManyRequests)),
+                _ => Ok(()),
+            }
+        })
+        .untuple_one();
+
+    // Who each request is from, for logging auth failures and for
+    // `LOCKOUT_THRESHOLD`, which refuses requests with credentials from a
+    // locked-out client IP or username
+    let check_lockout = lockout.clone();
+    let attempt = header::optional::<String>("authorization")
+        .and(reported_ip.clone())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(move |auth: Option<String>, reported: Option<String>, peer: Option<SocketAddr>, method: warp::http::Method, path: FullPath| {
+            let ip = reported.or_else(|| peer.map(|addr| addr.ip().to_string()));
+            let attempt = Attempt {
+                exempt: ip.as_deref().is_some_and(&attempt_exempt),
+                ip,
+                user: auth.as_ref().and_then(|auth| Id::from_basic(auth).ok()).map(|id| id.user),
+                has_credentials: auth.is_some(),
+                route: format!("{} {}", method, path.as_str()),
+            };
+            match &check_lockout {
+                Some(lockout) if lockout.locked(&attempt.lockout_keys(), db::now()) => Err(warp_err(LockedOut)),
+                _ => Ok(attempt),
+            }
+        });
+
+    // The request's store: its tenant's, named by `X-Tenant` or the first
+    // label of `Host`, or else the default store
+    let default_db = db.clone();
+    let default_store = Store { db, debounce };
+    let store = header::optional::<String>("x-tenant")
+        .and(header::optional::<String>("host"))
+        .and_then(move |tenant: Option<String>, host: Option<String>| {
+            match tenants.find(tenant.as_deref(), host.as_deref()) {
+                Ok(store) => Ok(store.unwrap_or(&default_store).clone()),
+                Err(()) => Err(warp_err(UnknownTenant)),
+            }
+        });
+    let db = store.clone().map(|store: Store| store.db);
+
+    // The caller's `Authorization: Basic` credentials, or for an
+    // `Authorization: Bearer` token, those of the `CREDENTIALS_FILE` user or
+    // record it belongs to.  Credentials that prove to be genuine clear the
+    // caller's `LOCKOUT_THRESHOLD` failures.
+    let (bearer_users, register_users, password_users) = (users.clone(), users.clone(), users.clone());
+    let (success_lockout, success_admin_key, rotate_admin_key) = (lockout.clone(), admin_key.clone(), admin_key.clone());
+    let (get_users, get_admin_key) = (users.clone(), admin_key.clone());
+    let credentials = header::optional::<String>("authorization")
+        .and(db.clone())
+        .and(attempt.clone())
+        .and_then(move |auth: Option<String>, db: DB, attempt: Attempt| -> Result<Id, Rejection> {
+            let auth = auth.ok_or_else(|| warp_err(Unauthorized))?;
+            let db = db.read();
+            let (id, genuine) = match auth.trim().strip_prefix("Bearer ") {
+                Some(token) => (token_id(&bearer_users, &db, token.trim()).ok_or_else(|| warp_err(Unauthorized))?, true),
+                None => {
+                    let id = Id::from_basic(&auth).map_err(warp_err)?;
+                    let genuine = genuine(&db, &bearer_users, &success_admin_key, &id);
+                    (id, genuine)
+                }
+            };
+            if let (Some(lockout), true) = (&success_lockout, genuine) {
+                lockout.success(&attempt.lockout_keys());
+            }
+            Ok(id)
+        });
+
+    // Only `Authorization: Basic` credentials, for managing tokens
+    let password = header::optional::<String>("authorization")
+        .and_then(|auth: Option<String>| match auth {
+            Some(auth) if !auth.trim().starts_with("Bearer ") => Id::from_basic(&auth).map_err(warp_err),
+            _ => Err(warp_err(Unauthorized)),
+        });
+
+    // Admin routes are only available when `ADMIN_KEY` is set
+    let admin = credentials.clone()
+        .and_then(move |id: Id| match &*admin_key.read() {
+            Some(k) if k.ct_eq(&id) => Ok(()),
+            Some(_) => Err(warp_err(Unauthorized)),
+            None => Err(warp::reject::not_found()),
+        })
+        .untuple_one();
+
+    // Reject requests for unexpected hosts when `ALLOWED_HOSTS` is set
+    let allowed_hosts = config.allowed_hosts.clone();
+    let host_check_exempt = config.host_check_exempt.clone();
+    let check_host = header::optional::<String>("host")
+        .and(warp::path::full())
+        .and_then(move |host: Option<String>, path: FullPath| match &allowed_hosts {
+            Some(allowed) if !host_check_exempt.iter().any(|p| p == path.as_str())
+                && !host.is_some_and(|h| host_allowed(allowed, &h)) => {
+                Err(warp_err(MisdirectedRequest))
+            }
+            _ => Ok(()),
         })
-        .ok();
+        .untuple_one();
 
-    let display_key = key.clone();
+    // Reject `X-Forwarded-For` chains longer than `MAX_XFF_ENTRIES`
+    let max_xff_entries = config.max_xff_entries;
+    let check_xff = header::optional::<String>("x-forwarded-for")
+        .and_then(move |xff: Option<String>| match (max_xff_entries, xff) {
+            (Some(max), Some(xff)) if xff.split(',').count() > max => Err(warp_err(BadRequest)),
+            _ => Ok(()),
+        })
+        .untuple_one();
 
-    let key = warp::any().map(move || key.clone());
+    let accept = header::optional::<String>("accept");
 
-    // Store all IP addresses in a thread-safe hash map
-    let db: DB = Arc::new(RwLock::new(HashMap::new()));
-    let db = warp::any().map(move || db.clone());
+    // The caller's IP, as reported by the reverse proxy
+    let observed_ip = reported_ip.map(|reported: Option<String>, _| reported);
+    // ...parsed, if it is a valid IP
+    let caller_ip = observed_ip.clone()
+        .map(|ip: Option<String>| ip.and_then(|ip| parse_ip(&ip)));
+    let client_ip = caller_ip.clone()
+        .and_then(|ip: Option<IpAddr>| ip.ok_or_else(|| warp_err(BadRequest)));
 
+    // `GET /?family=4|6|all` picks the record's IPv4 or IPv6 address, or
+    // both; by default the one stored most recently is returned.  Credentials
+    // that aren't genuine are refused, and so count towards `LOCKOUT_THRESHOLD`.
+    let get_geoip = geoip.clone();
     let get = warp::get2()
-        .and(header("authorization"))
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(accept)
+        .and(warp::query::<HashMap<String, String>>())
         .and(db.clone())
-        .and_then(move |id: String, db: DB| -> WarpResult {
-            let id = Id::from_basic(&id);
-            match db.read().map_err(|_| warp_err(Db))?.get(&id) {
-                Some(ip) => {
-                    log(&Get, &id, &ip);
-                    Ok(ip.to_string())
+        .and_then(move |id: Id, accept: Option<String>, query: HashMap<String, String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let now = db::now();
+            let id = db::authenticate(&mut db, id, now);
+            // Answered here rather than rejected, so `show` can't stand in
+            let entry = match db.get_live(&id) {
+                Some(entry) => entry,
+                None if !genuine(&db, &get_users, &get_admin_key, &id) && !unknown_user(&db, &get_users, &id) => {
+                    return Ok(error_response(&Unauthorized));
                 }
-                None => Err(warp::reject::custom(NotFound)),
+                None => return Err(warp_err(NotFound)),
+            };
+            if record_ttl.is_some_and(|ttl| entry.is_expired(now, ttl)) {
+                return Ok(error_response(&NotFound));
+            }
+            let text = picked_addresses(&query, entry.ip, |family| entry.address(family)).map_err(warp_err)?;
+            if sampled(&Get, log_sample_rate) {
+                log(&Get, &id, text.replace('\n', ","));
             }
+            let mut json = entry.to_json();
+            if let Some(geo) = get_geoip.lookup(entry.ip) {
+                json["geo"] = geo.to_json();
+            }
+            Ok(with_last_modified(reply(accept, text, json), entry.updated_at))
         });
 
+    // With `SHOW_REDIRECT` enabled, `GET /?redirect=URL` redirects to `URL`
+    // with `{ip}` replaced by the caller's IP
+    let show_redirect = config.show_redirect;
+    // With `SHOW_NO_CONTENT` enabled, `show` is a beacon: it logs the caller's
+    // IP but responds with an empty `204`
+    let show_no_content = config.show_no_content;
     let show = warp::get2()
-        .and(header("X-Forwarded-For").or(header("remote_addr")).unify())
-        .and_then(move |ip: String| -> WarpResult {
-            log(&Get, "UNKNOWN", &ip);
-            Ok(ip)
+        .and(warp::path::end())
+        .and(client_ip.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |ip: IpAddr, query: HashMap<String, String>| -> ReplyResult {
+            let redirect = match query.get("redirect") {
+                Some(template) if show_redirect => Some(redirect_url(template, ip).map_err(warp_err)?),
+                _ => None,
+            };
+            if sampled(&Get, log_sample_rate) {
+                log(&Get, "UNKNOWN", ip);
+            }
+            Ok(match redirect {
+                Some(url) => {
+                    let reply = warp::reply::with_header(warp::reply(), "location", url);
+                    with_status(reply, Code::FOUND).into_response()
+                }
+                None if show_no_content => with_status(warp::reply(), Code::NO_CONTENT).into_response(),
+                None => ip.to_string().into_response(),
+            })
         });
 
+    // Store an IP for the record a DDNS client's hostname names: the user's
+    // own if its first label is the username (or it is empty), else that
+    // named record.  Returns the record's previous address.
+    let update_hostname = {
+        let (hook, upstream, geoip) = (hook.clone(), upstream.clone(), geoip.clone());
+        Arc::new(move |db: &mut Records, debounce: &Debounce, id: &Id, hostname: &str, ip: IpAddr, now: u64| {
+            let label = hostname.split('.').next().unwrap_or_default().to_ascii_lowercase();
+            if !label.is_empty() && label != id.user {
+                return store_host(db, id, &label, ip, now, max_records).map(|(previous, _)| previous);
+            }
+            let (previous, _) = db::insert(db, id.clone(), ip, now, max_records)?;
+            if previous == Some(ip) {
+                if sampled_unchanged(log_sample_rate) {
+                    log("UNCHANGED", &id.user, geoip.annotate(ip));
+                }
+            } else if debounce.change(id, ip, now) {
+                notify(hook.as_ref(), upstream.as_ref(), &geoip, &id.user, previous, ip);
+            }
+            Ok(previous)
+        })
+    };
+
+    // With `ALLOW_IP_OVERRIDE`, `POST /?myip=IP` stores `IP` instead of the
+    // caller's IP, and with `ALLOW_IP_BODY` so does a body of `IP [NAME]` or
+    // `{"ip": IP, "name": NAME}`, into the named record `NAME` if given
+    let (post_users, post_geoip, post_override) = (users.clone(), geoip.clone(), allow_ip_override.clone());
+    let allow_ip_body = config.allow_ip_body.clone();
+    // The credentials, and the body only if `ALLOW_IP_BODY` permits the user
+    let credentials_and_body = credentials.clone()
+        .and(header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(move |id: Id, length: Option<u64>, body: warp::body::BodyStream| {
+            read_body(allow_ip_body.allows(&id.user), length, body).map(move |body| (id, body))
+        })
+        .untuple_one();
     let post = warp::post2()
-        .and(header("X-Forwarded-For").or(header("remote_addr")).unify())
-        .and(warp::header::<String>("authorization"))
-        .and(db.clone())
-        .and(key.clone())
-        .and_then(move |ip: String, id: String, db: DB, key: Option<Key>| {
-            let id = Id::from_basic(&id);
-            if key.is_some() && key.unwrap() != id {
-                return Err(warp_err(Unauthorized));
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(credentials_and_body)
+        .and(accept)
+        .and(store.clone())
+        .and_then(move |caller: Option<IpAddr>, query: HashMap<String, String>, id: Id, body: Vec<u8>, accept: Option<String>, store: Store| -> ReplyResult {
+            let Store { db, debounce } = store;
+            let mut db = db.write();
+            let now = db::now();
+            let id = db::authenticate(&mut db, id, now);
+            let (ip, name) = match parse_ip_body(&body).map_err(warp_err)? {
+                Some((ip, name)) => (ip, name),
+                None => (stored_ip(&query, caller, &id, &post_override)?, None),
+            };
+            may_store(&db, &id, &post_users, allowed_families, reject_private, ip)?;
+            if let Some(name) = name {
+                let (previous, host) = store_host(&mut db, &id, &name, ip, now, max_records).map_err(warp_err)?;
+                return Ok(update_reply(accept, ip, host.to_json(), previous != Some(ip)));
+            }
+            let (previous, entry) = db::insert(&mut db, id.clone(), ip, now, max_records)
+                .map_err(warp_err)?;
+            if previous == Some(ip) {
+                if sampled_unchanged(log_sample_rate) {
+                    log("UNCHANGED", &id.user, post_geoip.annotate(ip));
+                }
+            } else if debounce.change(&id, ip, now) {
+                notify(hook.as_ref(), upstream.as_ref(), &post_geoip, &id.user, previous, ip);
+            }
+            Ok(update_reply(accept, ip, entry.to_json(), previous != Some(ip)))
+        });
+
+    // `GET /nic/update?hostname=HOST[,HOST...]&myip=IP` speaks the DynDNS2
+    // protocol of consumer routers and ddclient, updating each hostname's
+    // record.  `myip` is honoured only with `ALLOW_IP_OVERRIDE`.
+    let optional_credentials = credentials.clone()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify();
+    let (dyndns_users, dyndns_override, dyndns_update) = (users.clone(), allow_ip_override.clone(), update_hostname.clone());
+    let nic_update = warp::get2()
+        .and(warp::path("nic"))
+        .and(warp::path("update"))
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(optional_credentials.clone())
+        .and(store.clone())
+        .map(move |caller: Option<IpAddr>, query: HashMap<String, String>, id: Option<Id>, store: Store| {
+            let id = match id {
+                Some(id) => id,
+                None => return dyndns_reply(Code::UNAUTHORIZED, "badauth".to_string()),
+            };
+            let Store { db, debounce } = store;
+            let mut db = db.write();
+            let now = db::now();
+            let id = db::authenticate(&mut db, id, now);
+            let myip = query.get("myip").filter(|_| dyndns_override.allows(&id.user)).and_then(|ip| parse_ip(ip));
+            let ip = match myip.or(caller) {
+                Some(ip) => ip,
+                None => return dyndns_reply(Code::OK, "911".to_string()),
+            };
+            if let Err(rejection) = may_store(&db, &id, &dyndns_users, allowed_families, reject_private, ip) {
+                let code = match rejection.find_cause::<Err>() {
+                    Some(Unauthorized) => "badauth",
+                    _ => "abuse",
+                };
+                return dyndns_reply(Code::OK, code.to_string());
+            }
+            let hostnames = query.get("hostname").map_or("", String::as_str);
+            let lines = hostnames.split(',').map(|hostname| {
+                match dyndns_update(&mut db, &debounce, &id, hostname.trim(), ip, now) {
+                    Ok(previous) if previous == Some(ip) => format!("nochg {}", ip),
+                    Ok(_) => format!("good {}", ip),
+                    Err(BadRequest) => "notfqdn".to_string(),
+                    Err(RecordLimit) => "numhost".to_string(),
+                    Err(_) => "911".to_string(),
+                }
+            });
+            dyndns_reply(Code::OK, lines.collect::<Vec<_>>().join("\n"))
+        });
+
+    // `GET /update?domains=NAME[,NAME...]&token=TOKEN&ip=IP` speaks the DuckDNS
+    // protocol, with a token from `POST /tokens` or the `CREDENTIALS_FILE`
+    // standing in for credentials, and answers `OK` or `KO`
+    let (duckdns_users, duckdns_override, duckdns_update) = (users.clone(), allow_ip_override.clone(), update_hostname.clone());
+    let duckdns = warp::get2()
+        .and(warp::path("update"))
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(store.clone())
+        .map(move |caller: Option<IpAddr>, query: HashMap<String, String>, store: Store| {
+            let Store { db, debounce } = store;
+            let mut db = db.write();
+            let now = db::now();
+            let token = query.get("token").map_or("", |token| token.trim());
+            let id = match token_id(&duckdns_users, &db, token).filter(|_| !token.is_empty()) {
+                Some(id) => db::authenticate(&mut db, id, now),
+                None => return "KO".to_string(),
+            };
+            let ip = query.get("ip")
+                .filter(|ip| !ip.is_empty() && duckdns_override.allows(&id.user))
+                .and_then(|ip| parse_ip(ip))
+                .or(caller);
+            let ip = match ip {
+                Some(ip) if may_store(&db, &id, &duckdns_users, allowed_families, reject_private, ip).is_ok() => ip,
+                _ => return "KO".to_string(),
+            };
+            let mut changed = false;
+            for domain in query.get("domains").map_or("", String::as_str).split(',') {
+                match duckdns_update(&mut db, &debounce, &id, domain.trim(), ip, now) {
+                    Ok(previous) => changed |= previous != Some(ip),
+                    Err(_) => return "KO".to_string(),
+                }
+            }
+            match query.get("verbose").map(String::as_str) {
+                Some("true") => {
+                    let (v4, v6) = match ip {
+                        IpAddr::V4(_) => (ip.to_string(), String::new()),
+                        IpAddr::V6(_) => (String::new(), ip.to_string()),
+                    };
+                    format!("OK\n{}\n{}\n{}", v4, v6, if changed { "UPDATED" } else { "NOCHANGE" })
+                }
+                _ => "OK".to_string(),
             }
-            log(&Post, &id.user, &ip);
-            db.write().map_err(|_| warp_err(Db))?.insert(id, ip.clone());
-            Ok(ip)
         });
 
     let delete = warp::delete2()
-        .and(header("authorization"))
-        .and(db)
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(db.clone())
         .and_then(move |id: Id, db: DB| -> WarpResult {
-            match db.write().map_err(|_| warp_err(Db))?.remove(&id) {
-                Some(ip) => {
-                    log(&Delete, &id.user, &ip);
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            match db::delete(&mut db, &id, db::now(), soft_delete) {
+                Some(entry) => {
+                    log(&Delete, &id.user, entry.ip);
                     Ok(format!("IP deleted for ID: {}", &id))
                 }
                 None => Err(warp_err(NotFound)),
             }
         });
 
-    let handle_err = |err: warp::Rejection| match err.find_cause::<Err>() {
-        Some(Db) => Ok(with_status(Db.to_string(), Code::INTERNAL_SERVER_ERROR)),
-        Some(NotFound) => Ok(with_status(NotFound.to_string(), Code::NOT_FOUND)),
-        Some(Unauthorized) => Ok(with_status(Unauthorized.to_string(), Code::UNAUTHORIZED)),
-        None => Err(err),
-    };
+    // `POST /undelete` restores a record deleted within the `SOFT_DELETE` window
+    let undelete = warp::post2()
+        .and(warp::path("undelete"))
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(db.clone())
+        .and_then(move |id: Id, db: DB| -> WarpResult {
+            let window = soft_delete.ok_or_else(warp::reject::not_found)?;
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let entry = db::undelete(&mut db, &id, db::now(), window).map_err(warp_err)?;
+            log(&Post, &id.user, entry.ip);
+            Ok(entry.ip.to_string())
+        });
 
-    eprintln!("d5 running on {}:{}", addr, port);
+    // `POST /record/NAME` stores the caller's IP under a named record, such as
+    // one per machine; `GET /record/NAME` reads it back (honouring `?family`),
+    // `DELETE /record/NAME` removes it and `GET /records` lists them all
+    let record_users = users.clone();
+    let update_record = warp::post2()
+        .and(warp::path("record"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(credentials.clone())
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |name: String, caller: Option<IpAddr>, query: HashMap<String, String>, id: Id, accept: Option<String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let now = db::now();
+            let id = db::authenticate(&mut db, id, now);
+            let ip = stored_ip(&query, caller, &id, &allow_ip_override)?;
+            may_store(&db, &id, &record_users, allowed_families, reject_private, ip)?;
+            let (previous, host) = store_host(&mut db, &id, &name, ip, now, max_records).map_err(warp_err)?;
+            Ok(update_reply(accept, ip, host.to_json(), previous != Some(ip)))
+        });
+    let get_record = warp::get2()
+        .and(warp::path("record"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(accept)
+        .and(warp::query::<HashMap<String, String>>())
+        .and(db.clone())
+        .and_then(move |name: String, id: Id, accept: Option<String>, query: HashMap<String, String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let now = db::now();
+            let id = db::authenticate(&mut db, id, now);
+            let host = db.get_live(&id)
+                .filter(|e| !record_ttl.is_some_and(|ttl| e.is_expired(now, ttl)))
+                .and_then(|e| e.hosts.get(&name))
+                .ok_or_else(|| warp_err(NotFound))?;
+            let text = picked_addresses(&query, host.ip, |family| host.address(family)).map_err(warp_err)?;
+            Ok(with_last_modified(reply(accept, text, host.to_json()), host.updated_at))
+        });
+    let delete_record = warp::delete2()
+        .and(warp::path("record"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(db.clone())
+        .and_then(move |name: String, id: Id, db: DB| {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let host = db::remove_host(&mut db, &id, &name).map_err(warp_err)?;
+            log(&Delete, format!("{}/{}", id.user, name), host.ip);
+            Ok::<_, Rejection>(with_status(warp::reply(), Code::NO_CONTENT))
+        });
+    let list_records = warp::get2()
+        .and(warp::path("records"))
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |id: Id, accept: Option<String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let hosts = db.get_live(&id).map(|e| e.hosts.clone()).unwrap_or_default();
+            let text = hosts.iter()
+                .flat_map(|(name, h)| std::iter::once(h.ip).chain(h.other_ip).map(move |ip| format!("{} {}\n", name, ip)))
+                .collect::<String>();
+            let json = hosts.iter().map(|(name, h)| (name.clone(), h.to_json())).collect::<serde_json::Map<_, _>>();
+            Ok(reply(accept, text, json.into()))
+        });
 
-    if let Some(k) = display_key {
-        eprintln!("Using key '{}'", k);
-    }
+    // `GET /history` lists the caller's latest address changes, newest first,
+    // as `TIME IP` lines
+    let history = warp::get2()
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(credentials.clone())
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |id: Id, accept: Option<String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let entry = db.get_live(&id).ok_or_else(|| warp_err(NotFound))?;
+            let changes = entry.history.iter().rev();
+            let text = changes.clone().map(|c| format!("{} {}\n", db::rfc3339(c.at), c.ip)).collect::<String>();
+            let json = changes.map(|c| serde_json::json!({ "ip": c.ip, "at": c.at })).collect();
+            Ok(reply(accept, text, serde_json::Value::Array(json)))
+        });
 
-    warp::serve(get.or(post).or(delete).or(show).recover(handle_err)).run((addr, port));
-}
+    // With `HTTPS_REDIRECT` enabled, a request the reverse proxy received
+    // over plain HTTP is redirected to HTTPS before anything else is done
+    let https_redirect = config.https_redirect;
+    let redirect_to_https = header::optional::<String>("x-forwarded-proto")
+        .and(header::optional::<String>("host"))
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(move |proto: Option<String>, host: Option<String>, method: warp::http::Method, path: FullPath, query: String| -> ReplyResult {
+            let host = match (proto, host) {
+                (Some(proto), Some(host)) if https_redirect && proto.trim().eq_ignore_ascii_case("http") => host,
+                _ => return Err(warp::reject::not_found()),
+            };
+            let mut location = format!("https://{}{}", host.trim(), path.as_str());
+            if !query.is_empty() {
+                location = format!("{}?{}", location, query);
+            }
+            // Only GET and HEAD may be answered with a 301; other methods need
+            // a 308 so clients don't turn them into a GET
+            let status = match method {
+                warp::http::Method::GET | warp::http::Method::HEAD => Code::MOVED_PERMANENTLY,
+                _ => Code::PERMANENT_REDIRECT,
+            };
+            let reply = warp::reply::with_header(warp::reply(), "location", location);
+            Ok(with_status(reply, status).into_response())
+        });
 
-fn log<X, Y, Z>(rest: X, id: Y, ip: Z)
-where
-    X: fmt::Display,
-    Y: fmt::Display,
-    Z: fmt::Display,
-{
-    println!("[{}] USER:{} IP:{}", rest, id, ip);
-}
+    // `OPTIONS` answers with the methods a path supports, or for `OPTIONS *`
+    // every method d5 supports
+    let options = warp::options()
+        .and(warp::path::full())
+        .and_then(|path: FullPath| -> ReplyResult {
+            let methods = allowed_methods(path.as_str());
+            if methods.is_empty() {
+                return Err(warp::reject::not_found());
+            }
+            let reply = warp::reply::with_header(warp::reply(), "allow", methods.join(", "));
+            Ok(with_status(reply, Code::NO_CONTENT).into_response())
+        });
 
-/// The HTTP REST methods
-#[derive(Debug)]
-enum Rest {
-    Post,
-    Get,
-    // Put,
-    // Patch,
-    Delete,
-}
+    // `POST /touch` marks a record as fresh without changing its IP
+    let touch = warp::post2()
+        .and(warp::path("touch"))
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(credentials.clone())
+        .and(db.clone())
+        .and_then(move |ip: Option<IpAddr>, id: Id, db: DB| {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            db::touch(&mut db, &id, db::now(), ip).map_err(warp_err)?;
+            Ok::<_, Rejection>(with_status(warp::reply(), Code::NO_CONTENT))
+        });
 
-impl fmt::Display for Rest {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", format!("{:?}", self).to_uppercase())
-    }
-}
+    // `GET /server-ip` returns the server's own public IP, if detected
+    let server_ip_enabled = config.server_ip_url.is_some();
+    let server_ip = warp::get2()
+        .and(warp::path("server-ip"))
+        .and(warp::path::end())
+        .and(accept)
+        .and_then(move |accept: Option<String>| -> ReplyResult {
+            if !server_ip_enabled {
+                return Err(warp::reject::not_found());
+            }
+            let ip = server_ip.get();
+            let text = ip.map_or_else(|| "null".to_string(), |ip| ip.to_string());
+            Ok(reply(accept, text, serde_json::json!({ "ip": ip.map(|ip| ip.to_string()) })))
+        });
 
-#[derive(Debug)]
-enum Err {
-    Db,
-    NotFound,
-    Unauthorized,
-}
+    // `POST /tokens` mints a token usable in place of the caller's
+    // credentials, and `DELETE /tokens/ID` revokes one
+    let mint_token = warp::post2()
+        .and(warp::path("tokens"))
+        .and(warp::path::end())
+        .and(password)
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |id: Id, accept: Option<String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let (token_id, token) = db::mint_token(&mut db, &id, db::now()).map_err(warp_err)?;
+            log(&Post, &id.user, format!("minted token {}", token_id));
+            let text = format!("{} {}\n", token_id, token);
+            Ok(reply(accept, text, serde_json::json!({ "id": token_id, "token": token })))
+        });
 
-impl fmt::Display for Err {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}",
-            match self {
-                Self::Db => "Internal server error.",
-                Self::NotFound => "No IP found for that username–password pair.",
-                Self::Unauthorized => "Unauthorized request.",
+    let revoke_token = warp::delete2()
+        .and(warp::path("tokens"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(password)
+        .and(db.clone())
+        .and_then(move |token_id: String, id: Id, db: DB| {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            db::revoke_token(&mut db, &id, &token_id).map_err(warp_err)?;
+            log(&Delete, &id.user, format!("revoked token {}", token_id));
+            Ok::<_, Rejection>(with_status(warp::reply(), Code::NO_CONTENT))
+        });
+
+    // `POST /update-url` gives the caller's record a secret `/u/KEY` URL
+    // (replacing any other) that stores the IP of whoever GETs it, with no
+    // other credentials, for devices that can only fetch a fixed URL;
+    // `DELETE /update-url` revokes it
+    let mint_update_url = warp::post2()
+        .and(warp::path("update-url"))
+        .and(warp::path::end())
+        .and(password)
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |id: Id, accept: Option<String>, db: DB| -> ReplyResult {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            let key = db::mint_update_key(&mut db, &id).map_err(warp_err)?;
+            log(&Post, &id.user, "minted update URL");
+            let path = format!("/u/{}", key);
+            Ok(reply(accept, format!("{}\n", path), serde_json::json!({ "path": path })))
+        });
+    let revoke_update_url = warp::delete2()
+        .and(warp::path("update-url"))
+        .and(warp::path::end())
+        .and(password)
+        .and(db.clone())
+        .and_then(move |id: Id, db: DB| {
+            let mut db = db.write();
+            let id = db::authenticate(&mut db, id, db::now());
+            db::revoke_update_key(&mut db, &id).map_err(warp_err)?;
+            log(&Delete, &id.user, "revoked update URL");
+            Ok::<_, Rejection>(with_status(warp::reply(), Code::NO_CONTENT))
+        });
+    let update_url_users = users.clone();
+    let update_url = warp::get2()
+        .and(warp::path("u"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(client_ip.clone())
+        .and(store.clone())
+        .and_then(move |key: String, ip: IpAddr, store: Store| -> WarpResult {
+            let Store { db, debounce } = store;
+            let mut db = db.write();
+            let now = db::now();
+            let id = db.update_key_owner(&key).cloned().ok_or_else(|| warp_err(NotFound))?;
+            let id = db::authenticate(&mut db, id, now);
+            may_store(&db, &id, &update_url_users, allowed_families, reject_private, ip)?;
+            update_hostname(&mut db, &debounce, &id, "", ip, now).map_err(warp_err)?;
+            Ok(ip.to_string())
+        });
+
+    // With `PDNS_BACKEND`, `/dns/METHOD/ARGS...` answers the HTTP connector of
+    // PowerDNS's remote backend from the default store, for `DNS_ZONE`.  It
+    // lists every record, so only `PDNS_BACKEND_ALLOW` peers may use it.
+    let pdns_zone = dns::Zone::from_config(config).filter(|_| config.pdns_backend);
+    let (pdns_db, pdns_acme) = (default_db.clone(), acme.clone().unwrap_or_default());
+    let pdns_backend_allow = config.pdns_backend_allow.clone();
+    let pdns = warp::get2()
+        .or(warp::post2())
+        .unify()
+        .and(warp::path("dns"))
+        .and(warp::path::tail())
+        .and(warp::addr::remote())
+        .and(header::optional::<String>("x-forwarded-for"))
+        .and(header::optional::<String>("forwarded"))
+        .and(header::optional::<String>("remote_addr"))
+        .and_then(move |tail: warp::path::Tail, peer: Option<SocketAddr>, xff: Option<String>, forwarded: Option<String>, remote_addr: Option<String>| {
+            let proxied = xff.is_some() || forwarded.is_some() || remote_addr.is_some();
+            if !pdns_allowed(&pdns_backend_allow, peer, proxied) {
+                return Err(warp::reject::not_found());
             }
-        )
-    }
-}
+            let zone = pdns_zone.as_ref().ok_or_else(warp::reject::not_found)?;
+            let mut args = tail.as_str().split('/');
+            let method = args.next().unwrap_or_default();
+            let db = pdns_db.read();
+            let result = pdns::call(zone, &db, &pdns_acme, method, &args.collect::<Vec<_>>(), db::now());
+            Ok::<_, Rejection>(warp::reply::json(&result))
+        });
 
-impl std::error::Error for Err {}
+    // `POST /acme-dns/register` and `POST /acme-dns/update` speak the acme-dns
+    // API with `ACME_DNS_FILE`, setting the TXT records `DNS_ADDR` serves at
+    // `SUBDOMAIN.ZONE`.  Registering with a record's credentials also serves
+    // the account's TXT records at `_acme-challenge.USER.ZONE`.
+    let acme_zone = dns::Zone::from_config(config).map(|zone| zone.origin);
+    let (register_acme, update_acme) = (acme.clone(), acme);
+    let open_registration = config.acme_dns_open_registration;
+    let acme_register = warp::post2()
+        .and(warp::path("acme-dns"))
+        .and(warp::path("register"))
+        .and(warp::path::end())
+        .and(optional_credentials.clone())
+        .and(header::optional::<u64>("content-length"))
+        .and(warp::body::stream())
+        .and_then(|id: Option<Id>, length: Option<u64>, body: warp::body::BodyStream| {
+            read_body(true, length, body).map(move |body| (id, body))
+        })
+        .untuple_one()
+        .and_then(move |id: Option<Id>, body: Vec<u8>| -> ReplyResult {
+            let (accounts, zone) = match (&register_acme, &acme_zone) {
+                (Some(accounts), Some(zone)) => (accounts, zone),
+                _ => return Err(warp::reject::not_found()),
+            };
+            let owner = match id {
+                Some(id) => match default_db.read().get_live(&id) {
+                    Some(_) => Some(id.user),
+                    None => return Ok(acme_reply(Code::UNAUTHORIZED, "forbidden")),
+                },
+                // Anyone may register only with `ACME_DNS_OPEN_REGISTRATION`
+                None if open_registration => None,
+                None => return Ok(acme_reply(Code::UNAUTHORIZED, "forbidden")),
+            };
+            let allow_from = match body.as_slice() {
+                [] => Vec::new(),
+                body => match serde_json::from_slice::<serde_json::Value>(body) {
+                    Ok(v) => match v["allowfrom"].as_array().map(|nets| nets.iter().map(|net| net.as_str()?.parse().ok()).collect()) {
+                        Some(Some(nets)) => nets,
+                        Some(None) => return Ok(acme_reply(Code::BAD_REQUEST, "invalid_allowfrom_cidr")),
+                        None => Vec::new(),
+                    },
+                    Err(_) => return Ok(acme_reply(Code::BAD_REQUEST, "malformed_json_payload")),
+                },
+            };
+            let (account, password) = match accounts.register(owner.clone(), allow_from) {
+                Ok(account) => account,
+                Err(e @ "too_many_accounts") => return Ok(acme_reply(Code::FORBIDDEN, e)),
+                Err(_) => return Err(warp_err(Db)),
+            };
+            log(&Post, owner.as_deref().unwrap_or("-"), "registered acme-dns account");
+            let json = serde_json::json!({
+                "username": account.username,
+                "password": password,
+                "fulldomain": format!("{}.{}", account.subdomain, zone),
+                "subdomain": account.subdomain,
+                "allowfrom": account.allow_from.iter().map(Cidr::to_string).collect::<Vec<_>>(),
+            });
+            Ok(with_status(warp::reply::json(&json), Code::CREATED).into_response())
+        });
+    let acme_update = warp::post2()
+        .and(warp::path("acme-dns"))
+        .and(warp::path("update"))
+        .and(warp::path::end())
+        .and(caller_ip.clone())
+        .and(header::optional::<String>("x-api-user"))
+        .and(header::optional::<String>("x-api-key"))
+        .and(warp::body::content_length_limit(MAX_IP_BODY))
+        .and(warp::body::concat())
+        .and_then(move |caller: Option<IpAddr>, user: Option<String>, key: Option<String>, body: warp::body::FullBody| -> ReplyResult {
+            let accounts = update_acme.as_ref().ok_or_else(warp::reject::not_found)?;
+            let body = match serde_json::from_slice::<serde_json::Value>(body.bytes()) {
+                Ok(body) => body,
+                Err(_) => return Ok(acme_reply(Code::BAD_REQUEST, "malformed_json_payload")),
+            };
+            let (subdomain, txt) = (body["subdomain"].as_str().unwrap_or_default(), body["txt"].as_str().unwrap_or_default());
+            let (user, key) = (user.unwrap_or_default(), key.unwrap_or_default());
+            match accounts.update(user.trim(), key.trim(), subdomain, txt, caller) {
+                Ok(()) => Ok(warp::reply::json(&serde_json::json!({ "txt": txt })).into_response()),
+                Err(e @ "forbidden") => Ok(acme_reply(Code::UNAUTHORIZED, e)),
+                Err(e @ "db_error") => Ok(acme_reply(Code::INTERNAL_SERVER_ERROR, e)),
+                Err(e) => Ok(acme_reply(Code::BAD_REQUEST, e)),
+            }
+        });
+
+    // `POST /register?invite=CODE` adds the caller's credentials to the
+    // `CREDENTIALS_FILE`, using up an invite code from `POST /admin/invite`
+    let credentials_file = config.credentials_file.clone();
+    let register_invites = invites.clone();
+    let register = warp::post2()
+        .and(warp::path("register"))
+        .and(warp::path::end())
+        .and(password)
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |id: Id, query: HashMap<String, String>| -> ReplyResult {
+            let path = credentials_file.as_ref().ok_or_else(warp::reject::not_found)?;
+            let code = query.get("invite").ok_or_else(|| warp_err(BadRequest))?;
+            // Such a line would be read back as a comment or a different user
+            if id.user.trim() != id.user || id.user.starts_with('#') {
+                return Err(warp_err(BadRequest));
+            }
+            let mut invites = register_invites.write();
+            if !invites.contains(code) {
+                return Err(warp_err(Unauthorized));
+            }
+            let mut users = register_users.write();
+            let users = users.as_mut().ok_or_else(warp::reject::not_found)?;
+            if users.contains(&id.user) {
+                return Err(warp_err(UserExists));
+            }
+            users.register(path, &id).map_err(|e| {
+                eprintln!("[ERROR] Unable to write {}: {}", path.display(), e);
+                warp_err(Db)
+            })?;
+            invites.remove(code);
+            log(&Post, &id.user, "registered");
+            Ok(with_status("Registered\n", Code::CREATED).into_response())
+        });
+
+    // `PUT /password` moves the caller's record to the new password in the
+    // body; the old password stops working at once
+    let password_file = config.credentials_file.clone();
+    let change_password = warp::put2()
+        .and(warp::path("password"))
+        .and(warp::path::end())
+        .and(password)
+        .and(warp::body::content_length_limit(4 * id::MAX_LEN as u64))
+        .and(warp::body::concat())
+        .and(db.clone())
+        .and_then(move |id: Id, body: warp::body::FullBody, db: DB| {
+            let new = std::str::from_utf8(body.bytes())
+                .ok()
+                .and_then(|password| Id::try_from(format!("{}:{}", id.user, password.trim()).as_str()).ok())
+                .ok_or_else(|| warp_err(BadRequest))?;
+            let mut db = db.write();
+            // Single-user keys only change through the config or `/admin/key`
+            if !db.keys().is_empty() {
+                return Err(warp::reject::not_found());
+            }
+            let id = db::authenticate(&mut db, id, db::now());
+            if db.get_live(&id).is_none() {
+                return Err(warp_err(NotFound));
+            }
+            let mut users = password_users.write();
+            let users = users.as_mut().zip(password_file.as_ref());
+            if users.as_ref().is_some_and(|(users, _)| !users.allows(&id)) {
+                return Err(warp_err(Unauthorized));
+            }
+            // Move the record first, so the file only changes once nothing
+            // else can fail, and move it back if the file can't be written
+            db::rekey(&mut db, &id, new.clone()).map_err(warp_err)?;
+            if let Some((users, path)) = users {
+                if let Err(e) = users.set_password(path, &new) {
+                    eprintln!("[ERROR] Unable to write {}: {}", path.display(), e);
+                    db::rekey(&mut db, &new, id.clone()).map_err(warp_err)?;
+                    return Err(warp_err(Db));
+                }
+            }
+            log(&Put, &id.user, "changed password");
+            Ok::<_, Rejection>(with_status(warp::reply(), Code::NO_CONTENT))
+        });
+
+    // `POST /admin/swap?a=USER&b=USER` exchanges the IPs of two users
+    let swap = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("swap"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(db.clone())
+        .and_then(move |query: HashMap<String, String>, db: DB| -> WarpResult {
+            let (a, b) = match (query.get("a"), query.get("b")) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Err(warp_err(BadRequest)),
+            };
+            let mut db = db.write();
+            let swapped = admin::swap(&mut db, a, b, db::now()).map_err(warp_err)?;
+            Ok(swapped.iter()
+                .map(|(user, ip)| {
+                    log(&Post, user, ip);
+                    format!("{} {}\n", user, ip)
+                })
+                .collect())
+        });
+
+    // `GET /admin/nsupdate?zone=ZONE[&server=SERVER][&ttl=SECONDS]` renders an
+    // nsupdate script for the current records
+    let nsupdate = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("nsupdate"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(db.clone())
+        .and_then(move |query: HashMap<String, String>, db: DB| -> WarpResult {
+            let zone = query.get("zone").ok_or_else(|| warp_err(BadRequest))?;
+            let ttl = match query.get("ttl") {
+                Some(ttl) => ttl.parse().map_err(|_| warp_err(BadRequest))?,
+                None => DEFAULT_TTL,
+            };
+            let db = db.read();
+            Ok(admin::nsupdate(&db, zone, query.get("server").map(String::as_str), ttl))
+        });
+
+    // `GET /admin/zone[?origin=ZONE][&ttl=SECONDS]` renders a BIND zone file
+    // for the current records, by default for `DNS_ZONE` with `DNS_TTL`
+    let (configured_zone, dns_ttl) = (dns::Zone::from_config(config), config.dns_ttl);
+    let zone = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("zone"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(db.clone())
+        .and_then(move |query: HashMap<String, String>, db: DB| -> WarpResult {
+            // Nameservers named for another zone don't belong to this one
+            let mut zone = match (query.get("origin"), &configured_zone) {
+                (Some(origin), _) => dns::Zone { record_ttl, ..dns::Zone::new(origin, None, None, dns_ttl) },
+                (None, Some(zone)) => zone.clone(),
+                (None, None) => return Err(warp_err(BadRequest)),
+            };
+            if let Some(ttl) = query.get("ttl") {
+                zone.ttl = ttl.parse().map_err(|_| warp_err(BadRequest))?;
+            }
+            let db = db.read();
+            Ok(admin::zone_file(&db, &zone, db::now()))
+        });
+
+    // `GET /admin/list` lists every record
+    let list = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("list"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |accept: Option<String>, db: DB| -> ReplyResult {
+            let db = db.read();
+            let records = admin::list(&db);
+            let text = records.iter()
+                .map(|(user, e)| format!("{} {} {} {}\n", user, e.ip, e.uuid, e.last_auth))
+                .collect();
+            let json = records.iter()
+                .map(|(user, e)| {
+                    let mut json = e.to_json();
+                    json["user"] = (*user).into();
+                    json["last_auth"] = e.last_auth.into();
+                    json
+                })
+                .collect();
+            Ok(reply(accept, text, json))
+        });
+
+    // `GET /admin/frequency` reports how often each user's address changes
+    let frequency = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("frequency"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(accept)
+        .and(db.clone())
+        .and_then(move |accept: Option<String>, db: DB| -> ReplyResult {
+            let db = db.read();
+            let frequency = admin::frequency(&db);
+            let text = frequency.iter()
+                .map(|(user, f)| {
+                    let interval = f.average_interval.map_or("-".to_string(), |i| i.to_string());
+                    format!("{} {} {} {} {}\n", user, f.changes, interval, f.v4, f.v6)
+                })
+                .collect();
+            let json = frequency.iter()
+                .map(|(user, f)| serde_json::json!({
+                    "user": user,
+                    "changes": f.changes,
+                    "average_interval": f.average_interval,
+                    "v4": f.v4,
+                    "v6": f.v6,
+                }))
+                .collect();
+            Ok(reply(accept, text, json))
+        });
+
+    // `GET /metrics/entries` exposes each record as Prometheus metrics
+    let entry_metrics = warp::get2()
+        .and(warp::path("metrics"))
+        .and(warp::path("entries"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(db.clone())
+        .and_then(move |db: DB| -> WarpResult {
+            let db = db.read();
+            Ok(metrics::entries(&db, db::now(), metrics_max_entries))
+        });
+
+    // `POST /admin/key` replaces the single-user `KEY` with the
+    // `USER:PASSWORD` body, without a restart.  The old key keeps working for
+    // `KEY_ROTATION_GRACE` seconds.
+    let key_rotation_grace = config.key_rotation_grace;
+    let rotate_key = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("key"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::concat())
+        .and(db.clone())
+        .and_then(move |body: warp::body::FullBody, db: DB| -> WarpResult {
+            let key = std::str::from_utf8(body.bytes())
+                .ok()
+                .and_then(|key| Key::try_from(key.trim()).ok())
+                .ok_or_else(|| warp_err(BadRequest))?;
+            if is_client_key(rotate_admin_key.read().as_ref(), std::slice::from_ref(&key)) {
+                return Err(warp_err(AdminKey));
+            }
+            let mut db = db.write();
+            db::rotate_key(&mut db, key.clone(), db::now(), key_rotation_grace).map_err(warp_err)?;
+            log(&Post, "ADMIN", format!("rotated key to user {}", key.user));
+            Ok("Key rotated\n".to_string())
+        });
+
+    // `GET /admin/trusted-proxies` lists the `TRUSTED_PROXIES`, one per line
+    let get_trusted_proxies = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("trusted-proxies"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(accept)
+        .map({
+            let trusted_proxies = trusted_proxies.clone();
+            move |accept: Option<String>| {
+                let cidrs = trusted_proxies.read().iter().flatten().map(Cidr::to_string).collect::<Vec<_>>();
+                let text = cidrs.iter().map(|cidr| format!("{}\n", cidr)).collect();
+                reply(accept, text, serde_json::json!(cidrs))
+            }
+        });
+
+    // `PUT /admin/trusted-proxies` replaces them with the comma- or
+    // newline-separated networks of the body, all or nothing
+    let put_trusted_proxies = warp::put2()
+        .and(warp::path("admin"))
+        .and(warp::path("trusted-proxies"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+        .and(warp::body::concat())
+        .and_then(move |body: warp::body::FullBody| -> WarpResult {
+            let cidrs = String::from_utf8_lossy(body.bytes())
+                .split(&[',', '\n'][..])
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::parse)
+                .collect::<Result<Vec<Cidr>, _>>()
+                .map_err(|_| warp_err(BadRequest))?;
+            let count = cidrs.len();
+            *trusted_proxies.write() = Some(cidrs);
+            log(&Put, "ADMIN", format!("set {} trusted proxies", count));
+            Ok(format!("Trusting {} proxies\n", count))
+        });
+
+    // `POST /admin/validate-credential` checks a `USER:PASSWORD` body against
+    // the credential rules without storing anything
+    let validate_credential = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("validate-credential"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::body::content_length_limit(4 * id::MAX_LEN as u64))
+        .and(warp::body::concat())
+        .and(accept)
+        .map(|body: warp::body::FullBody, accept: Option<String>| {
+            let credential = String::from_utf8_lossy(body.bytes());
+            match Id::try_from(credential.as_ref()) {
+                Ok(id) => {
+                    let basic = id.basic();
+                    let text = format!("valid\nuser: {}\nauthorization: {}\n", id.user, basic);
+                    reply(accept, text, serde_json::json!({ "valid": true, "user": id.user, "basic": basic }))
+                }
+                Err(e) => {
+                    let text = format!("invalid: {}\n", e);
+                    reply(accept, text, serde_json::json!({ "valid": false, "error": e.to_string() }))
+                }
+            }
+        });
+
+    let export = warp::get2()
+        .and(warp::path("admin"))
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(db.clone())
+        .and_then(move |db: DB| -> ReplyResult {
+            let db = db.read();
+            Ok(warp::reply::json(&snapshot::to_json(&db)).into_response())
+        });
+
+    let import = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("import"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+        .and(warp::body::concat())
+        .and(db.clone())
+        .and_then(move |query: HashMap<String, String>, body: warp::body::FullBody, db: DB| -> WarpResult {
+            let replace = match query.get("mode").map(String::as_str) {
+                None | Some("merge") => false,
+                Some("replace") => true,
+                Some(_) => return Err(warp_err(BadRequest)),
+            };
+            let mut db = db.write();
+            let (count, removed) = admin::import_json(&mut db, body.bytes(), replace).map_err(warp_err)?;
+            log(&Post, "ADMIN", format!("imported {} records, removed {}", count, removed));
+            Ok(format!("Imported {} records, removed {}\n", count, removed))
+        });
+
+    let purge = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("purge"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(db.clone())
+        .and_then(move |db: DB| -> WarpResult {
+            let mut db = db.write();
+            let count = db::purge(&mut db, db::now(), 0);
+            log(&Post, "ADMIN", format!("purged {} records", count));
+            Ok(format!("Purged {} records\n", count))
+        });
+
+    // `POST /admin/invite` creates a single-use invite code for `POST /register`
+    let registration_enabled = config.credentials_file.is_some();
+    let invite = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("invite"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and_then(move || -> WarpResult {
+            if !registration_enabled {
+                return Err(warp::reject::not_found());
+            }
+            let code = db::random_hex(16);
+            invites.write().insert(code.clone());
+            log(&Post, "ADMIN", "created an invite code");
+            Ok(format!("{}\n", code))
+        });
+
+    // `POST /admin/import.csv` imports `user,password,ip` rows
+    let import_csv = warp::post2()
+        .and(warp::path("admin"))
+        .and(warp::path("import.csv"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::body::content_length_limit(MAX_IMPORT_SIZE))
+        .and(warp::body::concat())
+        .and(db)
+        .and_then(move |body: warp::body::FullBody, db: DB| -> WarpResult {
+            let csv = String::from_utf8_lossy(body.bytes());
+            let mut db = db.write();
+            let count = admin::import_csv(&mut db, &csv, db::now()).map_err(warp_err)?;
+            log(&Post, "ADMIN", format!("imported {} records", count));
+            Ok(format!("Imported {} records\n", count))
+        });
+
+    // With `CUSTOM_404` set, a path no route serves gets a branded 404
+    // listing the endpoints.  It comes after every real route and ignores
+    // known paths, so it never shadows them.
+    let custom_404 = config.custom_404.clone();
+    let fallback = warp::path::full()
+        .and(accept)
+        .and_then(move |path: FullPath, accept: Option<String>| -> ReplyResult {
+            let message = match &custom_404 {
+                Some(message) if !is_endpoint(path.as_str()) => message,
+                _ => return Err(warp::reject::not_found()),
+            };
+            let endpoints = ENDPOINTS.iter().map(|(method, path)| format!("{} {}", method, path));
+            let text = format!("{}\n\nAvailable endpoints:\n{}\n", message, endpoints.clone()
+                .map(|e| format!("  {}", e))
+                .collect::<Vec<_>>()
+                .join("\n"));
+            let json = serde_json::json!({ "error": message, "endpoints": endpoints.collect::<Vec<_>>() });
+            Ok(with_status(reply(accept, text, json), Code::NOT_FOUND).into_response())
+        });
+
+    // Rejections carrying an `Err` are answered with it
+    let handle_err = |err: Rejection| match err.find_cause::<Err>() {
+        Some(e) => Ok(error_response(e)),
+        None => Err(err),
+    };
+
+    // Authenticated client routes tell the caller its observed IP
+    let routes = redirect_to_https
+        .or(options)
+        .or(observed_ip.clone().and(touch.or(undelete).or(mint_token).or(revoke_token)).map(with_your_ip))
+        .or(server_ip)
+        .or(swap)
+        .or(nsupdate)
+        .or(zone)
+        .or(register)
+        .or(acme_register).or(acme_update)
+        .or(pdns)
+        .or(observed_ip.clone().and(change_password).map(with_your_ip))
+        .or(list).or(frequency).or(export).or(import).or(purge).or(invite)
+        .or(rotate_key)
+        .or(get_trusted_proxies).or(put_trusted_proxies)
+        .or(validate_credential)
+        .or(import_csv)
+        .or(entry_metrics)
+        .or(observed_ip.and(
+            mint_update_url.or(revoke_update_url).or(update_url)
+                .or(nic_update)
+                .or(duckdns)
+                .or(update_record).or(get_record).or(delete_record).or(list_records)
+                .or(history)
+                .or(get).or(post).or(delete)
+        ).map(with_your_ip))
+        .or(show)
+        .or(fallback);
+
+    drain.filter()
+        .and(check_host)
+        .and(check_xff)
+        .and(check_rate)
+        .and(attempt)
+        .and(routes.recover(handle_err))
+        .map(move |_guard, attempt: Attempt, reply| {
+            let res = Reply::into_response(reply);
+            let now = db::now();
+            if res.status() == Code::UNAUTHORIZED {
+                log_auth_failure(&attempt, now);
+                if let Some(lockout) = &lockout {
+                    for (key, seconds) in lockout.failure(&attempt.lockout_keys(), now) {
+                        println!("[LOCKOUT] {} locked out for {} seconds", key, seconds);
+                    }
+                }
+            }
+            res
+        })
+        .recover(handle_err)
+        .with(warp::reply::with::headers(config.response_headers.clone()))
+}
+
+/// Log `e` and answer with it; a 401 challenges the client for Basic
+/// credentials
+fn error_response(e: &Err) -> Response {
+    log_error(e);
+    let mut res = with_status(e.to_string(), e.status()).into_response();
+    if e.status() == Code::UNAUTHORIZED {
+        res.headers_mut().insert("www-authenticate", warp::http::HeaderValue::from_static("Basic realm=\"d5\""));
+    }
+    res
+}
+
+/// Longest POST body accepted, enough for `ALLOW_IP_BODY`'s IP and name and
+/// for acme-dns requests
+const MAX_IP_BODY: u64 = 1024;
+
+/// The body, if `read`, refusing more than `MAX_IP_BODY` bytes whether or not
+/// its `length` is declared.  A body that isn't read is never buffered.
+fn read_body(read: bool, length: Option<u64>, body: warp::body::BodyStream) -> Box<dyn Future<Item = Vec<u8>, Error = Rejection> + Send> {
+    if !read {
+        return Box::new(future::ok(Vec::new()));
+    }
+    if length.is_some_and(|length| length > MAX_IP_BODY) {
+        return Box::new(future::err(warp_err(PayloadTooLarge)));
+    }
+    Box::new(body.map_err(|_| warp_err(BadRequest)).fold(Vec::new(), |mut body, chunk| {
+        body.extend_from_slice(chunk.bytes());
+        match body.len() as u64 > MAX_IP_BODY {
+            true => Err(warp_err(PayloadTooLarge)),
+            false => Ok(body),
+        }
+    }))
+}
+
+/// Who sent a request, and to which route
+#[derive(Debug)]
+struct Attempt {
+    /// The IP reported by a (trusted) reverse proxy, or else the peer's
+    ip: Option<String>,
+    /// The username of Basic credentials
+    user: Option<String>,
+    has_credentials: bool,
+    /// Whether `ip` is in the `RATELIMIT_EXEMPT_NETWORKS`
+    exempt: bool,
+    /// `METHOD /path`
+    route: String,
+}
+
+impl Attempt {
+    /// The `LOCKOUT_THRESHOLD` keys: none without credentials or from an
+    /// exempt network, or else the client IP and username
+    fn lockout_keys(&self) -> Vec<String> {
+        if !self.has_credentials || self.exempt {
+            return Vec::new();
+        }
+        let ip = self.ip.iter().map(|ip| format!("ip {}", ip));
+        ip.chain(self.user.iter().map(|user| format!("user {}", user))).collect()
+    }
+}
+
+/// Tell an authenticated caller the IP d5 observed for them, in `X-Your-IP`
+fn with_your_ip(ip: Option<String>, reply: impl Reply) -> Response {
+    let mut res = reply.into_response();
+    if let Some(value) = ip.and_then(|ip| warp::http::HeaderValue::from_str(&ip).ok()) {
+        res.headers_mut().insert("x-your-ip", value);
+    }
+    res
+}
+
+/// Whether some route serves `path`
+fn is_endpoint(path: &str) -> bool {
+    !allowed_methods(path).is_empty()
+}
+
+/// The methods served at `path`, including `OPTIONS`, or every method d5
+/// serves for `*`.  Empty for unknown paths.
+fn allowed_methods(path: &str) -> Vec<&'static str> {
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+    let mut methods = Vec::new();
+    for (method, p) in ENDPOINTS {
+        if (path == "*" || matches_endpoint(p, path)) && !methods.contains(method) {
+            methods.push(*method);
+        }
+    }
+    if !methods.is_empty() {
+        methods.push("OPTIONS");
+    }
+    methods
+}
+
+/// Whether `path` is served by the `ENDPOINTS` path `endpoint`, whose
+/// uppercase segments (`NAME`, `ID`, ...) each stand for any one segment, and
+/// whose `ARGS` stands for the rest of the path
+fn matches_endpoint(endpoint: &str, path: &str) -> bool {
+    let is_placeholder = |template: &str| !template.is_empty() && template.bytes().all(|b| b.is_ascii_uppercase());
+    let mut segments = path.split('/');
+    for template in endpoint.split('/') {
+        if template == "ARGS" {
+            return segments.any(|segment| !segment.is_empty());
+        }
+        match segments.next() {
+            Some(segment) if is_placeholder(template) && !segment.is_empty() => {}
+            Some(segment) if segment == template => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Whether `host` (with or without its port) is one of the `allowed` hosts
+fn host_allowed(allowed: &[String], host: &str) -> bool {
+    let host = host.trim().to_lowercase();
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => &host,
+    };
+    allowed.iter().any(|a| a.eq_ignore_ascii_case(&host) || a.eq_ignore_ascii_case(hostname))
+}
+
+/// The addresses a request was forwarded for, the client's first, from
+/// `X-Forwarded-For` or else the RFC 7239 `Forwarded` header
+fn forwarded_chain(xff: Option<String>, forwarded: Option<String>) -> Option<Vec<String>> {
+    let chain = match (xff, forwarded) {
+        (Some(xff), _) => xff.split(',').map(|ip| ip.trim().to_string()).collect(),
+        (None, Some(forwarded)) => forwarded_for(&forwarded),
+        (None, None) => return None,
+    };
+    Some(chain).filter(|chain: &Vec<_>| !chain.is_empty())
+}
+
+/// The `for=` addresses of a `Forwarded` header such as
+/// `for=1.2.3.4;proto=https, for="[2001:db8::1]:4711"`, unquoted
+fn forwarded_for(header: &str) -> Vec<String> {
+    header.split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                Some(value.to_string()).filter(|_| key.trim().eq_ignore_ascii_case("for"))
+            })
+        })
+        .collect()
+}
+
+/// The client's address as reported by the `policy` hop of its forwarded
+/// `chain` or else `remote_addr`.  With `TRUSTED_PROXIES`, these are believed
+/// only from a trusted peer (or over a Unix socket); other peers are the
+/// client.
+fn reported_ip(
+    trusted: Option<&[Cidr]>,
+    policy: XffPolicy,
+    chain: Option<Vec<String>>,
+    remote_addr: Option<String>,
+    peer: Option<SocketAddr>,
+) -> Option<String> {
+    let is_trusted = |ip: Option<IpAddr>| ip.is_some_and(|ip| trusted.is_some_and(|t| t.iter().any(|c| c.contains(ip))));
+    if trusted.is_some() && peer.is_some() && !is_trusted(peer.map(|p| p.ip())) {
+        return peer.map(|p| p.ip().to_string());
+    }
+    let chain = match chain {
+        Some(chain) => chain,
+        None if trusted.is_some() => return remote_addr.or_else(|| peer.map(|p| p.ip().to_string())),
+        None => return remote_addr,
+    };
+    let client = match policy {
+        XffPolicy::First => chain.first(),
+        XffPolicy::Last => chain.last(),
+        // When every hop is trusted, the client is the first of them
+        XffPolicy::RightmostUntrusted => chain.iter().rev().find(|ip| !is_trusted(parse_ip(ip))).or(chain.first()),
+    };
+    client.cloned()
+}
+
+/// Parse an address as a proxy may report it, with or without a port
+/// (`1.2.3.4:5678`, `[::1]:80`, `[::1]`), into its canonical form; an
+/// IPv4-mapped IPv6 address is its IPv4 address
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let unbracketed = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    let ip = match unbracketed.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => s.parse::<SocketAddr>().ok()?.ip(),
+    };
+    Some(match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    })
+}
+
+/// Store `ip` in `id`'s named record `name`, logging it if it changed;
+/// returns the record's previous address of `ip`'s family, and the record
+fn store_host<'a>(
+    db: &'a mut Records,
+    id: &Id,
+    name: &str,
+    ip: IpAddr,
+    now: u64,
+    max_records: Option<usize>,
+) -> Result<(Option<IpAddr>, &'a db::Host), Err> {
+    let previous = db::set_host(db, id.clone(), name, ip, now, max_records)?;
+    if previous != Some(ip) {
+        log(&Post, format!("{}/{}", id.user, name), ip);
+    }
+    Ok((previous, &db[id].hosts[name]))
+}
+
+/// The credentials a bearer `token` stands for: those of its
+/// `CREDENTIALS_FILE` user, or of the record it was minted for
+fn token_id(users: &AllowedUsers, db: &Records, token: &str) -> Option<Id> {
+    users.read().as_ref()
+        .and_then(|users| users.bearer(token))
+        .or_else(|| db.token_owner(token).cloned())
+}
+
+/// A DynDNS2 return code such as `good 1.2.3.4`, challenging a `badauth`
+/// client for credentials
+fn dyndns_reply(status: Code, body: String) -> Response {
+    let mut res = with_status(body, status).into_response();
+    if status == Code::UNAUTHORIZED {
+        res.headers_mut().insert("www-authenticate", warp::http::HeaderValue::from_static("Basic realm=\"d5\""));
+    }
+    res
+}
+
+/// An acme-dns error response, naming the error as acme-dns does
+fn acme_reply(status: Code, error: &str) -> Response {
+    with_status(warp::reply::json(&serde_json::json!({ "error": error })), status).into_response()
+}
+
+/// Parse a POST body of `IP`, `IP NAME` or `{"ip": IP, "name": NAME}` (with
+/// `name` optional); an empty body names no IP
+fn parse_ip_body(body: &[u8]) -> Result<Option<(IpAddr, Option<String>)>, Err> {
+    let body = std::str::from_utf8(body).map_err(|_| BadRequest)?.trim();
+    if body.is_empty() {
+        return Ok(None);
+    }
+    let (ip, name) = if body.starts_with('{') {
+        let json = serde_json::from_str::<serde_json::Value>(body).map_err(|_| BadRequest)?;
+        let name = match &json["name"] {
+            serde_json::Value::Null => None,
+            name => Some(name.as_str().ok_or(BadRequest)?.to_string()),
+        };
+        (json["ip"].as_str().ok_or(BadRequest)?.to_string(), name)
+    } else {
+        let mut words = body.split_whitespace();
+        let ip = words.next().unwrap_or_default().to_string();
+        let name = words.next().map(String::from);
+        if words.next().is_some() {
+            return Err(BadRequest);
+        }
+        (ip, name)
+    };
+    Ok(Some((parse_ip(&ip).ok_or(BadRequest)?, name)))
+}
+
+/// The IP a POST stores: the `myip` query parameter, if `id`'s user is
+/// allowed to override their address, or else the `caller`'s
+fn stored_ip(query: &HashMap<String, String>, caller: Option<IpAddr>, id: &Id, permit: &Permit) -> Result<IpAddr, Rejection> {
+    match query.get("myip") {
+        Some(_) if !permit.allows(&id.user) => Err(warp_err(IpOverride)),
+        Some(myip) => parse_ip(myip).ok_or_else(|| warp_err(BadRequest)),
+        None => caller.ok_or_else(|| warp_err(BadRequest)),
+    }
+}
+
+/// The kind of special-purpose range `ip` is in, if it isn't a public address
+fn reserved_range(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            match (a, b, c) {
+                (0, ..) => Some("\"this network\""),
+                (10, ..) | (172, 16..=31, _) | (192, 168, _) => Some("private"),
+                (100, 64..=127, _) => Some("shared (carrier-grade NAT)"),
+                (127, ..) => Some("loopback"),
+                (169, 254, _) => Some("link-local"),
+                (192, 0, 0) => Some("IETF protocol assignment"),
+                (192, 0, 2) | (198, 51, 100) | (203, 0, 113) => Some("documentation"),
+                (198, 18..=19, _) => Some("benchmarking"),
+                (224..=239, ..) => Some("multicast"),
+                (240..=255, ..) => Some("reserved"),
+                _ => None,
+            }
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            match s[0] {
+                _ if ip.is_unspecified() => Some("unspecified"),
+                _ if ip.is_loopback() => Some("loopback"),
+                0x0100 if s[1..4] == [0, 0, 0] => Some("discard-only"),
+                0x2001 if s[1] == 0x0db8 => Some("documentation"),
+                0xfc00..=0xfdff => Some("unique local"),
+                0xfe80..=0xfebf => Some("link-local"),
+                0xff00..=0xffff => Some("multicast"),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Fill the caller's IP into a `http(s)://` URL template containing `{ip}`
+fn redirect_url(template: &str, ip: IpAddr) -> Result<String, Err> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    if !template.contains("{ip}") {
+        return Err(BadRequest);
+    }
+    let url = Url::parse(&template.replace("{ip}", &ip)).map_err(|_| BadRequest)?;
+    match url.scheme() {
+        "http" | "https" if url.has_host() => Ok(url.into_string()),
+        _ => Err(BadRequest),
+    }
+}
+
+/// Add a `Last-Modified` header for Unix time `secs` to `res`
+fn with_last_modified(mut res: Response, secs: u64) -> Response {
+    if let Ok(value) = warp::http::HeaderValue::from_str(&db::http_date(secs)) {
+        res.headers_mut().insert("last-modified", value);
+    }
+    res
+}
+
+/// The reply to a POST that stored `ip`, telling the client in an `X-D5-Update`
+/// header (and `update` in JSON) whether it was `updated` or `unchanged`
+fn update_reply(accept: Option<String>, ip: IpAddr, mut json: serde_json::Value, changed: bool) -> Response {
+    let update = if changed { "updated" } else { "unchanged" };
+    json["update"] = update.into();
+    let mut res = reply(accept, ip.to_string(), json);
+    res.headers_mut().insert("x-d5-update", warp::http::HeaderValue::from_static(update));
+    res
+}
+
+/// Whether `id` names no user at all, in multi-user mode without a
+/// `CREDENTIALS_FILE`: there is no password to have got wrong, only a record
+/// not yet stored
+fn unknown_user(db: &Records, users: &AllowedUsers, id: &Id) -> bool {
+    db.keys().is_empty() && users.read().is_none() && db.user_ids(&id.user).is_empty()
+}
+
+/// Whether a `PDNS_BACKEND` request from `peer` may be answered: the peer is
+/// in `allow` (or local, on a Unix socket), and the request wasn't `proxied`,
+/// so a reverse proxy on an allowed address can't pass the Internet's on
+fn pdns_allowed(allow: &[Cidr], peer: Option<SocketAddr>, proxied: bool) -> bool {
+    !proxied && peer.is_none_or(|peer| allow.iter().any(|net| net.contains(peer.ip())))
+}
+
+/// Whether the admin key is among `keys`; it must not double as one handed out
+/// to clients
+fn is_client_key(admin: Option<&Key>, keys: &[Key]) -> bool {
+    admin.is_some_and(|admin| keys.contains(admin))
+}
+
+/// Whether Basic credentials `id` are genuine: the admin key, a single-user
+/// key or `CREDENTIALS_FILE` user, or else those of an existing record
+fn genuine(db: &Records, users: &AllowedUsers, admin_key: &AdminKey, id: &Id) -> bool {
+    let is_admin = admin_key.read().as_ref().is_some_and(|key| key.ct_eq(id));
+    // Check every key, so the time taken doesn't reveal which matched
+    let is_key = db.keys().iter().fold(false, |found, key| key.ct_eq(id) | found);
+    let is_user = users.read().as_ref().map(|users| users.allows(id));
+    is_admin || match (db.keys().is_empty(), is_user) {
+        (false, is_user) => is_key && is_user.unwrap_or(true),
+        (true, Some(is_user)) => is_user,
+        (true, None) => db.get_live(id).is_some(),
+    }
+}
+
+/// Refuse to store `ip` for `id` unless `id` is a single-user key (in
+/// single-user mode) and allowed by the `CREDENTIALS_FILE` (if any), and `ip`
+/// is of an allowed family and, with `REJECT_PRIVATE`, publicly routable
+fn may_store(
+    db: &Records,
+    id: &Id,
+    users: &AllowedUsers,
+    families: Families,
+    reject_private: bool,
+    ip: IpAddr,
+) -> Result<(), Rejection> {
+    // Check every key, so the time taken doesn't reveal which matched
+    let is_key = db.keys().iter().fold(false, |found, key| key.ct_eq(id) | found);
+    if !db.keys().is_empty() && !is_key {
+        return Err(warp_err(Unauthorized));
+    }
+    if users.read().as_ref().is_some_and(|users| !users.allows(id)) {
+        return Err(warp_err(Unauthorized));
+    }
+    if !families.allows(ip) {
+        return Err(warp_err(BadRequest));
+    }
+    match reserved_range(ip) {
+        Some(range) if reject_private => Err(warp_err(ReservedIp(format!(
+            "Refusing to store {}, a {} address; is d5 behind a proxy that doesn't set `X-Forwarded-For`?",
+            ip, range,
+        )))),
+        _ => Ok(()),
+    }
+}
+
+/// The addresses picked by `?family=4|6|all`, one per line, or else just
+/// `latest`.  `NotFound` if none of the picked families has an address.
+fn picked_addresses<F>(query: &HashMap<String, String>, latest: IpAddr, address: F) -> Result<String, Err>
+where
+    F: Fn(Family) -> Option<IpAddr>,
+{
+    let ips = match query.get("family").map(String::as_str) {
+        None => vec![latest],
+        Some("4") => address(Family::V4).into_iter().collect(),
+        Some("6") => address(Family::V6).into_iter().collect(),
+        Some("all") => address(Family::V4).into_iter().chain(address(Family::V6)).collect(),
+        Some(_) => return Err(BadRequest),
+    };
+    if ips.is_empty() {
+        return Err(NotFound);
+    }
+    Ok(ips.iter().map(IpAddr::to_string).collect::<Vec<_>>().join("\n"))
+}
+
+/// Reply with `json` if the client accepts JSON, or with `text` otherwise
+fn reply(accept: Option<String>, text: String, json: serde_json::Value) -> Response {
+    if accept.is_some_and(|a| a.contains("application/json")) {
+        warp::reply::json(&json).into_response()
+    } else {
+        text.into_response()
+    }
+}
+
+fn log<X, Y, Z>(rest: X, id: Y, ip: Z)
+where
+    X: fmt::Display,
+    Y: fmt::Display,
+    Z: fmt::Display,
+{
+    println!("[{}] USER:{} IP:{}", rest, id, ip);
+}
+
+/// Log a failed authentication in a stable format for tools like fail2ban:
+/// `[AUTH_FAILURE] TIME IP:ADDRESS USER:NAME ROUTE:METHOD /path`, with `-`
+/// for an unknown IP or username
+fn log_auth_failure(attempt: &Attempt, now: u64) {
+    println!(
+        "[AUTH_FAILURE] {} IP:{} USER:{} ROUTE:{}",
+        db::rfc3339(now),
+        attempt.ip.as_deref().unwrap_or("-"),
+        attempt.user.as_deref().unwrap_or("-"),
+        attempt.route,
+    );
+}
+
+fn log_error(err: &Err) {
+    println!("[ERROR] {} {}", err.status().as_u16(), err.to_string().trim_end());
+}
+
+/// Log a confirmed IP change, run the `ON_CHANGE_CMD` hook and push the
+/// change to the `NSUPDATE_SERVER`, if any
+fn notify(hook: Option<&Hook>, upstream: Option<&Upstream>, geoip: &GeoIp, user: &str, old_ip: Option<IpAddr>, new_ip: IpAddr) {
+    log(&Post, user, geoip.annotate(new_ip));
+    if let Some(hook) = hook {
+        hook.on_change(user, old_ip.map(|ip| ip.to_string()).as_deref(), &new_ip.to_string());
+    }
+    if let Some(upstream) = upstream {
+        upstream.push(user, new_ip);
+    }
+}
+
+/// Whether to log a successful request.  Mutations are always logged; reads
+/// are logged with probability `rate`.
+fn sampled(rest: &Rest, rate: f64) -> bool {
+    match rest {
+        Get => chance(rate),
+        Post | Put | Delete => true,
+    }
+}
+
+/// Whether to log a POST that left the address unchanged.  It isn't a
+/// mutation, so it is logged with probability `rate`, decided apart from
+/// any read's.
+fn sampled_unchanged(rate: f64) -> bool {
+    chance(rate)
+}
+
+fn chance(rate: f64) -> bool {
+    rate >= 1.0 || (rate > 0.0 && rand::random::<f64>() < rate)
+}
+
+/// The HTTP REST methods
+#[derive(Debug)]
+enum Rest {
+    Post,
+    Get,
+    Put,
+    // Patch,
+    Delete,
+}
+
+impl fmt::Display for Rest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Err {
+    AdminKey,
+    BadRequest,
+    Conflict,
+    Db,
+    Import(String),
+    InvalidBase64,
+    IpOverride,
+    LockedOut,
+    MisdirectedRequest,
+    NotFound,
+    NotUserPassword,
+    PayloadTooLarge,
+    RecordLimit,
+    ReservedIp(String),
+    TooManyRequests,
+    Unauthorized,
+    Unavailable,
+    UnknownTenant,
+    UserExists,
+}
+
+impl fmt::Display for Err {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}",
+            match self {
+                Self::AdminKey => "The admin key must differ from every client key.",
+                Self::BadRequest => "Bad request.",
+                Self::Conflict => "More than one record for that username.",
+                Self::Db => "Internal server error.",
+                Self::Import(e) => e.as_str(),
+                Self::InvalidBase64 => "Invalid Basic authorization: not valid base64.",
+                Self::IpOverride => "Storing an address other than your own isn't allowed for that username.",
+                Self::LockedOut => "Too many failed attempts; try again later.",
+                Self::MisdirectedRequest => "Misdirected request.",
+                Self::NotFound => "No IP found for that username–password pair.",
+                Self::NotUserPassword => "Invalid Basic authorization: expected a `user:password` pair separated by a colon.",
+                Self::PayloadTooLarge => "Request body too large.",
+                Self::RecordLimit => "Record limit reached for that username.",
+                Self::ReservedIp(e) => e.as_str(),
+                Self::TooManyRequests => "Too many requests; slow down.",
+                Self::Unauthorized => "Unauthorized request.",
+                Self::Unavailable => "Service unavailable; shutting down.",
+                Self::UnknownTenant => "Unknown tenant.",
+                Self::UserExists => "That username is taken.",
+            }
+        )
+    }
+}
+
+impl Err {
+    fn status(&self) -> Code {
+        match self {
+            Self::BadRequest | Self::Import(_) | Self::UnknownTenant => Code::BAD_REQUEST,
+            Self::InvalidBase64 | Self::NotUserPassword => Code::BAD_REQUEST,
+            Self::AdminKey | Self::Conflict | Self::UserExists => Code::CONFLICT,
+            Self::Db => Code::INTERNAL_SERVER_ERROR,
+            Self::MisdirectedRequest => Code::MISDIRECTED_REQUEST,
+            Self::NotFound => Code::NOT_FOUND,
+            Self::PayloadTooLarge => Code::PAYLOAD_TOO_LARGE,
+            Self::IpOverride | Self::RecordLimit => Code::FORBIDDEN,
+            Self::ReservedIp(_) => Code::UNPROCESSABLE_ENTITY,
+            Self::LockedOut | Self::TooManyRequests => Code::TOO_MANY_REQUESTS,
+            Self::Unauthorized => Code::UNAUTHORIZED,
+            Self::Unavailable => Code::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl std::error::Error for Err {}
+
+#[cfg(test)]
+fn test_db() -> DB {
+    Arc::new(RwLock::new(Records::new()))
+}
+
+#[cfg(test)]
+fn with_admin(admin: &Id) -> Services {
+    Services { admin_key: Arc::new(RwLock::new(Some(admin.clone()))), ..Services::default() }
+}
+
+#[test]
+fn allowed_hosts() {
+    let config = Config {
+        allowed_hosts: Some(vec!["d5.example.com".into()]),
+        host_check_exempt: vec!["/".into()],
+        ..Config::default()
+    };
+    let routes = routes(&config, test_db(), with_admin(&Id::new("admin", "admin")));
+    let post = |host: &str, path: &str| warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("host", host)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", Id::new("admin", "admin").basic())
+        .reply(&routes);
+
+    assert_eq!(post("d5.example.com", "/admin/swap").status(), Code::BAD_REQUEST);
+    assert_eq!(post("d5.example.com:443", "/admin/swap").status(), Code::BAD_REQUEST);
+    assert_eq!(post("evil.example.com", "/admin/swap").status(), Code::MISDIRECTED_REQUEST);
+    assert_eq!(post("10.0.0.1", "/admin/swap").status(), Code::MISDIRECTED_REQUEST);
+
+    // Exempt paths are served for any host
+    assert_eq!(post("10.0.0.1", "/").status(), Code::OK);
+}
+
+#[test]
+fn host_allowed_ports() {
+    let allowed = vec!["d5.example.com".to_string(), "[::1]".to_string()];
+    assert!(host_allowed(&allowed, "D5.example.com"));
+    assert!(host_allowed(&allowed, "d5.example.com:3030"));
+    assert!(host_allowed(&allowed, "[::1]:3030"));
+    assert!(host_allowed(&allowed, "[::1]"));
+    assert!(!host_allowed(&allowed, "example.com"));
+}
+
+#[test]
+fn uuid_in_json() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let request = |method: &str, id: &Id, ip: &str| {
+        let res = warp::test::request()
+            .method(method)
+            .header("accept", "application/json")
+            .header("x-forwarded-for", ip)
+            .header("authorization", id.basic())
+            .reply(&routes);
+        assert_eq!(res.status(), Code::OK);
+        serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()
+    };
+    let derp = Id::new("derp", "flerp");
+    let herp = Id::new("herp", "blerp");
+
+    let created = request("POST", &derp, "1.1.1.1");
+    let updated = request("POST", &derp, "2.2.2.2");
+    let got = request("GET", &derp, "3.3.3.3");
+    assert_eq!(got["ip"], "2.2.2.2");
+    assert_eq!(created["uuid"], updated["uuid"]);
+    assert_eq!(created["uuid"], got["uuid"]);
+
+    let other = request("POST", &herp, "1.1.1.1");
+    assert_ne!(created["uuid"], other["uuid"]);
+}
+
+#[test]
+fn touch_route() {
+    let db = test_db();
+    let routes = routes(&Config::default(), db.clone(), Services::default());
+    let derp = Id::new("derp", "flerp");
+    let touch = || warp::test::request()
+        .method("POST")
+        .path("/touch")
+        .header("authorization", derp.basic())
+        .reply(&routes);
+
+    assert_eq!(touch().status(), Code::NOT_FOUND);
+
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let res = touch();
+    assert_eq!(res.status(), Code::NO_CONTENT);
+    assert!(res.body().is_empty());
+
+    let entry = db.read()[&derp].clone();
+    assert_eq!(entry.ip.to_string(), "1.1.1.1");
+    assert!(entry.updated_at > 0);
+}
+
+#[test]
+fn response_headers() {
+    let mut config = Config::default();
+    config.response_headers.insert("server", "d5".parse().unwrap());
+    let routes = routes(&config, test_db(), with_admin(&Id::new("admin", "admin")));
+    let get = |path: &str| warp::test::request()
+        .path(path)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+
+    for (res, status) in [(get("/"), Code::OK), (get("/admin/list"), Code::UNAUTHORIZED)] {
+        assert_eq!(res.status(), status);
+        assert_eq!(res.headers()["server"], "d5");
+        assert_eq!(res.headers()["x-content-type-options"], "nosniff");
+    }
+}
+
+#[test]
+fn show_redirect() {
+    let enabled = routes(&Config { show_redirect: true, ..Config::default() }, test_db(), Services::default());
+    let disabled = routes(&Config::default(), test_db(), Services::default());
+    let show = |path: &str, ip: &str| warp::test::request()
+        .path(path)
+        .header("x-forwarded-for", ip)
+        .reply(&enabled);
+
+    let res = show("/?redirect=http://{ip}:8080/home", "1.2.3.4");
+    assert_eq!(res.status(), Code::FOUND);
+    assert_eq!(res.headers()["location"], "http://1.2.3.4:8080/home");
+
+    let res = show("/?redirect=https://{ip}/", "2001:db8::1");
+    assert_eq!(res.headers()["location"], "https://[2001:db8::1]/");
+
+    assert_eq!(show("/?redirect=http://example.com/", "1.2.3.4").status(), Code::BAD_REQUEST);
+    assert_eq!(show("/?redirect=javascript:{ip}", "1.2.3.4").status(), Code::BAD_REQUEST);
+    assert_eq!(show("/?redirect=http://{ip}/", "not an ip").status(), Code::BAD_REQUEST);
+
+    // Without a redirect, or with redirects disabled, `show` echoes the IP
+    assert_eq!(show("/", "1.2.3.4").body().as_ref(), b"1.2.3.4");
+    let res = warp::test::request()
+        .path("/?redirect=http://{ip}/")
+        .header("x-forwarded-for", "1.2.3.4")
+        .reply(&disabled);
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.body().as_ref(), b"1.2.3.4");
+}
+
+#[test]
+fn show_no_content() {
+    let show = |config: &Config| warp::test::request()
+        .header("x-forwarded-for", "1.2.3.4")
+        .reply(&routes(config, test_db(), Services::default()));
+
+    let res = show(&Config { show_no_content: true, ..Config::default() });
+    assert_eq!(res.status(), Code::NO_CONTENT);
+    assert!(res.body().is_empty());
+
+    let res = show(&Config::default());
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.body().as_ref(), b"1.2.3.4");
+}
+
+#[test]
+fn soft_delete_routes() {
+    let db = test_db();
+    let routes = routes(&Config { soft_delete: Some(60), ..Config::default() }, db.clone(), Services::default());
+    let derp = Id::new("derp", "flerp");
+    let request = |method: &str, path: &str| warp::test::request()
+        .method(method)
+        .path(path)
+        .header("authorization", derp.basic())
+        .header("x-forwarded-for", "1.1.1.1")
+        .reply(&routes);
+
+    request("POST", "/");
+    assert_eq!(request("DELETE", "/").status(), Code::OK);
+    assert!(db.read().get_live(&derp).is_none());
+    assert_eq!(request("DELETE", "/").status(), Code::NOT_FOUND);
+
+    let res = request("POST", "/undelete");
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.body().as_ref(), b"1.1.1.1");
+    assert!(db.read().get_live(&derp).is_some());
+    assert_eq!(request("POST", "/undelete").status(), Code::NOT_FOUND);
+}
+
+#[test]
+fn log_sampling() {
+    assert!((0..1000).all(|_| !sampled(&Get, 0.0)));
+    assert!((0..1000).all(|_| sampled(&Get, 1.0)));
+    assert!((0..1000).all(|_| sampled(&Post, 0.0) && sampled(&Delete, 0.0)));
+
+    let logged = (0..10_000).filter(|_| sampled(&Get, 0.5)).count();
+    assert!(logged > 4000 && logged < 6000);
+
+    assert!((0..1000).all(|_| !sampled_unchanged(0.0) && sampled_unchanged(1.0)));
+    let logged = (0..10_000).filter(|_| sampled_unchanged(0.5)).count();
+    assert!(logged > 4000 && logged < 6000);
+}
+
+#[test]
+fn server_ip_route() {
+    let server_ip = ServerIp::default();
+    let config = Config { server_ip_url: Some("http://example.com".into()), ..Config::default() };
+    let enabled = routes(&config, test_db(), Services { server_ip: server_ip.clone(), ..Services::default() });
+    let get = || warp::test::request().path("/server-ip").reply(&enabled);
+
+    assert_eq!(get().body().as_ref(), b"null");
+    server_ip.refresh(|| "203.0.113.7".parse().ok());
+    assert_eq!(get().body().as_ref(), b"203.0.113.7");
+
+    let res = warp::test::request()
+        .path("/server-ip")
+        .reply(&routes(&Config::default(), test_db(), Services { server_ip, ..Services::default() }));
+    assert!(res.status().is_client_error());
+}
+
+#[test]
+fn allowed_families_post() {
+    use config::Families;
+
+    let post = |v4: bool, v6: bool, ip: &str| {
+        let config = Config { allowed_families: Families { v4, v6 }, ..Config::default() };
+        warp::test::request()
+            .method("POST")
+            .header("x-forwarded-for", ip)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes(&config, test_db(), Services::default()))
+            .status()
+    };
+
+    assert_eq!(post(true, false, "::1"), Code::BAD_REQUEST);
+    assert_eq!(post(true, false, "1.1.1.1"), Code::OK);
+    assert_eq!(post(false, true, "1.1.1.1"), Code::BAD_REQUEST);
+    assert_eq!(post(false, true, "::1"), Code::OK);
+    assert_eq!(post(true, true, "1.1.1.1"), Code::OK);
+    assert_eq!(post(true, true, "::1"), Code::OK);
+}
+
+#[test]
+fn parse_ips() {
+    let parsed = |s: &str| parse_ip(s).map(|ip| ip.to_string());
+    assert_eq!(parsed(" 1.2.3.4 "), Some("1.2.3.4".into()));
+    assert_eq!(parsed("1.2.3.4:5678"), Some("1.2.3.4".into()));
+    assert_eq!(parsed("[2001:DB8:0:0::1]:80"), Some("2001:db8::1".into()));
+    assert_eq!(parsed("2001:0db8::0001"), Some("2001:db8::1".into()));
+    assert_eq!(parsed("::ffff:1.2.3.4"), Some("1.2.3.4".into()));
+    assert_eq!(parsed("[::1]"), Some("::1".into()));
+    assert_eq!(parsed("[1.2.3.4]:80"), None);
+    assert_eq!(parsed("1.2.3"), None);
+    assert_eq!(parsed("unknown"), None);
+    assert_eq!(parsed(""), None);
+
+    let res = warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", "[2001:db8::0:1]:4444, 10.0.0.1")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes(&Config::default(), test_db(), Services::default()));
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.body(), "2001:db8::1");
+}
+
+#[test]
+fn trusted_proxies() {
+    let trusted = ["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+    let peer = |ip: &str| Some(SocketAddr::new(ip.parse().unwrap(), 4444));
+    let xff = || Some("6.6.6.6, 1.1.1.1, 10.0.0.2".to_string());
+    let reported = |trusted: Option<&[Cidr]>, xff: Option<String>, remote_addr: Option<&str>, peer| {
+        let policy = if trusted.is_some() { XffPolicy::RightmostUntrusted } else { XffPolicy::First };
+        reported_ip(trusted, policy, forwarded_chain(xff, None), remote_addr.map(String::from), peer)
+    };
+
+    // Without `TRUSTED_PROXIES`, every peer's headers are believed
+    assert_eq!(reported(None, xff(), None, peer("8.8.8.8")), Some("6.6.6.6".into()));
+    assert_eq!(reported(None, None, Some("2.2.2.2"), peer("8.8.8.8")), Some("2.2.2.2".into()));
+    assert_eq!(reported(None, None, None, peer("8.8.8.8")), None);
+
+    // A trusted peer's chain is believed up to the first untrusted hop
+    assert_eq!(reported(Some(&trusted), xff(), None, peer("10.1.2.3")), Some("1.1.1.1".into()));
+    assert_eq!(reported(Some(&trusted), Some("10.0.0.2".into()), None, peer("::1")), Some("10.0.0.2".into()));
+    assert_eq!(reported(Some(&trusted), None, Some("2.2.2.2"), peer("10.1.2.3")), Some("2.2.2.2".into()));
+    assert_eq!(reported(Some(&trusted), None, None, None), None);
+    // ...and an untrusted peer is the client, whatever it claims
+    assert_eq!(reported(Some(&trusted), xff(), Some("2.2.2.2"), peer("8.8.8.8")), Some("8.8.8.8".into()));
+
+    // `XFF_POLICY` picks the hop
+    let chain = || forwarded_chain(xff(), None);
+    assert_eq!(reported_ip(None, XffPolicy::Last, chain(), None, None), Some("10.0.0.2".into()));
+    assert_eq!(reported_ip(None, XffPolicy::RightmostUntrusted, chain(), None, None), Some("10.0.0.2".into()));
+    assert_eq!(reported_ip(Some(&trusted), XffPolicy::First, chain(), None, peer("10.1.2.3")), Some("6.6.6.6".into()));
+    assert_eq!(reported_ip(Some(&trusted), XffPolicy::Last, chain(), None, peer("10.1.2.3")), Some("10.0.0.2".into()));
+    let all_trusted = forwarded_chain(Some("10.0.0.3, 10.0.0.2".into()), None);
+    assert_eq!(reported_ip(Some(&trusted), XffPolicy::RightmostUntrusted, all_trusted, None, None), Some("10.0.0.3".into()));
+}
+
+#[test]
+fn trusted_proxies_route() {
+    let admin = Id::new("admin", "admin");
+    let routes = routes(&Config::default(), test_db(), with_admin(&admin));
+    let show = || warp::test::request().header("x-forwarded-for", "6.6.6.6, 1.1.1.1, 10.0.0.2").reply(&routes);
+    let list = || warp::test::request()
+        .path("/admin/trusted-proxies")
+        .header("authorization", admin.basic())
+        .reply(&routes);
+    let put = |body: &str| warp::test::request()
+        .method("PUT")
+        .path("/admin/trusted-proxies")
+        .header("authorization", admin.basic())
+        .header("content-length", body.len().to_string())
+        .body(body)
+        .reply(&routes);
+
+    // Without `TRUSTED_PROXIES`, the first hop is the client
+    assert_eq!(list().body(), "");
+    assert_eq!(show().body(), "6.6.6.6");
+
+    // Trusting the proxies moves the client to the rightmost untrusted hop
+    assert_eq!(put("10.0.0.0/8, ::1\n").status(), Code::OK);
+    assert_eq!(list().body(), "10.0.0.0/8\n::1/128\n");
+    assert_eq!(show().body(), "1.1.1.1");
+
+    // An invalid network changes nothing
+    assert_eq!(put("192.168.0.0/16,nope").status(), Code::BAD_REQUEST);
+    assert_eq!(list().body(), "10.0.0.0/8\n::1/128\n");
+    assert_eq!(show().body(), "1.1.1.1");
+
+    assert_eq!(warp::test::request().path("/admin/trusted-proxies").reply(&routes).status(), Code::UNAUTHORIZED);
+}
+
+#[test]
+fn forwarded_header() {
+    assert_eq!(forwarded_for("for=1.2.3.4;proto=https"), vec!["1.2.3.4"]);
+    assert_eq!(
+        forwarded_for(r#"For="[2001:db8::1]:4711", proto=http;for=198.51.100.17;by=10.0.0.1, by=10.0.0.2"#),
+        vec!["[2001:db8::1]:4711", "198.51.100.17"],
+    );
+    assert_eq!(forwarded_for("for=unknown"), vec!["unknown"]);
+    assert!(forwarded_for("proto=https").is_empty());
+    assert_eq!(forwarded_chain(Some("1.1.1.1".into()), Some("for=2.2.2.2".into())), Some(vec!["1.1.1.1".to_string()]));
+    assert_eq!(forwarded_chain(None, Some("proto=https".into())), None);
+
+    let post = |forwarded: &str| {
+        warp::test::request()
+            .method("POST")
+            .header("forwarded", forwarded)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes(&Config::default(), test_db(), Services::default()))
+    };
+    assert_eq!(post(r#"for="[2001:db8::1]:4711";proto=https"#).body(), "2001:db8::1");
+    assert_eq!(post("for=_hidden").status(), Code::BAD_REQUEST);
+    let res = warp::test::request()
+        .header("forwarded", "for=1.2.3.4;proto=https")
+        .reply(&routes(&Config::default(), test_db(), Services::default()));
+    assert_eq!(res.body(), "1.2.3.4");
+}
+
+#[test]
+fn ip_override() {
+    let post = |permit: Permit, path: &str| {
+        let config = Config { allow_ip_override: permit, ..Config::default() };
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("x-forwarded-for", "1.1.1.1")
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes(&config, test_db(), Services::default()))
+    };
+
+    assert_eq!(post(Permit::Nobody, "/?myip=203.0.113.7").status(), Code::FORBIDDEN);
+    assert_eq!(post(Permit::Users(vec!["herp".into()]), "/?myip=203.0.113.7").status(), Code::FORBIDDEN);
+    assert_eq!(post(Permit::Users(vec!["derp".into()]), "/?myip=203.0.113.7").body(), "203.0.113.7");
+    assert_eq!(post(Permit::Everyone, "/record/vps?myip=::2").body(), "::2");
+    assert_eq!(post(Permit::Everyone, "/?myip=nope").status(), Code::BAD_REQUEST);
+    assert_eq!(post(Permit::Nobody, "/").body(), "1.1.1.1");
+}
+
+#[test]
+fn ip_body() {
+    let post = |permit: Permit, body: &str| {
+        let config = Config { allow_ip_body: permit, ..Config::default() };
+        warp::test::request()
+            .method("POST")
+            .header("x-forwarded-for", "1.1.1.1")
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .body(body)
+            .reply(&routes(&config, test_db(), Services::default()))
+    };
+
+    assert_eq!(post(Permit::Nobody, "203.0.113.7").body(), "1.1.1.1");
+    assert_eq!(post(Permit::Everyone, "").body(), "1.1.1.1");
+    assert_eq!(post(Permit::Everyone, "203.0.113.7\n").body(), "203.0.113.7");
+    assert_eq!(post(Permit::Users(vec!["derp".into()]), r#"{"ip": "::2"}"#).body(), "::2");
+    let res = post(Permit::Everyone, r#"{"ip": "203.0.113.7", "name": "vps"}"#);
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.body(), "203.0.113.7");
+    assert_eq!(post(Permit::Everyone, "203.0.113.7 Bad_Name").status(), Code::BAD_REQUEST);
+    assert_eq!(post(Permit::Everyone, "nope").status(), Code::BAD_REQUEST);
+    assert_eq!(post(Permit::Everyone, r#"{"name": "vps"}"#).status(), Code::BAD_REQUEST);
+    let oversized = |permit: Permit, length: Option<&str>| {
+        let config = Config { allow_ip_body: permit, ..Config::default() };
+        let request = warp::test::request()
+            .method("POST")
+            .header("x-forwarded-for", "1.1.1.1")
+            .header("authorization", Id::new("derp", "flerp").basic());
+        match length {
+            Some(length) => request.header("content-length", length),
+            None => request,
+        }
+        .body("1".repeat(2000))
+        .reply(&routes(&config, test_db(), Services::default()))
+    };
+    assert_eq!(oversized(Permit::Everyone, Some("2000")).status(), Code::PAYLOAD_TOO_LARGE);
+    // A body without a length is refused once it grows too large
+    assert_eq!(oversized(Permit::Everyone, None).status(), Code::PAYLOAD_TOO_LARGE);
+    // ...and isn't read at all when the user may not send one
+    assert_eq!(oversized(Permit::Nobody, Some("2000")).body(), "1.1.1.1");
+    assert_eq!(oversized(Permit::Nobody, None).body(), "1.1.1.1");
+
+    assert_eq!(parse_ip_body(b"::1 vps"), Ok(Some(("::1".parse().unwrap(), Some("vps".into())))));
+    assert_eq!(parse_ip_body(b"::1 vps extra"), Err(BadRequest));
+}
+
+#[test]
+fn dyndns_update() {
+    let db = test_db();
+    let update = |path: &str, ip: &str, auth: Option<Id>| {
+        let mut req = warp::test::request().path(path).header("x-forwarded-for", ip);
+        if let Some(id) = auth {
+            req = req.header("authorization", id.basic());
+        }
+        req.reply(&routes(&Config::default(), db.clone(), Services::default()))
+    };
+    let derp = || Some(Id::new("derp", "flerp"));
+
+    let res = update("/nic/update?hostname=derp.d5.example.com", "1.1.1.1", derp());
+    assert_eq!((res.status(), res.body().as_ref()), (Code::OK, &b"good 1.1.1.1"[..]));
+    assert_eq!(update("/nic/update?hostname=derp.d5.example.com", "1.1.1.1", derp()).body(), "nochg 1.1.1.1");
+    assert_eq!(update("/nic/update", "1.1.1.1", derp()).body(), "nochg 1.1.1.1");
+    assert_eq!(update("/nic/update?hostname=vps.example.com,Bad_Host", "2.2.2.2", derp()).body(), "good 2.2.2.2\nnotfqdn");
+    assert_eq!(db.read()[&Id::new("derp", "flerp")].hosts["vps"].ip.to_string(), "2.2.2.2");
+    // `myip` needs `ALLOW_IP_OVERRIDE`
+    assert_eq!(update("/nic/update?myip=3.3.3.3", "1.1.1.1", derp()).body(), "nochg 1.1.1.1");
+
+    let res = update("/nic/update?hostname=derp.d5.example.com", "1.1.1.1", None);
+    assert_eq!((res.status(), res.body().as_ref()), (Code::UNAUTHORIZED, &b"badauth"[..]));
+    assert!(res.headers().contains_key("www-authenticate"));
+}
+
+#[test]
+fn duckdns_update() {
+    let db = test_db();
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    let (_, token) = db::mint_token(&mut db.write(), &derp, 2).unwrap();
+    let update = |query: String| {
+        warp::test::request()
+            .path(&format!("/update?{}", query))
+            .header("x-forwarded-for", "2.2.2.2")
+            .reply(&routes(&Config::default(), db.clone(), Services::default()))
+    };
+
+    assert_eq!(update(format!("domains=derp&token={}&ip=", token)).body(), "OK");
+    assert_eq!(db.read()[&derp].ip.to_string(), "2.2.2.2");
+    assert_eq!(update(format!("domains=derp,vps&token={}&verbose=true", token)).body(), "OK\n2.2.2.2\n\nUPDATED");
+    assert_eq!(update(format!("domains=vps&token={}&verbose=true", token)).body(), "OK\n2.2.2.2\n\nNOCHANGE");
+    assert_eq!(db.read()[&derp].hosts["vps"].ip.to_string(), "2.2.2.2");
+    assert_eq!(update("domains=derp&token=nope".to_string()).body(), "KO");
+    assert_eq!(update("domains=derp&token=".to_string()).body(), "KO");
+    assert_eq!(update(format!("domains=Bad_Name&token={}", token)).body(), "KO");
+}
+
+#[test]
+fn update_url_route() {
+    let db = test_db();
+    let derp = Id::new("derp", "flerp");
+    let request = |method: &str, path: &str, auth: bool| {
+        let mut req = warp::test::request().method(method).path(path).header("x-forwarded-for", "2.2.2.2");
+        if auth {
+            req = req.header("authorization", derp.basic());
+        }
+        req.reply(&routes(&Config::default(), db.clone(), Services::default()))
+    };
+
+    assert_eq!(request("POST", "/update-url", true).status(), Code::NOT_FOUND);
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    let res = request("POST", "/update-url", true);
+    assert_eq!(res.status(), Code::OK);
+    let path = String::from_utf8_lossy(res.body()).trim().to_string();
+    assert!(path.starts_with("/u/"));
+
+    let res = request("GET", &path, false);
+    assert_eq!((res.status(), res.body().as_ref()), (Code::OK, &b"2.2.2.2"[..]));
+    assert_eq!(db.read()[&derp].ip.to_string(), "2.2.2.2");
+    assert_eq!(request("GET", "/u/nope", false).status(), Code::NOT_FOUND);
+
+    assert_eq!(request("DELETE", "/update-url", true).status(), Code::NO_CONTENT);
+    assert_eq!(request("GET", &path, false).status(), Code::NOT_FOUND);
+    assert_eq!(request("DELETE", "/update-url", true).status(), Code::NOT_FOUND);
+}
+
+#[test]
+fn acme_dns_routes() {
+    let db = test_db();
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    let path = std::env::temp_dir().join(format!("d5-acme-routes-{}.json", std::process::id()));
+    let accounts = Accounts::open(&path).unwrap();
+    let config = Config { dns_zone: Some("Dyn.Example.com.".to_string()), ..Config::default() };
+    let services = Services { acme: Some(accounts.clone()), ..Services::default() };
+    let request = |path: &str, headers: &[(&str, &str)], body: &str| {
+        let mut req = warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("x-forwarded-for", "2.2.2.2")
+            .header("content-length", body.len().to_string())
+            .body(body);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let res = req.reply(&routes(&config, db.clone(), services.clone()));
+        (res.status(), serde_json::from_slice::<serde_json::Value>(res.body()).unwrap_or_default())
+    };
+
+    let (status, account) = request("/acme-dns/register", &[("authorization", &derp.basic())], "");
+    assert_eq!(status, Code::CREATED);
+    let (user, key, subdomain) = (account["username"].as_str().unwrap(), account["password"].as_str().unwrap(), account["subdomain"].as_str().unwrap());
+    assert_eq!(account["fulldomain"], format!("{}.dyn.example.com", subdomain));
+
+    let txt = "x".repeat(43);
+    let update = serde_json::json!({ "subdomain": subdomain, "txt": txt }).to_string();
+    let (status, body) = request("/acme-dns/update", &[("x-api-user", user), ("x-api-key", key)], &update);
+    assert_eq!((status, body["txt"].as_str()), (Code::OK, Some(txt.as_str())));
+    assert_eq!(accounts.txt("_acme-challenge.derp"), vec![txt]);
+    let (status, body) = request("/acme-dns/update", &[("x-api-user", user), ("x-api-key", "wrong")], &update);
+    assert_eq!((status, body["error"].as_str()), (Code::UNAUTHORIZED, Some("forbidden")));
+    let bad_txt = serde_json::json!({ "subdomain": subdomain, "txt": "short" }).to_string();
+    let (status, body) = request("/acme-dns/update", &[("x-api-user", user), ("x-api-key", key)], &bad_txt);
+    assert_eq!((status, body["error"].as_str()), (Code::BAD_REQUEST, Some("bad_txt")));
+
+    let basic = derp.basic();
+    let derp_auth = [("authorization", basic.as_str())];
+    let (status, body) = request("/acme-dns/register", &derp_auth, r#"{"allowfrom": ["10.0.0.0/8", "nope"]}"#);
+    assert_eq!((status, body["error"].as_str()), (Code::BAD_REQUEST, Some("invalid_allowfrom_cidr")));
+    let (status, body) = request("/acme-dns/register", &derp_auth, r#"{"allowfrom": ["10.0.0.0/8"]}"#);
+    assert_eq!((status, body["allowfrom"].clone()), (Code::CREATED, serde_json::json!(["10.0.0.0/8"])));
+    // Registering needs credentials, unless registration is open
+    let (status, body) = request("/acme-dns/register", &[], "");
+    assert_eq!((status, body["error"].as_str()), (Code::UNAUTHORIZED, Some("forbidden")));
+    let open = Config { acme_dns_open_registration: true, ..config.clone() };
+    let res = warp::test::request()
+        .method("POST")
+        .path("/acme-dns/register")
+        .header("content-length", "0")
+        .reply(&routes(&open, db.clone(), services.clone()));
+    assert_eq!(res.status(), Code::CREATED);
+    let (status, _) = request("/acme-dns/register", &[("authorization", &Id::new("herp", "blerp").basic())], "");
+    assert_eq!(status, Code::UNAUTHORIZED);
+    let big = format!(r#"{{"allowfrom": [], "pad": "{}"}}"#, "x".repeat(2000));
+    assert_eq!(request("/acme-dns/register", &[], &big).0, Code::PAYLOAD_TOO_LARGE);
+    assert_eq!(request("/acme-dns/update", &[("x-api-user", user), ("x-api-key", key)], &big).0, Code::PAYLOAD_TOO_LARGE);
+    std::fs::remove_file(&path).unwrap();
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/acme-dns/register")
+        .header("content-length", "0")
+        .reply(&routes(&Config::default(), db.clone(), Services::default()));
+    assert!(res.status().is_client_error());
+}
+
+#[test]
+fn pdns_backend_route() {
+    let db = test_db();
+    db::upsert(&mut db.write(), Id::new("derp", "flerp"), "1.1.1.1".parse().unwrap(), 1);
+    let config = Config { dns_zone: Some("dyn.example.com".to_string()), pdns_backend: true, ..Config::default() };
+    let lookup = |config: &Config| {
+        warp::test::request()
+            .path("/dns/lookup/derp.dyn.example.com./A")
+            .reply(&routes(config, db.clone(), Services::default()))
+    };
+
+    let res = lookup(&config);
+    assert_eq!(res.status(), Code::OK);
+    let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+    assert_eq!(body["result"][0]["content"], "1.1.1.1");
+    assert!(lookup(&Config { pdns_backend: false, ..config.clone() }).status().is_client_error());
+
+    // A proxied request is refused, whoever the peer is
+    let res = warp::test::request()
+        .path("/dns/list/dyn.example.com.")
+        .header("x-forwarded-for", "127.0.0.1")
+        .reply(&routes(&config, db.clone(), Services::default()));
+    assert!(res.status().is_client_error());
+    assert!(!String::from_utf8_lossy(res.body()).contains("1.1.1.1"));
+
+    let allow = &config.pdns_backend_allow;
+    let peer = |ip: &str| Some(SocketAddr::new(ip.parse().unwrap(), 4444));
+    assert!(pdns_allowed(allow, peer("127.0.0.1"), false));
+    assert!(pdns_allowed(allow, peer("::1"), false));
+    assert!(pdns_allowed(allow, None, false));
+    assert!(!pdns_allowed(allow, peer("8.8.8.8"), false));
+    assert!(!pdns_allowed(allow, peer("127.0.0.1"), true));
+}
+
+#[test]
+fn post_reports_changes() {
+    let db = test_db();
+    let post = |path: &str, ip: &str| {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("x-forwarded-for", ip)
+            .header("accept", "application/json")
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes(&Config::default(), db.clone(), Services::default()))
+    };
+    let update = |res: &warp::http::Response<bytes::Bytes>| {
+        let json: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        (res.headers()["x-d5-update"].to_str().unwrap().to_string(), json["update"].as_str().unwrap().to_string())
+    };
+
+    let res = post("/", "1.1.1.1");
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(update(&res), ("updated".into(), "updated".into()));
+    assert_eq!(update(&post("/", "1.1.1.1")), ("unchanged".into(), "unchanged".into()));
+    assert_eq!(update(&post("/", "2.2.2.2")).0, "updated");
+    assert_eq!(db.read()[&Id::new("derp", "flerp")].history.len(), 2);
+    assert_eq!(update(&post("/record/vps", "::1")).0, "updated");
+    assert_eq!(update(&post("/record/vps", "::1")).0, "unchanged");
+}
+
+#[test]
+fn reject_private_post() {
+    let post = |reject_private: bool, ip: &str| {
+        let config = Config { reject_private, ..Config::default() };
+        warp::test::request()
+            .method("POST")
+            .header("x-forwarded-for", ip)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes(&config, test_db(), Services::default()))
+    };
+
+    assert_eq!(post(false, "10.0.0.5").status(), Code::OK);
+    let res = post(true, "10.0.0.5");
+    assert_eq!(res.status(), Code::UNPROCESSABLE_ENTITY);
+    assert!(String::from_utf8_lossy(res.body()).starts_with("Refusing to store 10.0.0.5, a private address"));
+    assert_eq!(post(true, "1.1.1.1").status(), Code::OK);
+    assert_eq!(post(true, "2606:4700::1111").status(), Code::OK);
+
+    for ip in &["127.0.0.1", "169.254.1.1", "100.64.0.1", "172.31.255.255", "192.0.2.1", "255.255.255.255"] {
+        assert!(reserved_range(ip.parse().unwrap()).is_some(), "{}", ip);
+    }
+    for ip in &["::1", "::", "fd00::1", "fe80::1", "ff02::1", "2001:db8::1"] {
+        assert!(reserved_range(ip.parse().unwrap()).is_some(), "{}", ip);
+    }
+    for ip in &["172.32.0.1", "100.128.0.1", "8.8.8.8", "2001:4860::8888"] {
+        assert_eq!(reserved_range(ip.parse().unwrap()), None, "{}", ip);
+    }
+}
+
+#[test]
+fn get_by_family() {
+    let (db, routes) = {
+        let db = test_db();
+        (db.clone(), routes(&Config::default(), db, Services::default()))
+    };
+    for ip in &["1.1.1.1", "::1", "2.2.2.2, 10.0.0.1"] {
+        let res = warp::test::request()
+            .method("POST")
+            .header("x-forwarded-for", *ip)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes);
+        assert_eq!(res.status(), Code::OK);
+    }
+    let entry = db.read()[&Id::new("derp", "flerp")].clone();
+    assert_eq!((entry.ip.to_string(), entry.other_ip.map(|ip| ip.to_string())), ("2.2.2.2".to_string(), Some("::1".to_string())));
+
+    let get = |query: &str| {
+        let res = warp::test::request()
+            .path(&format!("/{}", query))
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes);
+        (res.status(), String::from_utf8_lossy(res.body()).into_owned())
+    };
+    assert_eq!(get(""), (Code::OK, "2.2.2.2".to_string()));
+    assert_eq!(get("?family=4"), (Code::OK, "2.2.2.2".to_string()));
+    assert_eq!(get("?family=6"), (Code::OK, "::1".to_string()));
+    assert_eq!(get("?family=all"), (Code::OK, "2.2.2.2\n::1".to_string()));
+    assert_eq!(get("?family=5").0, Code::BAD_REQUEST);
+
+    let res = warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", "not an ip")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+    assert_eq!(res.status(), Code::BAD_REQUEST);
+}
+
+#[test]
+fn named_records() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let request = |method: &str, path: &str, ip: &str| {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("x-forwarded-for", ip)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes);
+        (res.status(), String::from_utf8_lossy(res.body()).into_owned())
+    };
+
+    assert_eq!(request("POST", "/record/home", "1.1.1.1"), (Code::OK, "1.1.1.1".to_string()));
+    assert_eq!(request("POST", "/record/vps", "2.2.2.2").0, Code::OK);
+    assert_eq!(request("POST", "/record/vps", "::2").0, Code::OK);
+    assert_eq!(request("POST", "/record/VPS", "2.2.2.2").0, Code::BAD_REQUEST);
+    assert_eq!(request("GET", "/record/home", "9.9.9.9"), (Code::OK, "1.1.1.1".to_string()));
+    assert_eq!(request("GET", "/record/vps?family=all", "9.9.9.9"), (Code::OK, "2.2.2.2\n::2".to_string()));
+    assert_eq!(request("GET", "/records", "9.9.9.9"), (Code::OK, "home 1.1.1.1\nvps ::2\nvps 2.2.2.2\n".to_string()));
+
+    assert_eq!(request("DELETE", "/record/home", "9.9.9.9").0, Code::NO_CONTENT);
+    assert_eq!(request("GET", "/record/home", "9.9.9.9").0, Code::NOT_FOUND);
+    assert_eq!(request("GET", "/", "9.9.9.9"), (Code::OK, "1.1.1.1".to_string()));
+}
+
+#[test]
+fn max_named_records() {
+    let config = Config { max_records: Some(2), ..Config::default() };
+    let routes = routes(&config, test_db(), Services::default());
+    let post = |name: &str, ip: &str| warp::test::request()
+        .method("POST")
+        .path(&format!("/record/{}", name))
+        .header("x-forwarded-for", ip)
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes)
+        .status();
+
+    // Up to the cap is allowed...
+    assert_eq!(post("home", "1.1.1.1"), Code::OK);
+    assert_eq!(post("vps", "2.2.2.2"), Code::OK);
+    // ...one more isn't...
+    assert_eq!(post("laptop", "3.3.3.3"), Code::FORBIDDEN);
+    // ...and updating one at the cap still is
+    assert_eq!(post("vps", "4.4.4.4"), Code::OK);
+    assert_eq!(post("home", "::1"), Code::OK);
+}
+
+#[test]
+fn ip_history() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let request = |method: &str, path: &str, ip: &str| {
+        let res = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("x-forwarded-for", ip)
+            .header("authorization", Id::new("derp", "flerp").basic())
+            .reply(&routes);
+        (res.status(), String::from_utf8_lossy(res.body()).into_owned())
+    };
+
+    assert_eq!(request("GET", "/history", "1.1.1.1").0, Code::NOT_FOUND);
+    for ip in &["1.1.1.1", "1.1.1.1", "2.2.2.2"] {
+        request("POST", "/", ip);
+    }
+    let (status, body) = request("GET", "/history", "1.1.1.1");
+    let ips = body.lines().map(|line| line.split(' ').nth(1).unwrap()).collect::<Vec<_>>();
+    assert_eq!((status, ips), (Code::OK, vec!["2.2.2.2", "1.1.1.1"]));
+}
+
+#[test]
+fn last_modified() {
+    let db = test_db();
+    let routes = routes(&Config::default(), db.clone(), Services::default());
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 784_111_777);
+    let touch = warp::test::request()
+        .method("POST")
+        .path("/touch")
+        .header("x-forwarded-for", "2.2.2.2")
+        .header("authorization", derp.basic())
+        .reply(&routes);
+    assert_eq!(touch.status(), Code::NO_CONTENT);
+    let updated_at = db.read()[&derp].updated_at;
+
+    let res = warp::test::request()
+        .header("accept", "application/json")
+        .header("authorization", derp.basic())
+        .reply(&routes);
+    assert_eq!(res.headers()["last-modified"], db::http_date(updated_at).as_str());
+    let json = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+    assert_eq!((&json["ip"], &json["updated_from"], &json["updated_at"]), (&"1.1.1.1".into(), &"2.2.2.2".into(), &updated_at.into()));
+}
+
+#[test]
+fn record_ttl() {
+    let db = test_db();
+    let config = Config { record_ttl: Some(60), ..Config::default() };
+    let routes = routes(&config, db.clone(), Services::default());
+    let (derp, herp) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), db::now());
+    db::upsert(&mut db.write(), herp.clone(), "2.2.2.2".parse().unwrap(), db::now() - 60);
+
+    let get = |id: &Id| {
+        warp::test::request()
+            .header("x-forwarded-for", "9.9.9.9")
+            .header("authorization", id.basic())
+            .reply(&routes)
+            .status()
+    };
+    assert_eq!(get(&derp), Code::OK);
+    assert_eq!(get(&herp), Code::NOT_FOUND);
+}
+
+#[test]
+fn shutdown_drain() {
+    let drain = Drain::default();
+    let routes = routes(&Config::default(), test_db(), Services { drain: drain.clone(), ..Services::default() });
+
+    // A slow request holds its guard until it finishes
+    let slow = {
+        let drain = drain.clone();
+        thread::spawn(move || {
+            let filter = drain.filter().map(|_guard| {
+                thread::sleep(Duration::from_millis(200));
+                "done"
+            });
+            warp::test::request().reply(&filter)
+        })
+    };
+    while drain.in_flight() == 0 {
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // Draining waits for the slow request while refusing new ones
+    let draining = {
+        let drain = drain.clone();
+        thread::spawn(move || drain.drain(Duration::from_secs(5)))
+    };
+    thread::sleep(Duration::from_millis(50));
+    let res = warp::test::request().path("/server-ip").reply(&routes);
+    assert_eq!(res.status(), Code::SERVICE_UNAVAILABLE);
+
+    assert_eq!(slow.join().unwrap().body().as_ref(), b"done");
+    assert_eq!(draining.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn last_auth() {
+    let db = test_db();
+    let routes = routes(&Config::default(), db.clone(), Services::default());
+    let derp = Id::new("derp", "flerp");
+    let request = |method: &str, ip: &str| warp::test::request()
+        .method(method)
+        .header("x-forwarded-for", ip)
+        .header("authorization", derp.basic())
+        .reply(&routes);
+    let entry = || db.read()[&derp].clone();
+
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    assert_eq!(request("GET", "1.1.1.1").status(), Code::OK);
+    assert!(entry().last_auth > 0);
+    assert_eq!(entry().updated_at, 0);
+
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    assert_eq!(request("POST", "2.2.2.2").status(), Code::OK);
+    assert!(entry().last_auth > 0);
+    assert!(entry().updated_at > 0);
+}
+
+#[test]
+fn on_change_hook() {
+    use std::time::{Instant, SystemTime};
+
+    let nanos = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    let out = std::env::temp_dir().join(format!("d5-hook-{}-{}", std::process::id(), nanos));
+    let cmd = format!("echo \"$D5_USER $D5_OLD_IP $D5_NEW_IP\" >> '{}'", out.display());
+    let hook = Some(Hook::new(cmd, Duration::from_secs(5)));
+    let routes = routes(&Config::default(), test_db(), Services { hook, ..Services::default() });
+    let post = |ip: &str| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", ip)
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+
+    for ip in &["1.1.1.1", "1.1.1.1", "2.2.2.2"] {
+        assert_eq!(post(ip).status(), Code::OK);
+    }
+
+    let lines = || {
+        let mut lines = std::fs::read_to_string(&out).unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    };
+    let start = Instant::now();
+    while lines().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(lines(), vec!["derp  1.1.1.1", "derp 1.1.1.1 2.2.2.2"]);
+    std::fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn rotate_key_route() {
+    let (old, new, admin) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"), Id::new("admin", "admin"));
+    let db = Arc::new(RwLock::new(Records::new().with_keys(vec![old.clone()])));
+    let config = Config::default();
+    let routes = routes(&config, db, with_admin(&admin));
+    let request = |method: &str, id: &Id| warp::test::request()
+        .method(method)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .reply(&routes);
+    let rotate = |id: &Id, body: &str| warp::test::request()
+        .method("POST")
+        .path("/admin/key")
+        .header("authorization", id.basic())
+        .header("content-length", body.len().to_string())
+        .body(body)
+        .reply(&routes)
+        .status();
+
+    assert_eq!(request("POST", &old).status(), Code::OK);
+    assert_eq!(request("POST", &new).status(), Code::UNAUTHORIZED);
+
+    assert_eq!(rotate(&old, "herp:blerp"), Code::UNAUTHORIZED);
+    assert_eq!(rotate(&admin, "no colon"), Code::BAD_REQUEST);
+    assert_eq!(rotate(&admin, "admin:admin"), Code::CONFLICT);
+    assert_eq!(request("POST", &old).status(), Code::OK);
+    assert_eq!(rotate(&admin, "herp:blerp"), Code::OK);
+
+    assert_eq!(request("POST", &old).status(), Code::UNAUTHORIZED);
+    assert_eq!(request("POST", &new).status(), Code::OK);
+    let res = warp::test::request()
+        .header("x-forwarded-for", "9.9.9.9")
+        .header("authorization", new.basic())
+        .reply(&routes);
+    assert_eq!(res.body().as_ref(), b"1.1.1.1");
+}
+
+#[test]
+fn rotate_key_needs_one_key() {
+    let admin = Id::new("admin", "admin");
+    let keys = vec![Id::new("derp", "flerp"), Id::new("herp", "blerp")];
+    for (keys, status) in [(keys, Code::CONFLICT), (vec![], Code::NOT_FOUND)] {
+        let db = Arc::new(RwLock::new(Records::new().with_keys(keys)));
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/key")
+            .header("authorization", admin.basic())
+            .header("content-length", "9")
+            .body("lerp:merp")
+            .reply(&routes(&Config::default(), db, with_admin(&admin)));
+        assert_eq!(res.status(), status);
+    }
+}
+
+#[test]
+fn custom_404() {
+    let config = Config { custom_404: Some("Nothing here; this is d5.".into()), ..Config::default() };
+    let routes = routes(&config, test_db(), Services::default());
+    let request = |path: &str, accept: &str| warp::test::request()
+        .path(path)
+        .header("accept", accept)
+        .header("x-forwarded-for", "1.1.1.1")
+        .reply(&routes);
+
+    let res = request("/nope", "text/plain");
+    assert_eq!(res.status(), Code::NOT_FOUND);
+    let body = String::from_utf8_lossy(res.body()).to_string();
+    assert!(body.starts_with("Nothing here; this is d5.\n"));
+    assert!(body.contains("  POST /touch\n"));
+
+    let res = request("/nope", "application/json");
+    let json = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+    assert_eq!(json["error"], "Nothing here; this is d5.");
+    assert_eq!(json["endpoints"][0], "GET /");
+
+    // Real routes, and their own errors, are unaffected
+    assert_eq!(request("/", "text/plain").body().as_ref(), b"1.1.1.1");
+    assert!(!request("/admin/list", "text/plain").body().starts_with(b"Nothing here"));
+    assert!(is_endpoint("/touch/"));
+}
+
+#[test]
+fn tenants() {
+    let new_store = || Store::new(Records::new(), Debounce::default());
+    let tenants = Tenants::new(&["a".parse().unwrap(), "b".parse().unwrap()], |_| new_store());
+    let routes = routes(&Config::default(), test_db(), Services { tenants, ..Services::default() });
+    let request = |method: &str, tenant: Option<&str>, ip: &str| {
+        let mut req = warp::test::request()
+            .method(method)
+            .header("x-forwarded-for", ip)
+            .header("authorization", Id::new("derp", "flerp").basic());
+        if let Some(tenant) = tenant {
+            req = req.header("x-tenant", tenant);
+        }
+        req.reply(&routes)
+    };
+
+    assert_eq!(request("POST", Some("a"), "1.1.1.1").status(), Code::OK);
+    assert_eq!(request("POST", Some("b"), "2.2.2.2").status(), Code::OK);
+    assert_eq!(request("GET", Some("a"), "9.9.9.9").body().as_ref(), b"1.1.1.1");
+    assert_eq!(request("GET", Some("b"), "9.9.9.9").body().as_ref(), b"2.2.2.2");
+
+    // The default store has no record, so `show` echoes the caller's IP
+    assert_eq!(request("GET", None, "9.9.9.9").body().as_ref(), b"9.9.9.9");
+
+    let res = warp::test::request()
+        .header("host", "b.d5.example.com")
+        .header("x-forwarded-for", "9.9.9.9")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+    assert_eq!(res.body().as_ref(), b"2.2.2.2");
+
+    assert_eq!(request("POST", Some("c"), "1.1.1.1").status(), Code::BAD_REQUEST);
+}
+
+#[test]
+fn malformed_credentials() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let post = |auth: &str| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", auth)
+        .reply(&routes);
+
+    let res = post("Basic ZGVycGZsZXJw");
+    assert_eq!(res.status(), Code::BAD_REQUEST);
+    assert_eq!(res.body().as_ref(), NotUserPassword.to_string().as_bytes());
+
+    let res = post("Basic not base64!");
+    assert_eq!(res.status(), Code::BAD_REQUEST);
+    assert_eq!(res.body().as_ref(), InvalidBase64.to_string().as_bytes());
+}
+
+#[test]
+fn options_allow() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let options = |path: &str| warp::test::request().method("OPTIONS").path(path).reply(&routes);
+
+    let res = options("/");
+    assert_eq!(res.status(), Code::NO_CONTENT);
+    assert_eq!(res.headers()["allow"], "GET, POST, DELETE, OPTIONS");
+    assert_eq!(options("/touch").headers()["allow"], "POST, OPTIONS");
+    assert_eq!(options("*").headers()["allow"], "GET, POST, DELETE, PUT, OPTIONS");
+    assert!(options("/nope").headers().get("allow").is_none());
+
+    // Paths with parameters match their templates
+    assert_eq!(options("/record/home").headers()["allow"], "POST, GET, DELETE, OPTIONS");
+    assert_eq!(options("/tokens/abcd").headers()["allow"], "DELETE, OPTIONS");
+    assert_eq!(options("/u/0123abcd").headers()["allow"], "GET, OPTIONS");
+    assert_eq!(options("/dns/lookup/derp.example.com/ANY").headers()["allow"], "GET, POST, OPTIONS");
+    assert!(options("/dns/lookup").headers().get("allow").is_none());
+    assert!(options("/record").headers().get("allow").is_none());
+    assert!(options("/record/home/extra").headers().get("allow").is_none());
+    assert!(is_endpoint("/record/home") && is_endpoint("/tokens/abcd/"));
+}
+
+#[test]
+fn rotate_key_grace_route() {
+    let (old, new, admin) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"), Id::new("admin", "admin"));
+    let db = Arc::new(RwLock::new(Records::new().with_keys(vec![old.clone()])));
+    let config = Config { key_rotation_grace: Some(60), ..Config::default() };
+    let routes = routes(&config, db.clone(), with_admin(&admin));
+    let post = |id: &Id, ip: &str| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", ip)
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+
+    assert_eq!(post(&old, "1.1.1.1"), Code::OK);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/admin/key")
+        .header("authorization", admin.basic())
+        .header("content-length", "10")
+        .body("herp:blerp")
+        .reply(&routes);
+    assert_eq!(res.status(), Code::OK);
+
+    assert_eq!(post(&old, "2.2.2.2"), Code::OK);
+    assert_eq!(post(&new, "3.3.3.3"), Code::OK);
+    assert!(!db.read().contains_key(&old));
+    assert_eq!(db.read()[&new].ip.to_string(), "3.3.3.3");
+}
+
+#[test]
+fn validate_credential() {
+    let admin = Id::new("admin", "admin");
+    let config = Config::default();
+    let db = test_db();
+    let routes = routes(&config, db.clone(), with_admin(&admin));
+    let validate = |body: &str| {
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/validate-credential")
+            .header("authorization", admin.basic())
+            .header("accept", "application/json")
+            .header("content-length", body.len().to_string())
+            .body(body)
+            .reply(&routes);
+        assert_eq!(res.status(), Code::OK);
+        serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()
+    };
+
+    let json = validate("derp:flerp");
+    assert_eq!(json["valid"], true);
+    assert_eq!(json["user"], "derp");
+    assert_eq!(json["basic"], Id::new("derp", "flerp").basic());
+
+    let json = validate("derpflerp");
+    assert_eq!(json["valid"], false);
+    assert!(json["error"].as_str().unwrap().contains("user:password"));
+
+    let json = validate(&format!("derp:{}", "f".repeat(id::MAX_LEN)));
+    assert_eq!(json["valid"], false);
+    assert!(json["error"].as_str().unwrap().contains("longer than"));
+
+    assert!(db.read().list().next().is_none());
+}
+
+#[test]
+fn max_xff_entries() {
+    let config = Config { max_xff_entries: Some(2), ..Config::default() };
+    let routes = routes(&config, test_db(), Services::default());
+    let show = |xff: &str| warp::test::request().header("x-forwarded-for", xff).reply(&routes);
+
+    assert_eq!(show("1.1.1.1").status(), Code::OK);
+    assert_eq!(show("1.1.1.1, 2.2.2.2").status(), Code::OK);
+    assert_eq!(show("1.1.1.1, 2.2.2.2, 3.3.3.3").status(), Code::BAD_REQUEST);
+}
+
+#[test]
+fn your_ip_header() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let request = |method: &str| warp::test::request()
+        .method(method)
+        .header("x-forwarded-for", "203.0.113.7")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+
+    assert_eq!(request("POST").headers()["x-your-ip"], "203.0.113.7");
+    assert_eq!(request("GET").headers()["x-your-ip"], "203.0.113.7");
+    assert_eq!(request("DELETE").headers()["x-your-ip"], "203.0.113.7");
+    let res = warp::test::request()
+        .method("POST")
+        .path("/record/home")
+        .header("x-forwarded-for", "203.0.113.7")
+        .header("authorization", Id::new("derp", "flerp").basic())
+        .reply(&routes);
+    assert_eq!(res.status(), Code::OK);
+    assert_eq!(res.headers()["x-your-ip"], "203.0.113.7");
+
+    // `show` isn't authenticated, so it gets no header
+    let res = warp::test::request().header("x-forwarded-for", "203.0.113.7").reply(&routes);
+    assert!(res.headers().get("x-your-ip").is_none());
+}
+
+#[test]
+fn export_route() {
+    let admin = Id::new("admin", "admin");
+    let config = Config::default();
+    let db = test_db();
+    db::upsert(&mut db.write(), Id::new("derp", "flerp"), "1.1.1.1".parse().unwrap(), 10);
+    let routes = routes(&config, db, with_admin(&admin));
+    let export = |id: &Id| warp::test::request()
+        .path("/admin/export")
+        .header("authorization", id.basic())
+        .reply(&routes);
+
+    assert_eq!(export(&Id::new("derp", "flerp")).status(), Code::UNAUTHORIZED);
+    let res = export(&admin);
+    assert_eq!(res.status(), Code::OK);
+    let json = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+    assert_eq!(json[0]["user"], "derp");
+    assert_eq!(json[0]["ip"], "1.1.1.1");
+    assert_eq!(json[0]["updated_at"], 10);
+}
+
+#[test]
+fn import_route() {
+    let admin = Id::new("admin", "admin");
+    let config = Config::default();
+    let db = test_db();
+    db::upsert(&mut db.write(), Id::new("derp", "flerp"), "1.1.1.1".parse().unwrap(), 0);
+    let dump = format!("[{}]", snapshot::record(&Id::new("herp", "blerp"), &db::Entry::new("2.2.2.2".parse().unwrap(), 0)));
+    let routes = routes(&config, db.clone(), with_admin(&admin));
+    let import = |query: &str| warp::test::request()
+        .method("POST")
+        .path(&format!("/admin/import{}", query))
+        .header("authorization", admin.basic())
+        .header("content-length", dump.len().to_string())
+        .body(&dump)
+        .reply(&routes);
+
+    assert_eq!(import("?mode=sideways").status(), Code::BAD_REQUEST);
+    let res = import("");
+    assert_eq!(res.body().as_ref(), b"Imported 1 records, removed 0\n");
+    assert_eq!(db.read().list().count(), 2);
+    let res = import("?mode=replace");
+    assert_eq!(res.body().as_ref(), b"Imported 1 records, removed 1\n");
+    assert!(!db.read().contains_key(&Id::new("derp", "flerp")));
+}
+
+#[test]
+fn credentials_file() {
+    let users = Users::parse("derp:flerp\n").unwrap();
+    let services = Services { users: Arc::new(RwLock::new(Some(users))), ..Services::default() };
+    let routes = routes(&Config::default(), test_db(), services);
+    let post = |id: &Id| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+
+    assert_eq!(post(&Id::new("derp", "flerp")), Code::OK);
+    assert_eq!(post(&Id::new("derp", "blerp")), Code::UNAUTHORIZED);
+    assert_eq!(post(&Id::new("herp", "flerp")), Code::UNAUTHORIZED);
+}
+
+#[test]
+fn multiple_keys() {
+    let (derp, herp) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+    let db = Arc::new(RwLock::new(Records::new().with_keys(vec![derp.clone(), herp.clone()])));
+    let routes = routes(&Config::default(), db, Services::default());
+    let post = |id: &Id| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+
+    assert_eq!(post(&derp), Code::OK);
+    assert_eq!(post(&herp), Code::OK);
+    assert_eq!(post(&Id::new("lerp", "merp")), Code::UNAUTHORIZED);
+}
+
+#[test]
+fn purge_route() {
+    let admin = Id::new("admin", "admin");
+    let derp = Id::new("derp", "flerp");
+    let db = test_db();
+    db::upsert(&mut db.write(), derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    db::delete(&mut db.write(), &derp, 0, Some(3600));
+    let routes = routes(&Config::default(), db.clone(), with_admin(&admin));
+    let purge = |id: &Id| warp::test::request()
+        .method("POST")
+        .path("/admin/purge")
+        .header("authorization", id.basic())
+        .reply(&routes);
+
+    assert_eq!(purge(&derp).status(), Code::UNAUTHORIZED);
+    assert_eq!(purge(&admin).body().as_ref(), b"Purged 1 records\n");
+    assert!(!db.read().contains_key(&derp));
+}
+
+#[test]
+fn bearer_token() {
+    let users = Users::parse("derp:{BEARER}s3cret\n").unwrap();
+    let services = Services { users: Arc::new(RwLock::new(Some(users))), ..Services::default() };
+    let routes = routes(&Config::default(), test_db(), services);
+    let request = |method: &str, auth: &str| warp::test::request()
+        .method(method)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", auth)
+        .reply(&routes);
+
+    assert_eq!(request("POST", "Bearer s3cret").status(), Code::OK);
+    assert_eq!(request("GET", "Bearer s3cret").body().as_ref(), b"1.1.1.1");
+    assert_eq!(request("GET", &Id::new("derp", "s3cret").basic()).body().as_ref(), b"1.1.1.1");
+    assert_eq!(request("POST", "Bearer wrong").status(), Code::UNAUTHORIZED);
+}
+
+#[test]
+fn update_tokens() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let derp = Id::new("derp", "flerp");
+    let request = |method: &str, path: &str, auth: &str| warp::test::request()
+        .method(method)
+        .path(path)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", auth)
+        .reply(&routes);
+
+    assert_eq!(request("POST", "/tokens", &derp.basic()).status(), Code::NOT_FOUND);
+    request("POST", "/", &derp.basic());
+    let minted = request("POST", "/tokens", &derp.basic());
+    let minted = String::from_utf8_lossy(minted.body()).into_owned();
+    let (token_id, token) = minted.trim().split_once(' ').unwrap();
+    let bearer = format!("Bearer {}", token);
+
+    assert_eq!(request("POST", "/", &bearer).status(), Code::OK);
+    assert_eq!(request("GET", "/", &bearer).body().as_ref(), b"1.1.1.1");
+    // A token can't mint or revoke tokens
+    assert_eq!(request("POST", "/tokens", &bearer).status(), Code::UNAUTHORIZED);
+    assert_eq!(request("DELETE", &format!("/tokens/{}", token_id), &bearer).status(), Code::UNAUTHORIZED);
+
+    assert_eq!(request("DELETE", &format!("/tokens/{}", token_id), &derp.basic()).status(), Code::NO_CONTENT);
+    assert_eq!(request("POST", "/", &bearer).status(), Code::UNAUTHORIZED);
+    assert_eq!(request("DELETE", &format!("/tokens/{}", token_id), &derp.basic()).status(), Code::NOT_FOUND);
+}
+
+#[test]
+fn register_with_invite() {
+    let path = std::env::temp_dir().join(format!("d5-register-{}", std::process::id()));
+    std::fs::write(&path, "derp:flerp\n").unwrap();
+    let admin = Id::new("admin", "admin");
+    let services = Services { users: Arc::new(RwLock::new(Some(Users::load(&path).unwrap()))), ..with_admin(&admin) };
+    let config = Config { credentials_file: Some(path.clone()), ..Config::default() };
+    let routes = routes(&config, test_db(), services);
+    let request = |path: &str, id: &Id| warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .reply(&routes);
+    let herp = Id::new("herp", "blerp");
+
+    let code = request("/admin/invite", &admin);
+    let code = String::from_utf8_lossy(code.body()).trim().to_string();
+    assert_eq!(request("/", &herp).status(), Code::UNAUTHORIZED);
+    assert_eq!(request("/register?invite=nope", &herp).status(), Code::UNAUTHORIZED);
+    assert_eq!(request(&format!("/register?invite={}", code), &Id::new("derp", "x")).status(), Code::CONFLICT);
+    assert_eq!(request(&format!("/register?invite={}", code), &herp).status(), Code::CREATED);
+    assert_eq!(request("/", &herp).status(), Code::OK);
+    // Each code works once
+    assert_eq!(request(&format!("/register?invite={}", code), &Id::new("lerp", "x")).status(), Code::UNAUTHORIZED);
+    assert!(Users::load(&path).unwrap().allows(&herp));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn change_password() {
+    let routes = routes(&Config::default(), test_db(), Services::default());
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("derp", "blerp"));
+    let request = |method: &str, path: &str, id: &Id, body: &str| warp::test::request()
+        .method(method)
+        .path(path)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .header("content-length", body.len().to_string())
+        .body(body)
+        .reply(&routes)
+        .status();
+
+    assert_eq!(request("PUT", "/password", &old, "blerp"), Code::NOT_FOUND);
+    request("POST", "/", &old, "");
+    assert_eq!(request("PUT", "/password", &old, "bl:erp"), Code::BAD_REQUEST);
+    assert_eq!(request("PUT", "/password", &old, "blerp\n"), Code::NO_CONTENT);
+    assert_eq!(request("GET", "/", &new, ""), Code::OK);
+    assert_eq!(request("DELETE", "/", &old, ""), Code::NOT_FOUND);
+}
+
+#[test]
+fn change_password_conflict_keeps_file() {
+    let path = std::env::temp_dir().join(format!("d5-password-conflict-{}", std::process::id()));
+    std::fs::write(&path, "derp:flerp\n").unwrap();
+    let (old, new) = (Id::new("derp", "flerp"), Id::new("derp", "blerp"));
+    let db = test_db();
+    db::upsert(&mut db.write(), old.clone(), "1.1.1.1".parse().unwrap(), 1);
+    db::upsert(&mut db.write(), new.clone(), "2.2.2.2".parse().unwrap(), 1);
+    let config = Config { credentials_file: Some(path.clone()), ..Config::default() };
+    let services = Services { users: Arc::new(RwLock::new(Some(Users::load(&path).unwrap()))), ..Services::default() };
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/password")
+        .header("authorization", old.basic())
+        .header("content-length", "5")
+        .body("blerp")
+        .reply(&routes(&config, db.clone(), services));
+
+    assert_eq!(res.status(), Code::CONFLICT);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "derp:flerp\n");
+    assert_eq!(db.read()[&old].ip.to_string(), "1.1.1.1");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rate_limit() {
+    let services = Services { rate_limit: Some(RateLimit::new(2)), ..Services::default() };
+    let routes = routes(&Config::default(), test_db(), services);
+    let get = |ip: &str| warp::test::request().header("x-forwarded-for", ip).reply(&routes).status();
+
+    assert_eq!(get("1.1.1.1"), Code::OK);
+    assert_eq!(get("1.1.1.1"), Code::OK);
+    assert_eq!(get("1.1.1.1"), Code::TOO_MANY_REQUESTS);
+    assert_eq!(get("2.2.2.2"), Code::OK);
+}
+
+#[test]
+fn ratelimit_exempt_networks() {
+    let config = Config { keys: vec![Id::new("derp", "flerp")], ratelimit_exempt: vec!["10.0.0.0/8".parse().unwrap()], ..Config::default() };
+    let services = Services { rate_limit: Some(RateLimit::new(1)), lockout: Some(Lockout::new(1, 60)), ..Services::default() };
+    let routes = routes(&config, Arc::new(RwLock::new(Records::new().with_keys(config.keys.clone()))), services);
+    let request = |ip: &str, id: &Id| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", ip)
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+    let (right, wrong) = (Id::new("derp", "flerp"), Id::new("derp", "blerp"));
+
+    // The limiter is saturated for everyone but the exempt network
+    assert_eq!(request("1.1.1.1", &right), Code::OK);
+    assert_eq!(request("1.1.1.1", &right), Code::TOO_MANY_REQUESTS);
+    for _ in 0..3 {
+        assert_eq!(request("10.1.2.3", &right), Code::OK);
+    }
+    // ...and its failures don't lock anyone out
+    assert_eq!(request("10.1.2.3", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(request("10.1.2.3", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(request("10.1.2.3", &right), Code::OK);
+    assert_eq!(request("2.2.2.2", &right), Code::OK);
+}
+
+#[test]
+fn lockout() {
+    let config = Config { keys: vec![Id::new("derp", "flerp")], ..Config::default() };
+    let services = Services { lockout: Some(Lockout::new(2, 60)), ..Services::default() };
+    let routes = routes(&config, Arc::new(RwLock::new(Records::new().with_keys(config.keys.clone()))), services);
+    let post = |ip: &str, id: &Id| warp::test::request()
+        .method("POST")
+        .header("x-forwarded-for", ip)
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+    let (right, wrong) = (Id::new("derp", "flerp"), Id::new("derp", "blerp"));
+
+    assert_eq!(post("1.1.1.1", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(post("1.1.1.1", &right), Code::OK);
+    // Success resets the count
+    assert_eq!(post("1.1.1.1", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(post("2.2.2.2", &wrong), Code::UNAUTHORIZED);
+    // The user is now locked out, even with the right password
+    assert_eq!(post("2.2.2.2", &right), Code::TOO_MANY_REQUESTS);
+    assert_eq!(post("1.1.1.1", &Id::new("herp", "flerp")), Code::UNAUTHORIZED);
+    assert_eq!(post("1.1.1.1", &Id::new("herp", "flerp")), Code::TOO_MANY_REQUESTS);
+    assert_eq!(post("3.3.3.3", &Id::new("herp", "flerp")), Code::UNAUTHORIZED);
+}
+
+#[test]
+fn lockout_not_cleared_by_show() {
+    let config = Config { keys: vec![Id::new("derp", "flerp")], ..Config::default() };
+    let services = Services { lockout: Some(Lockout::new(2, 60)), ..Services::default() };
+    let routes = routes(&config, Arc::new(RwLock::new(Records::new().with_keys(config.keys.clone()))), services);
+    let request = |method: &str, id: &Id| warp::test::request()
+        .method(method)
+        .header("x-forwarded-for", "1.1.1.1")
+        .header("authorization", id.basic())
+        .reply(&routes)
+        .status();
+    let wrong = Id::new("derp", "blerp");
+
+    // Wrong credentials aren't shown past, and count as a failure
+    assert_eq!(request("GET", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(request("GET", &wrong), Code::UNAUTHORIZED);
+    assert_eq!(request("GET", &Id::new("derp", "flerp")), Code::TOO_MANY_REQUESTS);
+    assert_eq!(request("POST", &Id::new("derp", "flerp")), Code::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn www_authenticate() {
+    let config = Config { keys: vec![Id::new("derp", "flerp")], ..Config::default() };
+    let routes = routes(&config, Arc::new(RwLock::new(Records::new().with_keys(config.keys.clone()))), Services::default());
+    let request = |method: &str, auth: Option<&Id>| {
+        let request = warp::test::request().method(method).header("x-forwarded-for", "1.1.1.1");
+        match auth {
+            Some(id) => request.header("authorization", id.basic()),
+            None => request,
+        }
+        .reply(&routes)
+    };
+
+    let res = request("POST", Some(&Id::new("derp", "blerp")));
+    assert_eq!(res.status(), Code::UNAUTHORIZED);
+    assert_eq!(res.headers()["www-authenticate"], "Basic realm=\"d5\"");
+    let res = request("POST", None);
+    assert_eq!(res.status(), Code::UNAUTHORIZED);
+    assert_eq!(res.headers()["www-authenticate"], "Basic realm=\"d5\"");
+
+    // An unauthenticated GET still just echoes the caller's IP
+    let res = request("GET", None);
+    assert_eq!(res.status(), Code::OK);
+    assert!(res.headers().get("www-authenticate").is_none());
+    assert!(request("POST", Some(&Id::new("derp", "flerp"))).headers().get("www-authenticate").is_none());
+}
+
+#[test]
+fn https_redirect() {
+    let config = Config { https_redirect: true, ..Config::default() };
+    let routes = routes(&config, test_db(), Services::default());
+    let request = |method: &str, proto: &str| warp::test::request()
+        .method(method)
+        .path("/admin/nsupdate?zone=example.com")
+        .header("host", "d5.example.com")
+        .header("x-forwarded-proto", proto)
+        .header("x-forwarded-for", "1.1.1.1")
+        .reply(&routes);
+
+    let res = request("GET", "http");
+    assert_eq!(res.status(), Code::MOVED_PERMANENTLY);
+    assert_eq!(res.headers()["location"], "https://d5.example.com/admin/nsupdate?zone=example.com");
+    assert_eq!(request("POST", "http").status(), Code::PERMANENT_REDIRECT);
+    assert_eq!(request("GET", "https").status(), Code::UNAUTHORIZED);
+}
@@ -0,0 +1,84 @@
+use std::{iter, net::IpAddr};
+
+use crate::admin;
+use crate::db::Records;
+#[cfg(test)]
+use crate::{db, id::Id};
+
+/// Default cap on the number of records exposed as metrics
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// Render a `d5_entry_info` series per stored address and a
+/// `d5_entry_age_seconds` series per record, for at most `max` records
+pub fn entries(db: &Records, now: u64, max: usize) -> String {
+    let records = admin::list(db);
+    let shown = &records[..records.len().min(max)];
+
+    let mut out = String::new();
+    out.push_str("# HELP d5_entry_info A stored record.\n");
+    out.push_str("# TYPE d5_entry_info gauge\n");
+    for (user, e) in shown {
+        for ip in iter::once(e.ip).chain(e.other_ip) {
+            let family = match ip {
+                IpAddr::V4(_) => "v4",
+                IpAddr::V6(_) => "v6",
+            };
+            out.push_str(&format!(
+                "d5_entry_info{{user=\"{}\",uuid=\"{}\",ip=\"{}\",family=\"{}\"}} 1\n",
+                escape(user), e.uuid, ip, family,
+            ));
+        }
+    }
+
+    out.push_str("# HELP d5_entry_age_seconds Seconds since the record was last updated.\n");
+    out.push_str("# TYPE d5_entry_age_seconds gauge\n");
+    for (user, e) in shown {
+        out.push_str(&format!(
+            "d5_entry_age_seconds{{user=\"{}\",uuid=\"{}\"}} {}\n",
+            escape(user), e.uuid, now.saturating_sub(e.updated_at),
+        ));
+    }
+
+    out.push_str("# HELP d5_entries_omitted Records left out by the cardinality cap.\n");
+    out.push_str("# TYPE d5_entries_omitted gauge\n");
+    out.push_str(&format!("d5_entries_omitted {}\n", records.len() - shown.len()));
+    out
+}
+
+/// Escape a Prometheus label value
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+fn seed(n: usize) -> Records {
+    let mut records = Records::new();
+    for i in 0..n {
+        db::upsert(&mut records, Id::new(&format!("user{}", i), "pass"), format!("10.0.0.{}", i).parse().unwrap(), 100);
+    }
+    records
+}
+
+#[test]
+fn entry_metrics() {
+    let mut db = seed(1);
+    db::upsert(&mut db, Id::new("v\"6", "pass"), "::1".parse().unwrap(), 50);
+    let metrics = entries(&db, 160, DEFAULT_MAX_ENTRIES);
+    let uuid = &db[&Id::new("user0", "pass")].uuid;
+
+    assert!(metrics.contains(&format!(
+        "d5_entry_info{{user=\"user0\",uuid=\"{}\",ip=\"10.0.0.0\",family=\"v4\"}} 1\n", uuid
+    )));
+    assert!(metrics.contains(&format!("d5_entry_age_seconds{{user=\"user0\",uuid=\"{}\"}} 60\n", uuid)));
+    assert!(metrics.contains("user=\"v\\\"6\""));
+    assert!(metrics.contains("ip=\"::1\",family=\"v6\"} 1\n"));
+    assert!(metrics.contains("d5_entries_omitted 0\n"));
+}
+
+#[test]
+fn entry_metrics_cap() {
+    let metrics = entries(&seed(5), 100, 3);
+    assert_eq!(metrics.matches("d5_entry_info{").count(), 3);
+    assert_eq!(metrics.matches("d5_entry_age_seconds{").count(), 3);
+    assert!(metrics.contains("d5_entries_omitted 2\n"));
+}
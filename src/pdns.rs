@@ -0,0 +1,70 @@
+use serde_json::{json, Value};
+
+use crate::acme::Accounts;
+use crate::db::Records;
+use crate::dns::{Data, Zone};
+
+/// Answer `method` of the HTTP connector of PowerDNS's remote backend, called
+/// with the rest of the path split into `args`.  Other methods, and zones
+/// other than `zone`, get `false`, which PowerDNS takes as having no answer.
+pub fn call(zone: &Zone, db: &Records, acme: &Accounts, method: &str, args: &[&str], now: u64) -> Value {
+    let result = match (method, args) {
+        ("lookup", [qname, qtype]) => {
+            let name = qname.trim_end_matches('.').to_ascii_lowercase();
+            zone.records(db, acme, &name, now).unwrap_or_default().iter()
+                .filter(|data| qtype.eq_ignore_ascii_case("ANY") || qtype.eq_ignore_ascii_case(data.type_name()))
+                .map(|data| record(zone, qname, data))
+                .collect()
+        }
+        ("list", [_, name]) if name.trim_end_matches('.').eq_ignore_ascii_case(&zone.origin) => {
+            zone.names(db, acme, now).iter()
+                .flat_map(|name| {
+                    let records = zone.records(db, acme, name, now).unwrap_or_default();
+                    records.into_iter().map(move |data| record(zone, &format!("{}.", name), &data))
+                })
+                .collect()
+        }
+        _ => Value::Bool(false),
+    };
+    json!({ "result": result })
+}
+
+/// A resource record, as the remote backend exchanges them
+fn record(zone: &Zone, qname: &str, data: &Data) -> Value {
+    json!({ "qtype": data.type_name(), "qname": qname, "content": data.content(zone), "ttl": zone.ttl, "auth": true })
+}
+
+#[test]
+fn remote_backend() {
+    use crate::db;
+    use crate::id::Id;
+
+    let mut db = Records::new();
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 10);
+    db::set_host(&mut db, derp, "vps", "2001:db8::1".parse().unwrap(), 20, None).unwrap();
+    let zone = Zone::new("dyn.example.com", None, None, 60);
+    let acme = Accounts::default();
+    let call = |method: &str, args: &[&str]| call(&zone, &db, &acme, method, args, 30)["result"].clone();
+
+    assert_eq!(call("lookup", &["DERP.dyn.example.com.", "ANY"]), json!([
+        { "qtype": "A", "qname": "DERP.dyn.example.com.", "content": "1.1.1.1", "ttl": 60, "auth": true },
+    ]));
+    assert_eq!(call("lookup", &["derp.dyn.example.com.", "AAAA"]), json!([]));
+    assert_eq!(call("lookup", &["nobody.dyn.example.com.", "A"]), json!([]));
+    assert_eq!(call("lookup", &["dyn.example.com.", "SOA"])[0]["content"], "ns.dyn.example.com. hostmaster.dyn.example.com. 20 3600 600 86400 60");
+
+    let list = call("list", &["-1", "dyn.example.com."]);
+    let names = list.as_array().unwrap().iter()
+        .map(|rr| format!("{} {} {}", rr["qname"].as_str().unwrap(), rr["qtype"].as_str().unwrap(), rr["content"].as_str().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![
+        "dyn.example.com. SOA ns.dyn.example.com. hostmaster.dyn.example.com. 20 3600 600 86400 60",
+        "dyn.example.com. NS ns.dyn.example.com.",
+        "derp.dyn.example.com. A 1.1.1.1",
+        "vps.derp.dyn.example.com. AAAA 2001:db8::1",
+    ]);
+
+    assert_eq!(call("list", &["-1", "example.org."]), json!(false));
+    assert_eq!(call("getDomainMetadata", &["dyn.example.com.", "PRESIGNED"]), json!(false));
+}
@@ -0,0 +1,97 @@
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use parking_lot::Mutex;
+
+/// How many clients to track before forgetting those whose buckets have
+/// refilled, and then the least recent
+const MAX_TRACKED: usize = 10_000;
+
+/// A token bucket per client IP, holding up to `per_minute` requests and
+/// refilling at `per_minute` requests a minute
+#[derive(Debug, Clone)]
+pub struct RateLimit(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    per_minute: u32,
+    buckets: HashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimit {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimit(Arc::new(Mutex::new(State { per_minute, buckets: HashMap::new() })))
+    }
+
+    /// Take a request from `ip`'s bucket at `now`, returning whether it had
+    /// one left
+    pub fn allow(&self, ip: &str, now: Instant) -> bool {
+        let mut state = self.0.lock();
+        let capacity = f64::from(state.per_minute);
+        let refill = |b: &Bucket| {
+            let elapsed = now.saturating_duration_since(b.updated_at).as_secs_f64();
+            (b.tokens + elapsed * capacity / 60.0).min(capacity)
+        };
+        if state.buckets.len() >= MAX_TRACKED && !state.buckets.contains_key(ip) {
+            state.buckets.retain(|_, b| refill(b) < capacity);
+            if state.buckets.len() >= MAX_TRACKED {
+                // Forget a tenth at once, so the sort isn't repeated every request
+                let mut oldest = state.buckets.iter().map(|(ip, b)| (b.updated_at, ip.clone())).collect::<Vec<_>>();
+                oldest.sort_unstable();
+                for (_, ip) in oldest.into_iter().take(MAX_TRACKED / 10) {
+                    state.buckets.remove(&ip);
+                }
+            }
+        }
+        let bucket = state.buckets.entry(ip.to_string())
+            .or_insert(Bucket { tokens: capacity, updated_at: now });
+        bucket.tokens = refill(bucket);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[test]
+fn token_bucket() {
+    use std::time::Duration;
+
+    let limit = RateLimit::new(2);
+    let start = Instant::now();
+    assert!(limit.allow("1.1.1.1", start));
+    assert!(limit.allow("1.1.1.1", start));
+    assert!(!limit.allow("1.1.1.1", start));
+    assert!(limit.allow("2.2.2.2", start));
+
+    // One request comes back every 30 seconds
+    assert!(!limit.allow("1.1.1.1", start + Duration::from_secs(29)));
+    assert!(limit.allow("1.1.1.1", start + Duration::from_secs(31)));
+    assert!(!limit.allow("1.1.1.1", start + Duration::from_secs(32)));
+    assert!(limit.allow("1.1.1.1", start + Duration::from_secs(600)));
+    assert!(limit.allow("1.1.1.1", start + Duration::from_secs(600)));
+    assert!(!limit.allow("1.1.1.1", start + Duration::from_secs(600)));
+}
+
+#[test]
+fn tracked_clients_capped() {
+    use std::time::Duration;
+
+    // No bucket refills within the test, so only the cap can forget one
+    let limit = RateLimit::new(1);
+    let start = Instant::now();
+    assert!(limit.allow("1.1.1.1", start));
+    for n in 0..MAX_TRACKED as u64 {
+        limit.allow(&n.to_string(), start + Duration::from_millis(n + 1));
+    }
+    let buckets = &limit.0.lock().buckets;
+    assert!(buckets.len() <= MAX_TRACKED);
+    assert!(!buckets.contains_key("1.1.1.1"));
+}
@@ -0,0 +1,29 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How often to check for a reload request
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set by the signal handler
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Run `reload` on every `SIGHUP`, without interrupting the server
+pub fn on_signal_reload<F: FnMut() + Send + 'static>(mut reload: F) {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if REQUESTED.swap(false, Ordering::SeqCst) {
+            reload();
+        }
+    });
+}
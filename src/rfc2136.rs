@@ -0,0 +1,241 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::db;
+use crate::dns::{encode_name, Data};
+
+/// The only TSIG algorithm the sha-1 crate lets d5 sign with
+const ALGORITHM: &str = "hmac-sha1";
+/// How far the server's clock may be from d5's, in seconds
+const FUDGE: u16 = 300;
+/// How long to wait for the server's answer to each attempt
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// How many times to send an update before giving up
+const ATTEMPTS: usize = 3;
+
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_SOA: u16 = 6;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+/// A TSIG key, written `[hmac-sha1:]NAME:SECRET` as `nsupdate -y` takes it,
+/// with the secret in base64
+#[derive(Clone, PartialEq)]
+pub struct TsigKey {
+    name: String,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TsigKey").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl FromStr for TsigKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let parts = s.trim().split(':').collect::<Vec<_>>();
+        let (name, secret) = match parts.as_slice() {
+            [algorithm, name, secret] if algorithm.eq_ignore_ascii_case(ALGORITHM) => (name, secret),
+            [algorithm, _, _] => return Err(format!("unsupported algorithm '{}'; expected `{}`", algorithm, ALGORITHM)),
+            [name, secret] => (name, secret),
+            _ => return Err("expected `[hmac-sha1:]NAME:SECRET`".to_string()),
+        };
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() || encode_name(&name).is_none() {
+            return Err("invalid key name".to_string());
+        }
+        let secret = base64::decode(secret).map_err(|_| "invalid base64 secret".to_string())?;
+        Ok(TsigKey { name, secret })
+    }
+}
+
+/// An authoritative server that address changes are pushed to as RFC 2136
+/// UPDATE messages, replacing `USER.ZONE`'s record of the new address's
+/// family
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    server: SocketAddr,
+    zone: String,
+    ttl: u32,
+    key: Option<TsigKey>,
+}
+
+impl Upstream {
+    pub fn new(server: SocketAddr, zone: &str, ttl: u32, key: Option<TsigKey>) -> Self {
+        Upstream { server, zone: zone.trim().trim_end_matches('.').to_ascii_lowercase(), ttl, key }
+    }
+
+    /// Send the update for `user`'s new `ip` on a background thread, retrying
+    /// if the server doesn't answer and logging if it refuses
+    pub fn push(&self, user: &str, ip: IpAddr) -> JoinHandle<()> {
+        let (upstream, user) = (self.clone(), user.to_string());
+        thread::spawn(move || {
+            let id = rand::random();
+            let message = match upstream.message(id, &user, ip, db::now()) {
+                Some(message) => message,
+                None => return eprintln!("[NSUPDATE] USER:{} isn't a valid DNS label", user),
+            };
+            match upstream.send(id, &message) {
+                Ok(0) => {}
+                Ok(rcode) => eprintln!("[NSUPDATE] USER:{} refused by {}: RCODE {}", user, upstream.server, rcode),
+                Err(e) => eprintln!("[NSUPDATE] USER:{} failed: {}", user, e),
+            }
+        })
+    }
+
+    /// Send `message`, returning the RCODE of the server's answer
+    fn send(&self, id: u16, message: &[u8]) -> io::Result<u16> {
+        let bind: SocketAddr = if self.server.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.connect(self.server)?;
+        let mut buf = [0; 512];
+        for _ in 0..ATTEMPTS {
+            socket.send(message)?;
+            match socket.recv(&mut buf) {
+                // A TSIG error is reported in the TSIG record, with RCODE
+                // NOTAUTH in the header
+                Ok(len) if len >= 4 && buf[..2] == id.to_be_bytes() => return Ok(u16::from(buf[3] & 0x0f)),
+                Ok(_) => continue,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer from {}", self.server)))
+    }
+
+    /// The UPDATE replacing `user`'s record of `ip`'s family, signed at `now`
+    /// when there is a key; `None` if `user.ZONE` isn't a valid name
+    fn message(&self, id: u16, user: &str, ip: IpAddr, now: u64) -> Option<Vec<u8>> {
+        let user = user.to_ascii_lowercase();
+        if !db::valid_host_name(&user) {
+            return None;
+        }
+        let name = encode_name(&format!("{}.{}", user, self.zone))?;
+        let rtype = Data::Addr(ip).rtype();
+        let rdata = match ip {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        };
+        let mut message = id.to_be_bytes().to_vec();
+        // One zone, no prerequisites, two updates
+        for n in [OPCODE_UPDATE, 1, 0, 2, 0] {
+            message.extend_from_slice(&n.to_be_bytes());
+        }
+        message.extend(encode_name(&self.zone)?);
+        message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        // Delete the RRset, then add the new address
+        message.extend(record(&name, rtype, CLASS_ANY, 0, &[]));
+        message.extend(record(&name, rtype, CLASS_IN, self.ttl, &rdata));
+
+        if let Some(key) = &self.key {
+            let tsig = key.sign(&message, now)?;
+            message[10..12].copy_from_slice(&1u16.to_be_bytes());
+            message.extend(tsig);
+        }
+        Some(message)
+    }
+}
+
+impl TsigKey {
+    /// The TSIG record signing `message`, per RFC 8945
+    fn sign(&self, message: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (name, algorithm) = (encode_name(&self.name)?, encode_name(ALGORITHM)?);
+        let time = &now.to_be_bytes()[2..];
+        let mut signed = message.to_vec();
+        signed.extend_from_slice(&name);
+        signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        signed.extend_from_slice(&0u32.to_be_bytes());
+        signed.extend_from_slice(&algorithm);
+        signed.extend_from_slice(time);
+        signed.extend_from_slice(&FUDGE.to_be_bytes());
+        // No error and no other data
+        signed.extend_from_slice(&[0; 4]);
+        let mac = hmac_sha1(&self.secret, &signed);
+
+        let mut rdata = algorithm;
+        rdata.extend_from_slice(time);
+        rdata.extend_from_slice(&FUDGE.to_be_bytes());
+        rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&mac);
+        rdata.extend_from_slice(&message[..2]);
+        rdata.extend_from_slice(&[0; 4]);
+        Some(record(&name, TYPE_TSIG, CLASS_ANY, 0, &rdata))
+    }
+}
+
+fn record(name: &[u8], rtype: u16, class: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+    let mut out = name.to_vec();
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+    out
+}
+
+/// HMAC-SHA1 (RFC 2104) of `message` under `key`
+fn hmac_sha1(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..20].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<_>>();
+    let inner = Sha1::new().chain(pad(0x36)).chain(message).result();
+    Sha1::new().chain(pad(0x5c)).chain(inner).result().to_vec()
+}
+
+#[test]
+fn hmac_sha1_vectors() {
+    let hex = |bytes: Vec<u8>| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    // RFC 2202, test cases 1, 2 and 6
+    assert_eq!(hex(hmac_sha1(&[0x0b; 20], b"Hi There")), "b617318655057264e28bc0b6fb378c8ef146be00");
+    assert_eq!(hex(hmac_sha1(b"Jefe", b"what do ya want for nothing?")), "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+    assert_eq!(
+        hex(hmac_sha1(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+        "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+    );
+}
+
+#[test]
+fn update_message() {
+    let key = "hmac-sha1:d5-key.:aGVsbG8=".parse::<TsigKey>().unwrap();
+    assert_eq!(key, "d5-key:aGVsbG8=".parse().unwrap());
+    assert!("hmac-sha256:d5:aGVsbG8=".parse::<TsigKey>().is_err());
+    assert!("d5:not base64".parse::<TsigKey>().is_err());
+    assert!(format!("{}:aGVsbG8=", "k".repeat(64)).parse::<TsigKey>().is_err());
+
+    let upstream = Upstream::new(([127, 0, 0, 1], 53).into(), "Dyn.Example.com.", 60, None);
+    let message = upstream.message(0xbeef, "derp", "1.1.1.1".parse().unwrap(), 0).unwrap();
+    let mut expected = vec![0xbe, 0xef, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0];
+    expected.extend(encode_name("dyn.example.com").unwrap());
+    expected.extend_from_slice(&[0, 6, 0, 1]);
+    expected.extend(record(&encode_name("derp.dyn.example.com").unwrap(), 1, CLASS_ANY, 0, &[]));
+    expected.extend(record(&encode_name("derp.dyn.example.com").unwrap(), 1, CLASS_IN, 60, &[1, 1, 1, 1]));
+    assert_eq!(message, expected);
+    assert_eq!(upstream.message(0xbeef, "x\nsend", "1.1.1.1".parse().unwrap(), 0), None);
+
+    // Signing appends a TSIG record over the unsigned message
+    let signed = Upstream { key: Some(key.clone()), ..upstream }.message(0xbeef, "derp", "1.1.1.1".parse().unwrap(), 1_000_000).unwrap();
+    assert_eq!(signed[10..12], [0, 1]);
+    assert_eq!(signed[12..expected.len()], expected[12..]);
+    assert_eq!(signed[expected.len()..], key.sign(&expected, 1_000_000).unwrap()[..]);
+    let tsig = &signed[expected.len()..];
+    assert!(tsig.starts_with(&encode_name("d5-key").unwrap()));
+    let algorithm = encode_name(ALGORITHM).unwrap();
+    assert!(tsig.windows(algorithm.len()).any(|w| w == algorithm.as_slice()));
+}
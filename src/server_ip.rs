@@ -0,0 +1,87 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use futures::{Future, Stream};
+use parking_lot::RwLock;
+use tokio::prelude::FutureExt;
+
+/// How long to wait for the detection service to answer
+const DETECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The server's own public IP, as last detected
+#[derive(Debug, Clone, Default)]
+pub struct ServerIp(Arc<RwLock<Option<IpAddr>>>);
+
+impl ServerIp {
+    pub fn get(&self) -> Option<IpAddr> {
+        *self.0.read()
+    }
+
+    /// Run `detect` now, keeping the last known IP if detection fails
+    pub fn refresh<F: Fn() -> Option<IpAddr>>(&self, detect: F) {
+        if let Some(ip) = detect() {
+            *self.0.write() = Some(ip);
+        }
+    }
+
+    /// Detect the IP on a background thread, then again every `interval`
+    pub fn spawn<F>(&self, interval: Option<Duration>, detect: F)
+    where
+        F: Fn() -> Option<IpAddr> + Send + 'static,
+    {
+        let server_ip = self.clone();
+        thread::spawn(move || loop {
+            server_ip.refresh(&detect);
+            match interval {
+                Some(interval) => thread::sleep(interval),
+                None => break,
+            }
+        });
+    }
+}
+
+/// Ask an "echo my IP" service (e.g. `http://api.ipify.org`) for our address
+pub fn detect(url: &str) -> Option<IpAddr> {
+    let request = hyper::Client::new()
+        .get(url.parse().ok()?)
+        .and_then(|res| res.into_body().concat2())
+        .timeout(DETECT_TIMEOUT);
+    let body = tokio::runtime::current_thread::Runtime::new().ok()?.block_on(request).ok()?;
+    String::from_utf8_lossy(&body).trim().parse().ok()
+}
+
+#[test]
+fn refresh_keeps_last_known() {
+    let server_ip = ServerIp::default();
+    assert_eq!(server_ip.get(), None);
+
+    server_ip.refresh(|| "1.1.1.1".parse().ok());
+    assert_eq!(server_ip.get(), "1.1.1.1".parse().ok());
+    server_ip.refresh(|| None);
+    assert_eq!(server_ip.get(), "1.1.1.1".parse().ok());
+}
+
+#[test]
+fn spawn_refreshes_on_interval() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let server_ip = ServerIp::default();
+    server_ip.spawn(Some(Duration::from_millis(10)), move || {
+        let n = counter.fetch_add(1, Ordering::SeqCst);
+        format!("10.0.0.{}", n.min(2)).parse().ok()
+    });
+
+    let start = Instant::now();
+    while server_ip.get() != "10.0.0.2".parse().ok() {
+        assert!(start.elapsed() < Duration::from_secs(5), "server IP was never refreshed");
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(calls.load(Ordering::SeqCst) >= 3);
+}
@@ -0,0 +1,124 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use warp::{reject::custom as warp_err, Filter, Rejection};
+
+use crate::Err::Unavailable;
+
+/// Exit code used when in-flight requests outlive `SHUTDOWN_TIMEOUT`
+pub const EXIT_SHUTDOWN_TIMEOUT: i32 = 3;
+
+/// How often to check for a shutdown signal or for drained requests
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Set by the signal handler
+static SIGNALED: AtomicBool = AtomicBool::new(false);
+
+/// Tracks in-flight requests so shutdown can wait for them to finish
+#[derive(Debug, Clone, Default)]
+pub struct Drain(Arc<State>);
+
+#[derive(Debug, Default)]
+struct State {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// Counts a request as in flight until dropped
+#[derive(Debug)]
+pub struct Guard(Arc<State>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drain {
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Start counting a request, or `None` once draining has started
+    pub fn track(&self) -> Option<Guard> {
+        self.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = Guard(self.0.clone());
+        Some(guard).filter(|_| !self.0.draining.load(Ordering::SeqCst))
+    }
+
+    /// A filter extracting a `Guard` for each request, or rejecting with
+    /// `Unavailable` while draining
+    pub fn filter(&self) -> impl Filter<Extract = (Guard,), Error = Rejection> + Clone {
+        let drain = self.clone();
+        warp::any().and_then(move || drain.track().ok_or_else(|| warp_err(Unavailable)))
+    }
+
+    /// Refuse new requests and wait up to `timeout` for in-flight ones to
+    /// finish, returning the number abandoned if they don't
+    pub fn drain(&self, timeout: Duration) -> Result<(), usize> {
+        self.0.draining.store(true, Ordering::SeqCst);
+        let start = Instant::now();
+        loop {
+            match self.in_flight() {
+                0 => return Ok(()),
+                n if start.elapsed() >= timeout => return Err(n),
+                _ => thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALED.store(true, Ordering::SeqCst);
+}
+
+/// On `SIGINT` or `SIGTERM`, drain `drain` for up to `timeout`, run `flush`
+/// to persist state, then exit
+pub fn on_signal_drain<F>(drain: Drain, timeout: Duration, flush: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    thread::spawn(move || {
+        while !SIGNALED.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        eprintln!("Shutting down; waiting for {} in-flight requests", drain.in_flight());
+        let drained = drain.drain(timeout);
+        flush();
+        match drained {
+            Ok(()) => {
+                eprintln!("Shut down cleanly");
+                std::process::exit(0)
+            }
+            Err(n) => {
+                eprintln!("Shutdown timed out; abandoning {} in-flight requests", n);
+                std::process::exit(EXIT_SHUTDOWN_TIMEOUT);
+            }
+        }
+    });
+}
+
+#[test]
+fn drain_refuses_new_requests() {
+    let drain = Drain::default();
+    let guard = drain.track().unwrap();
+    assert_eq!(drain.in_flight(), 1);
+
+    assert_eq!(drain.drain(Duration::from_millis(10)), Err(1));
+    assert!(drain.track().is_none());
+    assert_eq!(drain.in_flight(), 1);
+
+    drop(guard);
+    assert_eq!(drain.drain(Duration::from_millis(10)), Ok(()));
+}
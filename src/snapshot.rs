@@ -0,0 +1,243 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use crate::db::{self, Change, Entry, Host, Records, Token};
+use crate::id::Id;
+
+/// A record in the form it is persisted in, credentials included
+pub fn record(id: &Id, e: &Entry) -> Value {
+    json!({
+        "user": id.user,
+        "password": id.password,
+        "ip": e.ip,
+        "other_ip": e.other_ip,
+        "uuid": e.uuid,
+        "created_at": e.created_at,
+        "updated_at": e.updated_at,
+        "updated_from": e.updated_from,
+        "deleted_at": e.deleted_at,
+        "last_auth": e.last_auth,
+        "tokens": e.tokens.iter()
+            .map(|t| json!({ "id": t.id, "hash": t.hash, "created_at": t.created_at }))
+            .collect::<Vec<_>>(),
+        "update_key": e.update_key,
+        "hosts": e.hosts.iter()
+            .map(|(name, h)| (name.clone(), json!({ "ip": h.ip, "other_ip": h.other_ip, "updated_at": h.updated_at })))
+            .collect::<serde_json::Map<_, _>>(),
+        "history": e.history.iter().map(|c| json!({ "ip": c.ip, "at": c.at })).collect::<Vec<_>>(),
+    })
+}
+
+/// Parse a record written by `record`
+pub fn parse_record(v: &Value) -> Option<(Id, Entry)> {
+    let id = Id::new(v["user"].as_str()?, v["password"].as_str()?);
+    let updated_at = v["updated_at"].as_u64()?;
+    let entry = Entry {
+        ip: v["ip"].as_str()?.parse().ok()?,
+        other_ip: optional_ip(v, "other_ip")?,
+        uuid: v["uuid"].as_str()?.to_string(),
+        created_at: v["created_at"].as_u64().unwrap_or(updated_at),
+        updated_at,
+        updated_from: optional_ip(v, "updated_from")?,
+        deleted_at: v["deleted_at"].as_u64(),
+        last_auth: v["last_auth"].as_u64()?,
+        previous_key: None,
+        tokens: match v.get("tokens") {
+            Some(tokens) => tokens.as_array()?.iter().map(parse_token).collect::<Option<_>>()?,
+            None => Vec::new(),
+        },
+        update_key: v["update_key"].as_str().map(String::from),
+        hosts: match v.get("hosts") {
+            Some(hosts) => hosts.as_object()?.iter()
+                .map(|(name, h)| Some((name.clone(), parse_host(h)?)))
+                .collect::<Option<_>>()?,
+            None => Default::default(),
+        },
+        history: match v.get("history") {
+            Some(history) => history.as_array()?.iter().map(parse_change).collect::<Option<_>>()?,
+            None => Vec::new(),
+        },
+    };
+    Some((id, entry))
+}
+
+/// The optional IP under `key`, `None` if it is invalid
+fn optional_ip(v: &Value, key: &str) -> Option<Option<IpAddr>> {
+    match v.get(key) {
+        Some(Value::Null) | None => Some(None),
+        Some(ip) => Some(Some(ip.as_str()?.parse().ok()?)),
+    }
+}
+
+fn parse_change(v: &Value) -> Option<Change> {
+    Some(Change { ip: v["ip"].as_str()?.parse().ok()?, at: v["at"].as_u64()? })
+}
+
+fn parse_host(v: &Value) -> Option<Host> {
+    Some(Host {
+        ip: v["ip"].as_str()?.parse().ok()?,
+        other_ip: optional_ip(v, "other_ip")?,
+        updated_at: v["updated_at"].as_u64()?,
+    })
+}
+
+fn parse_token(v: &Value) -> Option<Token> {
+    Some(Token {
+        id: v["id"].as_str()?.to_string(),
+        hash: v["hash"].as_str()?.to_string(),
+        created_at: v["created_at"].as_u64()?,
+    })
+}
+
+/// Every record, sorted by user, as a JSON array
+pub fn to_json(db: &Records) -> Value {
+    let mut records = db.list().collect::<Vec<_>>();
+    records.sort_by(|a, b| a.0.user.cmp(&b.0.user).then(a.1.uuid.cmp(&b.1.uuid)));
+    records.into_iter().map(|(id, e)| record(id, e)).collect()
+}
+
+/// Create (or truncate) `path` for writing, readable only by its owner when
+/// created, since records hold their passwords in plain text
+pub fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+/// Marks the digest line `save` appends with `SNAPSHOT_CHECKSUM`.  It is
+/// SHA-1 rather than SHA-256, as `sha1` is the only hash crate d5 depends on.
+const DIGEST_PREFIX: &str = "sha1:";
+
+/// The hex SHA-1 digest of `bytes`
+fn digest(bytes: &[u8]) -> String {
+    Sha1::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write every record to `path`, replacing it only once the new snapshot is
+/// completely written.  With `checksum`, a line holding the digest of the
+/// records follows them.
+pub fn save(db: &Records, path: &Path, checksum: bool) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = create_private(Path::new(&tmp))?;
+    let json = to_json(db).to_string();
+    file.write_all(json.as_bytes())?;
+    if checksum {
+        write!(file, "\n{}{}\n", DIGEST_PREFIX, digest(json.as_bytes()))?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// The records of a saved snapshot, once any digest line is checked against
+/// them; with `checksum`, a snapshot without one is refused too
+fn verify(file: &[u8], checksum: bool) -> io::Result<&[u8]> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let trimmed = file.strip_suffix(b"\n").unwrap_or(file);
+    let split = trimmed.iter().rposition(|&b| b == b'\n').map(|n| (&trimmed[..n], &trimmed[n + 1..]));
+    match split {
+        Some((json, line)) if line.starts_with(DIGEST_PREFIX.as_bytes()) => {
+            if line[DIGEST_PREFIX.len()..] != *digest(json).as_bytes() {
+                return Err(invalid("integrity check failed: the digest doesn't match; the snapshot is truncated or corrupt"));
+            }
+            Ok(json)
+        }
+        _ if checksum => Err(invalid("integrity check failed: no digest; the snapshot is truncated or was saved without SNAPSHOT_CHECKSUM")),
+        _ => Ok(file),
+    }
+}
+
+/// Load the records saved at `path` into `db`; a missing file holds no
+/// records.  A snapshot whose digest doesn't match (or, with `checksum`, that
+/// has none) is refused; unreadable records are skipped with a warning.
+pub fn load(db: &mut Records, path: &Path, checksum: bool) -> io::Result<()> {
+    let file = match fs::read(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let records = match serde_json::from_slice::<Value>(verify(&file, checksum)?) {
+        Ok(Value::Array(records)) => records,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a JSON array of records")),
+    };
+    for (n, v) in records.iter().enumerate() {
+        match parse_record(v) {
+            Some((id, entry)) => db::restore(db, id, Some(entry)),
+            None => eprintln!("Skipping unreadable record {} in {}", n, path.display()),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn save_and_load() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("d5-snapshot-{}", std::process::id()));
+    let (derp, herp) = (Id::new("derp", "flerp"), Id::new("herp", "blerp"));
+
+    let mut db = Records::new();
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    db::upsert(&mut db, herp.clone(), "::1".parse().unwrap(), 2);
+    db::delete(&mut db, &herp, 3, Some(60));
+    let (_, token) = db::mint_token(&mut db, &derp, 4).unwrap();
+    db::set_host(&mut db, derp.clone(), "vps", "::2".parse().unwrap(), 5, None).unwrap();
+    let key = db::mint_update_key(&mut db, &derp).unwrap();
+    save(&db, &path, false).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let mut loaded = Records::indexed();
+    load(&mut loaded, &path, false).unwrap();
+    assert_eq!(loaded[&derp], db[&derp]);
+    assert_eq!(loaded[&herp], db[&herp]);
+    assert_eq!(loaded.user_ids("herp"), vec![&herp]);
+    assert_eq!(loaded.token_owner(&token), Some(&derp));
+    assert_eq!(loaded.update_key_owner(&key), Some(&derp));
+
+    fs::write(&path, "{}").unwrap();
+    assert!(load(&mut Records::new(), &path, false).is_err());
+    fs::remove_file(&path).unwrap();
+    assert!(load(&mut Records::new(), &path, false).is_ok());
+}
+
+#[test]
+fn checksummed_snapshot() {
+    let path = std::env::temp_dir().join(format!("d5-checksum-{}", std::process::id()));
+    let derp = Id::new("derp", "flerp");
+    let mut db = Records::new();
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 1);
+    db::upsert(&mut db, Id::new("herp", "blerp"), "::1".parse().unwrap(), 2);
+    save(&db, &path, true).unwrap();
+
+    let mut loaded = Records::new();
+    load(&mut loaded, &path, true).unwrap();
+    assert_eq!(loaded[&derp], db[&derp]);
+    // A digest is checked even when not required
+    load(&mut Records::new(), &path, false).unwrap();
+
+    // Cutting the file anywhere, even where the records still parse, is
+    // caught
+    let file = fs::read(&path).unwrap();
+    let json_len = to_json(&db).to_string().len();
+    for len in [file.len() - 2, json_len + 3, json_len, 10] {
+        fs::write(&path, &file[..len]).unwrap();
+        let err = load(&mut Records::new(), &path, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("integrity"), "{}", err);
+    }
+    // ...as is a changed record
+    fs::write(&path, String::from_utf8(file).unwrap().replace("1.1.1.1", "6.6.6.6")).unwrap();
+    assert!(load(&mut Records::new(), &path, false).unwrap_err().to_string().contains("integrity"));
+
+    // Without the option, a snapshot saved without a digest still loads
+    save(&db, &path, false).unwrap();
+    assert!(load(&mut Records::new(), &path, true).is_err());
+    load(&mut Records::new(), &path, false).unwrap();
+    fs::remove_file(&path).unwrap();
+}
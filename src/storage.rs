@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use crate::db::Entry;
+use crate::id::Id;
+
+/// Where the records themselves live.  `Records` keeps the index, key and
+/// journal on top of any `Storage`; the in-memory map is the default.
+pub trait Storage: Debug + Send + Sync {
+    fn get(&self, id: &Id) -> Option<&Entry>;
+
+    fn get_mut(&mut self, id: &Id) -> Option<&mut Entry>;
+
+    /// Store `entry` for `id`, returning the entry it replaced
+    fn put(&mut self, id: Id, entry: Entry) -> Option<Entry>;
+
+    /// Remove the entry for `id`, returning it
+    fn delete(&mut self, id: &Id) -> Option<Entry>;
+
+    /// Every stored `(id, entry)` pair, in no particular order
+    fn list(&self) -> Box<dyn Iterator<Item = (&Id, &Entry)> + '_>;
+
+    fn contains_key(&self, id: &Id) -> bool {
+        self.get(id).is_some()
+    }
+}
+
+impl Storage for HashMap<Id, Entry> {
+    fn get(&self, id: &Id) -> Option<&Entry> {
+        HashMap::get(self, id)
+    }
+
+    fn get_mut(&mut self, id: &Id) -> Option<&mut Entry> {
+        HashMap::get_mut(self, id)
+    }
+
+    fn put(&mut self, id: Id, entry: Entry) -> Option<Entry> {
+        self.insert(id, entry)
+    }
+
+    fn delete(&mut self, id: &Id) -> Option<Entry> {
+        self.remove(id)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = (&Id, &Entry)> + '_> {
+        Box::new(self.iter())
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct VecStorage(Vec<(Id, Entry)>);
+
+#[cfg(test)]
+impl Storage for VecStorage {
+    fn get(&self, id: &Id) -> Option<&Entry> {
+        self.0.iter().find(|(i, _)| i == id).map(|(_, e)| e)
+    }
+
+    fn get_mut(&mut self, id: &Id) -> Option<&mut Entry> {
+        self.0.iter_mut().find(|(i, _)| i == id).map(|(_, e)| e)
+    }
+
+    fn put(&mut self, id: Id, entry: Entry) -> Option<Entry> {
+        let previous = self.delete(&id);
+        self.0.push((id, entry));
+        previous
+    }
+
+    fn delete(&mut self, id: &Id) -> Option<Entry> {
+        let n = self.0.iter().position(|(i, _)| i == id)?;
+        Some(self.0.remove(n).1)
+    }
+
+    fn list(&self) -> Box<dyn Iterator<Item = (&Id, &Entry)> + '_> {
+        Box::new(self.0.iter().map(|(id, e)| (id, e)))
+    }
+}
+
+#[test]
+fn alternative_backend() {
+    use crate::db::{self, Records};
+
+    let mut db = Records::with_storage(VecStorage::default());
+    let derp = Id::new("derp", "flerp");
+    db::upsert(&mut db, derp.clone(), "1.1.1.1".parse().unwrap(), 0);
+    let uuid = db[&derp].uuid.clone();
+    db::upsert(&mut db, derp.clone(), "2.2.2.2".parse().unwrap(), 10);
+
+    assert_eq!((db[&derp].ip.to_string(), &db[&derp].uuid), ("2.2.2.2".to_string(), &uuid));
+    assert_eq!(db.user_ids("derp"), vec![&derp]);
+    assert!(db::delete(&mut db, &derp, 20, None).is_some());
+    assert!(!db.contains_key(&derp));
+}
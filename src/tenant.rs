@@ -0,0 +1,80 @@
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+
+use crate::db::Records;
+use crate::debounce::Debounce;
+use crate::DB;
+
+/// A tenant's record store, with its own debounce state
+#[derive(Debug, Clone)]
+pub struct Store {
+    pub db: DB,
+    pub debounce: Debounce,
+}
+
+impl Store {
+    pub fn new(records: Records, debounce: Debounce) -> Self {
+        Store { db: Arc::new(RwLock::new(records)), debounce }
+    }
+}
+
+/// The independent stores of the configured `TENANTS`, by name
+#[derive(Debug, Clone, Default)]
+pub struct Tenants(Arc<HashMap<String, Store>>);
+
+impl Tenants {
+    /// One store per name, each built by `store` from the tenant's name
+    pub fn new<F: Fn(&str) -> Store>(names: &[String], store: F) -> Self {
+        let mut stores = HashMap::new();
+        for name in names.iter().map(|name| name.to_lowercase()) {
+            let tenant = store(&name);
+            stores.insert(name, tenant);
+        }
+        Tenants(Arc::new(stores))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn stores(&self) -> impl Iterator<Item = &Store> {
+        self.0.values()
+    }
+
+    /// Every tenant's name and store
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Store)> {
+        self.0.iter().map(|(name, store)| (name.as_str(), store))
+    }
+
+    /// The tenant named by `header`, or else by the first label of `host`.
+    /// `Ok(None)` means the default store; `Err(())` an unknown `header`.
+    pub fn find(&self, header: Option<&str>, host: Option<&str>) -> Result<Option<&Store>, ()> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        if let Some(name) = header {
+            return self.0.get(&name.trim().to_lowercase()).map(Some).ok_or(());
+        }
+        let label = host.and_then(|h| h.split(&['.', ':'][..]).next()).map(str::to_lowercase);
+        Ok(label.and_then(|label| self.0.get(&label)))
+    }
+}
+
+#[test]
+fn find_tenant() {
+    let tenants = Tenants::new(&["a".parse().unwrap(), "B".into()], |_| Store::new(Records::new(), Debounce::default()));
+    let is = |found: Result<Option<&Store>, ()>, tenant: &str| {
+        found.unwrap().is_some_and(|store| Arc::ptr_eq(&store.db, &tenants.0[tenant].db))
+    };
+
+    assert!(is(tenants.find(Some("a"), None), "a"));
+    assert!(is(tenants.find(Some(" b "), Some("a.d5.example.com")), "b"));
+    assert!(is(tenants.find(None, Some("B.d5.example.com:3030")), "b"));
+    assert!(tenants.find(None, Some("d5.example.com")).unwrap().is_none());
+    assert!(tenants.find(None, None).unwrap().is_none());
+    assert!(tenants.find(Some("c"), None).is_err());
+
+    // Without tenants, every request uses the default store
+    assert!(Tenants::default().find(Some("a"), None).unwrap().is_none());
+}
@@ -0,0 +1,159 @@
+/// Parse the subset of TOML read from `d5.toml` into `(VARIABLE, value)`
+/// pairs, named like the env variables they stand for: `port` is `PORT`, and
+/// `interval` under `[snapshot]` is `SNAPSHOT_INTERVAL`.  Values are strings,
+/// numbers, booleans or single-line arrays of those, whose items are joined
+/// with newlines.
+pub fn parse(src: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::<(String, String)>::new();
+    let mut table = None;
+
+    for (n, line) in src.lines().enumerate() {
+        let err = |e: &str| format!("line {}: {}", n + 1, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let end = header.find(']').ok_or_else(|| err("unterminated table header"))?;
+            let name = header[..end].trim();
+            if !is_bare_key(name) || !is_blank(&header[end + 1..]) {
+                return Err(err("invalid table header"));
+            }
+            table = Some(name);
+            continue;
+        }
+
+        let eq = line.find('=').ok_or_else(|| err("expected `key = value`"))?;
+        let key = line[..eq].trim();
+        if !is_bare_key(key) {
+            return Err(err("invalid key"));
+        }
+        let (value, rest) = parse_value(line[eq + 1..].trim_start()).map_err(err)?;
+        if !is_blank(rest) {
+            return Err(err("unexpected text after value"));
+        }
+
+        let var = match table {
+            Some(table) => format!("{}_{}", table, key),
+            None => key.to_string(),
+        };
+        let var = var.to_uppercase().replace('-', "_");
+        if vars.iter().any(|(v, _)| *v == var) {
+            return Err(err("duplicate key"));
+        }
+        vars.push((var, value));
+    }
+    Ok(vars)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Whether only whitespace or a comment remains
+fn is_blank(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parse the value at the start of `s`, returning it and the rest of `s`
+fn parse_value(s: &str) -> Result<(String, &str), &'static str> {
+    match s.chars().next() {
+        Some('"') => parse_basic_string(&s[1..]),
+        Some('\'') => {
+            let end = s[1..].find('\'').ok_or("unterminated string")?;
+            Ok((s[1..end + 1].to_string(), &s[end + 2..]))
+        }
+        Some('[') => {
+            let mut items = Vec::new();
+            let mut rest = s[1..].trim_start();
+            while !rest.starts_with(']') {
+                let (item, after) = parse_value(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                match rest.strip_prefix(',') {
+                    Some(after) => rest = after.trim_start(),
+                    None if rest.starts_with(']') => {}
+                    None => return Err("expected `,` or `]` in array"),
+                }
+            }
+            Ok((items.join("\n"), &rest[1..]))
+        }
+        Some(_) => {
+            let end = s.find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace())
+                .unwrap_or(s.len());
+            let value = &s[..end];
+            let valid = value == "true"
+                || value == "false"
+                || value.replace('_', "").parse::<f64>().is_ok();
+            if !valid {
+                return Err("expected a string, number, boolean or array");
+            }
+            Ok((value.replace('_', ""), &s[end..]))
+        }
+        None => Err("missing value"),
+    }
+}
+
+/// Parse the rest of a `"`-quoted string, handling escapes
+fn parse_basic_string(s: &str) -> Result<(String, &str), &'static str> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                _ => return Err("unsupported escape"),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string")
+}
+
+#[test]
+fn parse_config() {
+    let src = r#"
+        # Listen on every interface
+        host = "0.0.0.0"
+        port = 8080   # the default is 3030
+        key = 'derp:fl"erp'
+        tenants = ["acme", 'globex',]
+        response_headers = ["Cache-Control: no-cache, no-store"]
+
+        [snapshot]
+        path = "C:\\d5\\snapshot.json"
+        interval = 1_000
+        [on-change]
+        cmd = "echo \"$D5_USER\""
+        user_index = true
+    "#;
+    let vars = parse(src).unwrap();
+    let get = |var: &str| vars.iter().find(|(v, _)| v == var).map(|(_, value)| value.as_str());
+
+    assert_eq!(get("HOST"), Some("0.0.0.0"));
+    assert_eq!(get("PORT"), Some("8080"));
+    assert_eq!(get("KEY"), Some("derp:fl\"erp"));
+    assert_eq!(get("TENANTS"), Some("acme\nglobex"));
+    assert_eq!(get("RESPONSE_HEADERS"), Some("Cache-Control: no-cache, no-store"));
+    assert_eq!(get("SNAPSHOT_PATH"), Some("C:\\d5\\snapshot.json"));
+    assert_eq!(get("SNAPSHOT_INTERVAL"), Some("1000"));
+    assert_eq!(get("ON_CHANGE_CMD"), Some("echo \"$D5_USER\""));
+    assert_eq!(get("ON_CHANGE_USER_INDEX"), Some("true"));
+}
+
+#[test]
+fn parse_errors() {
+    assert_eq!(parse("port = 80\nport = 81"), Err("line 2: duplicate key".to_string()));
+    assert_eq!(parse("host = \"0.0.0.0"), Err("line 1: unterminated string".to_string()));
+    assert_eq!(parse("host = localhost"), Err("line 1: expected a string, number, boolean or array".to_string()));
+    assert_eq!(parse("port = 80 81"), Err("line 1: unexpected text after value".to_string()));
+    assert_eq!(parse("[[tenant]]"), Err("line 1: invalid table header".to_string()));
+    assert_eq!(parse("just words"), Err("line 1: expected `key = value`".to_string()));
+}
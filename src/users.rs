@@ -0,0 +1,199 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::id::{constant_time_eq, Id};
+
+/// The users of a `CREDENTIALS_FILE`, the only ones allowed to store an IP
+#[derive(Debug, Default, PartialEq)]
+pub struct Users(HashMap<String, Secret>);
+
+#[derive(Debug, PartialEq)]
+enum Secret {
+    Plain(String),
+    /// An htpasswd `{SHA}` hash: the SHA-1 digest of the password
+    Sha1(Vec<u8>),
+    /// A `{BEARER}` token, sent as `Authorization: Bearer TOKEN` or as the
+    /// password
+    Bearer(String),
+}
+
+impl Users {
+    /// Parse `user:password`, `user:{SHA}BASE64` or `user:{BEARER}TOKEN` lines,
+    /// skipping blank lines and `#` comments
+    pub fn parse(src: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: &str| format!("line {}: {}", n + 1, e);
+            let mut split = line.splitn(2, ':');
+            let (user, secret) = match (split.next(), split.next()) {
+                (Some(user), Some(secret)) if !user.is_empty() => (user, secret),
+                _ => return Err(err("expected `user:password`")),
+            };
+            let secret = if let Some(hash) = secret.strip_prefix("{SHA}") {
+                match base64::decode(hash) {
+                    Ok(digest) if digest.len() == 20 => Secret::Sha1(digest),
+                    _ => return Err(err("invalid {SHA} hash")),
+                }
+            } else if let Some(token) = secret.strip_prefix("{BEARER}") {
+                if token.is_empty() || token.contains(':') {
+                    return Err(err("invalid {BEARER} token"));
+                }
+                Secret::Bearer(token.to_string())
+            } else {
+                Secret::Plain(secret.to_string())
+            };
+            if users.insert(user.to_string(), secret).is_some() {
+                return Err(err("duplicate user"));
+            }
+        }
+        Ok(Users(users))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|src| Users::parse(&src))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Whether `id` is a listed user with the right password
+    pub fn allows(&self, id: &Id) -> bool {
+        match self.0.get(&id.user) {
+            Some(Secret::Plain(password)) | Some(Secret::Bearer(password)) => {
+                constant_time_eq(password.as_bytes(), id.password.as_bytes())
+            }
+            Some(Secret::Sha1(digest)) => constant_time_eq(&Sha1::digest(id.password.as_bytes()), digest),
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.0.contains_key(user)
+    }
+
+    /// Add `id` as a new user, appending its `{SHA}` hash to the file at `path`
+    pub fn register(&mut self, path: &Path, id: &Id) -> io::Result<()> {
+        let (mut line, digest) = hashed_line(id);
+        line.push('\n');
+        if fs::read(path).map(|src| !src.is_empty() && !src.ends_with(b"\n"))? {
+            line.insert(0, '\n');
+        }
+        OpenOptions::new().append(true).open(path)?.write_all(line.as_bytes())?;
+        self.0.insert(id.user.clone(), Secret::Sha1(digest));
+        Ok(())
+    }
+
+    /// Replace the password of `id.user` with `id.password`, rewriting their
+    /// line in the file at `path` as a `{SHA}` hash.  The file keeps its
+    /// permissions.
+    pub fn set_password(&mut self, path: &Path, id: &Id) -> io::Result<()> {
+        let (line, digest) = hashed_line(id);
+        let mut out = String::new();
+        for l in fs::read_to_string(path)?.lines() {
+            let trimmed = l.trim();
+            let is_user = !trimmed.starts_with('#') && trimmed.split(':').next() == Some(id.user.as_str());
+            out.push_str(if is_user { &line } else { l });
+            out.push('\n');
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.set_permissions(fs::metadata(path)?.permissions())?;
+        file.write_all(out.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        self.0.insert(id.user.clone(), Secret::Sha1(digest));
+        Ok(())
+    }
+
+    /// The credentials of the user with the bearer `token`: their username,
+    /// with the token as the password
+    pub fn bearer(&self, token: &str) -> Option<Id> {
+        // Check every token, so the time taken doesn't reveal which matched
+        self.0.iter().fold(None, |found, (user, secret)| match secret {
+            Secret::Bearer(t) if constant_time_eq(t.as_bytes(), token.as_bytes()) => Some(Id::new(user, token)),
+            _ => found,
+        })
+    }
+}
+
+/// The `user:{SHA}BASE64` line for `id`, and the digest it holds
+fn hashed_line(id: &Id) -> (String, Vec<u8>) {
+    let digest = Sha1::digest(id.password.as_bytes()).to_vec();
+    (format!("{}:{{SHA}}{}", id.user, base64::encode(&digest)), digest)
+}
+
+#[test]
+fn parse_users() {
+    let users = Users::parse("# d5 users\nderp:flerp\n\nherp:{SHA}+Hz9luIbudjJQWAeOj8aPPG+Ki4=\nlerp:\n").unwrap();
+
+    assert!(users.allows(&Id::new("derp", "flerp")));
+    assert!(!users.allows(&Id::new("derp", "blerp")));
+    assert!(users.allows(&Id::new("herp", "blerp")));
+    assert!(!users.allows(&Id::new("herp", "{SHA}+Hz9luIbudjJQWAeOj8aPPG+Ki4=")));
+    assert!(users.allows(&Id::new("lerp", "")));
+    assert!(!users.allows(&Id::new("merp", "flerp")));
+}
+
+#[test]
+fn parse_users_errors() {
+    assert_eq!(Users::parse("derp"), Err("line 1: expected `user:password`".to_string()));
+    assert_eq!(Users::parse(":flerp"), Err("line 1: expected `user:password`".to_string()));
+    assert_eq!(Users::parse("derp:{SHA}nope"), Err("line 1: invalid {SHA} hash".to_string()));
+    assert_eq!(Users::parse("derp:a\nderp:b"), Err("line 2: duplicate user".to_string()));
+    assert_eq!(Users::parse("derp:{BEARER}"), Err("line 1: invalid {BEARER} token".to_string()));
+}
+
+#[test]
+fn bearer_tokens() {
+    let users = Users::parse("derp:flerp\nherp:{BEARER}s3cret\n").unwrap();
+
+    assert_eq!(users.bearer("s3cret"), Some(Id::new("herp", "s3cret")));
+    assert!(users.allows(&Id::new("herp", "s3cret")));
+    assert_eq!(users.bearer("flerp"), None);
+    assert_eq!(users.bearer("s3cre"), None);
+}
+
+#[test]
+fn register_user() {
+    let path = std::env::temp_dir().join(format!("d5-users-{}", std::process::id()));
+    fs::write(&path, "derp:flerp").unwrap();
+    let mut users = Users::load(&path).unwrap();
+
+    users.register(&path, &Id::new("herp", "blerp")).unwrap();
+    assert!(users.contains("herp"));
+    assert!(users.allows(&Id::new("herp", "blerp")));
+    assert_eq!(Users::load(&path).as_ref(), Ok(&users));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "derp:flerp\nherp:{SHA}+Hz9luIbudjJQWAeOj8aPPG+Ki4=\n");
+
+    users.set_password(&path, &Id::new("derp", "blerp")).unwrap();
+    assert!(!users.allows(&Id::new("derp", "flerp")));
+    assert!(users.allows(&Id::new("derp", "blerp")));
+    assert_eq!(Users::load(&path), Ok(users));
+    assert!(fs::read_to_string(&path).unwrap().starts_with("derp:{SHA}+Hz9luIbudjJQWAeOj8aPPG+Ki4=\nherp:"));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn set_password_keeps_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("d5-users-mode-{}", std::process::id()));
+    fs::write(&path, "derp:flerp\n").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    let mut users = Users::load(&path).unwrap();
+
+    users.set_password(&path, &Id::new("derp", "blerp")).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    fs::remove_file(&path).unwrap();
+}